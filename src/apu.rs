@@ -0,0 +1,923 @@
+use std::io;
+
+use crate::audio::{AudioSink, NullSink};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+pub const NR10: u16 = 0xFF10;
+pub const NR11: u16 = 0xFF11;
+pub const NR12: u16 = 0xFF12;
+pub const NR13: u16 = 0xFF13;
+pub const NR14: u16 = 0xFF14;
+pub const NR21: u16 = 0xFF16;
+pub const NR22: u16 = 0xFF17;
+pub const NR23: u16 = 0xFF18;
+pub const NR24: u16 = 0xFF19;
+pub const NR30: u16 = 0xFF1A;
+pub const NR31: u16 = 0xFF1B;
+pub const NR32: u16 = 0xFF1C;
+pub const NR33: u16 = 0xFF1D;
+pub const NR34: u16 = 0xFF1E;
+pub const NR41: u16 = 0xFF20;
+pub const NR42: u16 = 0xFF21;
+pub const NR43: u16 = 0xFF22;
+pub const NR44: u16 = 0xFF23;
+pub const NR50: u16 = 0xFF24;
+pub const NR51: u16 = 0xFF25;
+pub const NR52: u16 = 0xFF26;
+pub const WAVE_RAM_START: u16 = 0xFF30;
+pub const WAVE_RAM_END: u16 = 0xFF3F;
+
+/// the whole APU register area, including the unused addresses in it
+pub const APU_START: u16 = NR10;
+pub const APU_END: u16 = WAVE_RAM_END;
+
+/// bits that can't be read back from each register from NR10 to 0xFF2F,
+/// they read as 1
+#[rustfmt::skip]
+const READ_MASKS: [u8; 32] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // unused, NR21-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // unused, NR41-NR44
+    0x00, 0x00, 0x70,             // NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+const NR52_POWER: u8 = 0x80;
+const NRX4_TRIGGER: u8 = 0x80;
+const NRX4_LENGTH_ENABLE: u8 = 0x40;
+
+/// machine cycles per second, which the output is resampled from
+const CYCLES_PER_SECOND: u32 = 1_048_576;
+/// the channels count in clocks, four to every machine cycle
+const CLOCKS_PER_CYCLE: u32 = 4;
+/// the frame sequencer runs at 512Hz
+const FRAME_SEQUENCER_CYCLES: u32 = 2048;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+/// stereo frames collected before they are pushed to the sink
+const SINK_BUFFER_FRAMES: usize = 1024;
+
+#[rustfmt::skip]
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+
+/// noise channel periods in clocks, before the clock shift
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/// Counts a channel down to silence when enabled in NRx4
+#[derive(Default)]
+struct Length {
+    counter: u16,
+    enabled: bool,
+}
+
+impl Length {
+    fn load(&mut self, max: u16, value: u16) {
+        self.counter = max - value;
+    }
+
+    /// returns false once the channel should be switched off
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter != 0;
+        }
+        true
+    }
+
+    fn trigger(&mut self, max: u16) {
+        if self.counter == 0 {
+            self.counter = max;
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.u16(self.counter);
+        writer.bool(self.enabled);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.counter = reader.u16()?;
+        self.enabled = reader.bool()?;
+        Ok(())
+    }
+}
+
+/// The volume envelope of the square and noise channels, set up by NRx2
+#[derive(Default)]
+struct Envelope {
+    register: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn period(&self) -> u8 {
+        self.register & 0x07
+    }
+
+    /// the upper five bits of NRx2 double as the channel's DAC power
+    fn dac_enabled(&self) -> bool {
+        self.register & 0xF8 != 0
+    }
+
+    fn clock(&mut self) {
+        if self.period() == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period();
+            if self.register & 0x08 != 0 && self.volume < 15 {
+                self.volume += 1;
+            } else if self.register & 0x08 == 0 && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.register >> 4;
+        self.timer = self.period();
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.register);
+        writer.u8(self.volume & 0x0F);
+        writer.u8(self.timer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.register = reader.u8()?;
+        self.volume = reader.u8()? & 0x0F;
+        self.timer = reader.u8()?;
+        Ok(())
+    }
+}
+
+/// Square channel 1's frequency sweep, set up by NR10
+#[derive(Default)]
+struct Sweep {
+    register: u8,
+    enabled: bool,
+    shadow: u16,
+    timer: u8,
+    /// a subtraction happened since the last trigger. clearing the
+    /// negate bit after that switches the channel off
+    negated: bool,
+}
+
+impl Sweep {
+    fn period(&self) -> u8 {
+        (self.register >> 4) & 0x07
+    }
+
+    fn negate(&self) -> bool {
+        self.register & 0x08 != 0
+    }
+
+    fn shift(&self) -> u8 {
+        self.register & 0x07
+    }
+
+    fn reload_timer(&mut self) {
+        // a period of 0 is treated as 8
+        self.timer = if self.period() == 0 { 8 } else { self.period() };
+    }
+
+    /// the next frequency, anything above 2047 switches the channel off
+    fn next_frequency(&mut self) -> u16 {
+        let delta = self.shadow >> self.shift();
+        if self.negate() {
+            self.negated = true;
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.register);
+        writer.bool(self.enabled);
+        writer.u16(self.shadow);
+        writer.u8(self.timer);
+        writer.bool(self.negated);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.register = reader.u8()?;
+        self.enabled = reader.bool()?;
+        self.shadow = reader.u16()? & 0x7FF;
+        self.timer = reader.u8()?;
+        self.negated = reader.bool()?;
+        Ok(())
+    }
+}
+
+/// Square channels 1 and 2, only channel 1 has a sweep
+#[derive(Default)]
+struct Square {
+    enabled: bool,
+    duty: u8,
+    duty_step: u8,
+    frequency: u16,
+    /// clocks until the next duty step
+    timer: u32,
+    length: Length,
+    envelope: Envelope,
+    sweep: Option<Sweep>,
+}
+
+impl Square {
+    fn with_sweep() -> Square {
+        Square {
+            sweep: Some(Sweep::default()),
+            ..Square::default()
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 4
+    }
+
+    fn step(&mut self, mut clocks: u32) {
+        while clocks >= self.timer {
+            clocks -= self.timer;
+            self.timer = self.period();
+            self.duty_step = (self.duty_step + 1) % 8;
+        }
+        self.timer -= clocks;
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        DUTY_PATTERNS[self.duty as usize][self.duty_step as usize] * self.envelope.volume
+    }
+
+    fn write_nrx1(&mut self, value: u8) {
+        self.duty = value >> 6;
+        self.length.load(64, (value & 0x3F) as u16);
+    }
+
+    fn write_nrx2(&mut self, value: u8) {
+        self.envelope.register = value;
+        if !self.envelope.dac_enabled() {
+            self.enabled = false;
+        }
+    }
+
+    fn write_nrx3(&mut self, value: u8) {
+        self.frequency = (self.frequency & 0x700) | value as u16;
+    }
+
+    fn write_nrx4(&mut self, value: u8) {
+        self.frequency = (self.frequency & 0xFF) | ((value & 0x07) as u16) << 8;
+        self.length.enabled = value & NRX4_LENGTH_ENABLE != 0;
+        if value & NRX4_TRIGGER != 0 {
+            self.trigger();
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.timer = self.period();
+        self.length.trigger(64);
+        self.envelope.trigger();
+
+        let frequency = self.frequency;
+        if let Some(sweep) = &mut self.sweep {
+            sweep.shadow = frequency;
+            sweep.negated = false;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period() != 0 || sweep.shift() != 0;
+            // with a shift the overflow check runs straight away
+            if sweep.shift() != 0 && sweep.next_frequency() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn write_sweep(&mut self, value: u8) {
+        if let Some(sweep) = &mut self.sweep {
+            let was_negating = sweep.negate();
+            sweep.register = value;
+            if was_negating && !sweep.negate() && sweep.negated {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        let Some(sweep) = &mut self.sweep else {
+            return;
+        };
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer != 0 {
+            return;
+        }
+        sweep.reload_timer();
+        if !sweep.enabled || sweep.period() == 0 {
+            return;
+        }
+
+        let frequency = sweep.next_frequency();
+        if frequency > 2047 {
+            self.enabled = false;
+        } else if sweep.shift() != 0 {
+            sweep.shadow = frequency;
+            self.frequency = frequency;
+            // the new frequency is checked for overflow again, but not used
+            if sweep.next_frequency() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.enabled);
+        writer.u8(self.duty);
+        writer.u8(self.duty_step);
+        writer.u16(self.frequency);
+        writer.u32(self.timer);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+        if let Some(sweep) = &self.sweep {
+            sweep.save_state(writer);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.bool()?;
+        self.duty = reader.u8()? & 0x03;
+        self.duty_step = reader.u8()? & 0x07;
+        self.frequency = reader.u16()? & 0x7FF;
+        self.timer = reader.u32()?.clamp(1, self.period());
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+        if let Some(sweep) = &mut self.sweep {
+            sweep.load_state(reader)?;
+        }
+        Ok(())
+    }
+}
+
+/// The wave channel, playing 32 4-bit samples from wave RAM
+#[derive(Default)]
+struct Wave {
+    enabled: bool,
+    dac_enabled: bool,
+    volume_code: u8,
+    frequency: u16,
+    timer: u32,
+    position: u8,
+    /// the sample last read from wave RAM, which is what the channel outputs
+    sample: u8,
+    length: Length,
+    ram: [u8; 16],
+}
+
+impl Wave {
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 2
+    }
+
+    fn step(&mut self, mut clocks: u32) {
+        while clocks >= self.timer {
+            clocks -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % 32;
+            let byte = self.ram[self.position as usize / 2];
+            // the high nibble plays first
+            self.sample = if self.position.is_multiple_of(2) { byte >> 4 } else { byte & 0x0F };
+        }
+        self.timer -= clocks;
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        match self.volume_code {
+            0 => 0,
+            code => self.sample >> (code - 1),
+        }
+    }
+
+    fn write_nr30(&mut self, value: u8) {
+        self.dac_enabled = value & 0x80 != 0;
+        if !self.dac_enabled {
+            self.enabled = false;
+        }
+    }
+
+    fn write_nr34(&mut self, value: u8) {
+        self.frequency = (self.frequency & 0xFF) | ((value & 0x07) as u16) << 8;
+        self.length.enabled = value & NRX4_LENGTH_ENABLE != 0;
+        if value & NRX4_TRIGGER != 0 {
+            self.enabled = self.dac_enabled;
+            // the first sample is only read after a full period, and the
+            // delay before it is a few clocks longer than the rest
+            self.timer = self.period() + 6;
+            self.position = 0;
+            self.length.trigger(256);
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.enabled);
+        writer.bool(self.dac_enabled);
+        writer.u8(self.volume_code);
+        writer.u16(self.frequency);
+        writer.u32(self.timer);
+        writer.u8(self.position);
+        writer.u8(self.sample);
+        self.length.save_state(writer);
+        writer.bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.bool()?;
+        self.dac_enabled = reader.bool()?;
+        self.volume_code = reader.u8()? & 0x03;
+        self.frequency = reader.u16()? & 0x7FF;
+        self.timer = reader.u32()?.clamp(1, self.period() + 6);
+        self.position = reader.u8()? % 32;
+        self.sample = reader.u8()? & 0x0F;
+        self.length.load_state(reader)?;
+        reader.bytes_into(&mut self.ram)
+    }
+}
+
+/// The noise channel, the low bit of a linear feedback shift register
+#[derive(Default)]
+struct Noise {
+    enabled: bool,
+    /// NR43, clock shift, width and divisor
+    register: u8,
+    lfsr: u16,
+    timer: u32,
+    length: Length,
+    envelope: Envelope,
+}
+
+impl Noise {
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[(self.register & 0x07) as usize] << (self.register >> 4)
+    }
+
+    /// NR43 bit 3 shortens the register to 7 bits, which repeats much sooner
+    fn short_mode(&self) -> bool {
+        self.register & 0x08 != 0
+    }
+
+    fn step(&mut self, mut clocks: u32) {
+        while clocks >= self.timer {
+            clocks -= self.timer;
+            self.timer = self.period();
+            let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+            if self.short_mode() {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+            }
+        }
+        self.timer -= clocks;
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr & 1 != 0 {
+            return 0;
+        }
+        self.envelope.volume
+    }
+
+    fn write_nr42(&mut self, value: u8) {
+        self.envelope.register = value;
+        if !self.envelope.dac_enabled() {
+            self.enabled = false;
+        }
+    }
+
+    fn write_nr44(&mut self, value: u8) {
+        self.length.enabled = value & NRX4_LENGTH_ENABLE != 0;
+        if value & NRX4_TRIGGER != 0 {
+            self.enabled = self.envelope.dac_enabled();
+            self.timer = self.period();
+            self.lfsr = 0x7FFF;
+            self.length.trigger(64);
+            self.envelope.trigger();
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.enabled);
+        writer.u8(self.register);
+        writer.u16(self.lfsr);
+        writer.u32(self.timer);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.bool()?;
+        self.register = reader.u8()?;
+        self.lfsr = reader.u16()? & 0x7FFF;
+        self.timer = reader.u32()?.clamp(1, self.period());
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)
+    }
+}
+
+/// The audio processing unit. Pushes interleaved stereo samples into its
+/// [AudioSink] at a sample rate chosen by the frontend
+pub struct APU {
+    /// the raw register values, for reading back
+    registers: [u8; 32],
+    square1: Square,
+    square2: Square,
+    wave: Wave,
+    noise: Noise,
+    frame_sequencer_cycles: u32,
+    frame_sequencer_step: u8,
+    sample_rate: u32,
+    /// counts up by the sample rate every cycle, a sample is due
+    /// every time it passes the cycles in a second
+    sample_clock: u32,
+    /// the charge of the output capacitors that block DC, left and right
+    capacitors: [f32; 2],
+    samples: Vec<f32>,
+    sink: Box<dyn AudioSink>,
+}
+
+impl APU {
+    /// creates an APU in the state the DMG boot ROM leaves it in
+    pub fn new() -> APU {
+        let mut apu = APU {
+            registers: [0; 32],
+            square1: Square::with_sweep(),
+            square2: Square::default(),
+            wave: Wave::default(),
+            noise: Noise::default(),
+            frame_sequencer_cycles: 0,
+            frame_sequencer_step: 0,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            sample_clock: 0,
+            capacitors: [0.0; 2],
+            samples: Vec::with_capacity(SINK_BUFFER_FRAMES * 2),
+            sink: Box::new(NullSink),
+        };
+        for (address, value) in [(NR52, 0xF1), (NR50, 0x77), (NR51, 0xF3), (NR10, 0x80), (NR11, 0xBF), (NR12, 0xF3)] {
+            apu.write(address, value);
+        }
+        // the boot sound has finished playing
+        apu.square1.enabled = true;
+        apu.square1.envelope.volume = 0;
+        apu
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// samples made at the old rate are pushed to the sink first
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.flush_samples();
+        self.sample_rate = sample_rate.clamp(1, CYCLES_PER_SECOND);
+        self.sample_clock = 0;
+    }
+
+    /// swaps in a new sink, returning the old one
+    pub fn set_sink(&mut self, sink: Box<dyn AudioSink>) -> Box<dyn AudioSink> {
+        self.flush_samples();
+        std::mem::replace(&mut self.sink, sink)
+    }
+
+    /// pushes out what is buffered and lets the sink finish up
+    pub fn finish_audio(&mut self) -> io::Result<()> {
+        self.flush_samples();
+        self.sink.finish()
+    }
+
+    fn flush_samples(&mut self) {
+        if !self.samples.is_empty() {
+            self.sink.push_samples(&self.samples, self.sample_rate);
+            self.samples.clear();
+        }
+    }
+
+    fn powered(&self) -> bool {
+        self.registers[(NR52 - APU_START) as usize] & NR52_POWER != 0
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            WAVE_RAM_START..=WAVE_RAM_END => self.wave.ram[(address - WAVE_RAM_START) as usize],
+            NR52 => {
+                let channels = [self.square1.enabled, self.square2.enabled, self.wave.enabled, self.noise.enabled];
+                let status = channels.iter().enumerate().fold(0, |status, (bit, on)| status | (*on as u8) << bit);
+                READ_MASKS[(NR52 - APU_START) as usize] | self.registers[(NR52 - APU_START) as usize] | status
+            },
+            APU_START..=APU_END => {
+                let index = (address - APU_START) as usize;
+                READ_MASKS[index] | self.registers[index]
+            },
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        if let WAVE_RAM_START..=WAVE_RAM_END = address {
+            self.wave.ram[(address - WAVE_RAM_START) as usize] = value;
+            return;
+        }
+        if address == NR52 {
+            self.write_nr52(value);
+            return;
+        }
+        // while powered off only the length counters can be written
+        if !self.powered() {
+            match address {
+                NR11 => self.square1.length.load(64, (value & 0x3F) as u16),
+                NR21 => self.square2.length.load(64, (value & 0x3F) as u16),
+                NR31 => self.wave.length.load(256, value as u16),
+                NR41 => self.noise.length.load(64, (value & 0x3F) as u16),
+                _ => {},
+            }
+            return;
+        }
+        if !(APU_START..=APU_END).contains(&address) {
+            return;
+        }
+        self.registers[(address - APU_START) as usize] = value;
+
+        match address {
+            NR10 => self.square1.write_sweep(value),
+            NR11 => self.square1.write_nrx1(value),
+            NR12 => self.square1.write_nrx2(value),
+            NR13 => self.square1.write_nrx3(value),
+            NR14 => self.square1.write_nrx4(value),
+            NR21 => self.square2.write_nrx1(value),
+            NR22 => self.square2.write_nrx2(value),
+            NR23 => self.square2.write_nrx3(value),
+            NR24 => self.square2.write_nrx4(value),
+            NR30 => self.wave.write_nr30(value),
+            NR31 => self.wave.length.load(256, value as u16),
+            NR32 => self.wave.volume_code = (value >> 5) & 0x03,
+            NR33 => self.wave.frequency = (self.wave.frequency & 0x700) | value as u16,
+            NR34 => self.wave.write_nr34(value),
+            NR41 => self.noise.length.load(64, (value & 0x3F) as u16),
+            NR42 => self.noise.write_nr42(value),
+            NR43 => self.noise.register = value,
+            NR44 => self.noise.write_nr44(value),
+            _ => {},
+        }
+    }
+
+    /// powering off clears every register and silences all channels,
+    /// wave RAM and the length counters survive
+    fn write_nr52(&mut self, value: u8) {
+        let index = (NR52 - APU_START) as usize;
+        if value & NR52_POWER == 0 {
+            let lengths = [
+                self.square1.length.counter,
+                self.square2.length.counter,
+                self.wave.length.counter,
+                self.noise.length.counter,
+            ];
+            let ram = self.wave.ram;
+            self.registers = [0; 32];
+            self.square1 = Square::with_sweep();
+            self.square2 = Square::default();
+            self.wave = Wave {
+                ram,
+                ..Wave::default()
+            };
+            self.noise = Noise::default();
+            self.square1.length.counter = lengths[0];
+            self.square2.length.counter = lengths[1];
+            self.wave.length.counter = lengths[2];
+            self.noise.length.counter = lengths[3];
+        } else if !self.powered() {
+            self.frame_sequencer_step = 0;
+            self.frame_sequencer_cycles = 0;
+        }
+        self.registers[index] = value & NR52_POWER;
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        for _ in 0..cycles {
+            if self.powered() {
+                self.step_channels();
+            }
+            self.sample_clock += self.sample_rate;
+            if self.sample_clock >= CYCLES_PER_SECOND {
+                self.sample_clock -= CYCLES_PER_SECOND;
+                self.push_sample();
+            }
+        }
+    }
+
+    fn step_channels(&mut self) {
+        self.square1.step(CLOCKS_PER_CYCLE);
+        self.square2.step(CLOCKS_PER_CYCLE);
+        self.wave.step(CLOCKS_PER_CYCLE);
+        self.noise.step(CLOCKS_PER_CYCLE);
+
+        self.frame_sequencer_cycles += 1;
+        if self.frame_sequencer_cycles < FRAME_SEQUENCER_CYCLES {
+            return;
+        }
+        self.frame_sequencer_cycles = 0;
+
+        // lengths at 256Hz, sweep at 128Hz and envelopes at 64Hz
+        if self.frame_sequencer_step.is_multiple_of(2) {
+            self.square1.clock_length();
+            self.square2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        if self.frame_sequencer_step % 4 == 2 {
+            self.square1.clock_sweep();
+        }
+        if self.frame_sequencer_step == 7 {
+            self.square1.envelope.clock();
+            self.square2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+        self.frame_sequencer_step = (self.frame_sequencer_step + 1) % 8;
+    }
+
+    /// mixes the channels through NR51's panning and NR50's master volume
+    fn push_sample(&mut self) {
+        let outputs = [
+            (self.square1.output(), self.square1.envelope.dac_enabled()),
+            (self.square2.output(), self.square2.envelope.dac_enabled()),
+            (self.wave.output(), self.wave.dac_enabled),
+            (self.noise.output(), self.noise.envelope.dac_enabled()),
+        ];
+        let panning = self.registers[(NR51 - APU_START) as usize];
+        let volume = self.registers[(NR50 - APU_START) as usize];
+
+        // right is the low nibble of both registers, left the high one
+        for (side, shift) in [(0, 4), (1, 0)] {
+            let mut mixed = 0.0;
+            let mut any_dac = false;
+            for (channel, (output, dac_enabled)) in outputs.iter().enumerate() {
+                // each DAC maps 0-15 to an analog level, a DAC that is off outputs nothing
+                if *dac_enabled && self.powered() {
+                    any_dac = true;
+                    if panning & (1 << (channel + shift)) != 0 {
+                        mixed += *output as f32 / 7.5 - 1.0;
+                    }
+                }
+            }
+            mixed = mixed / 4.0 * (((volume >> shift) & 0x07) + 1) as f32 / 8.0;
+
+            // the output capacitor slowly removes any DC offset
+            let sample = if any_dac {
+                let filtered = mixed - self.capacitors[side];
+                self.capacitors[side] = mixed - filtered * self.capacitor_charge();
+                filtered
+            } else {
+                0.0
+            };
+            self.samples.push(sample);
+        }
+
+        if self.samples.len() >= SINK_BUFFER_FRAMES * 2 {
+            self.flush_samples();
+        }
+    }
+
+    /// how much of the capacitor's charge is left after one sample
+    fn capacitor_charge(&self) -> f32 {
+        0.999958f32.powf((CYCLES_PER_SECOND * CLOCKS_PER_CYCLE) as f32 / self.sample_rate as f32)
+    }
+}
+
+impl Default for APU {
+    fn default() -> Self {
+        APU::new()
+    }
+}
+
+/// the sample rate, the resampling position, the sink and the samples
+/// that haven't been pushed to it belong to the frontend and aren't saved
+impl SaveState for APU {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bytes(&self.registers);
+        self.square1.save_state(writer);
+        self.square2.save_state(writer);
+        self.wave.save_state(writer);
+        self.noise.save_state(writer);
+        writer.u32(self.frame_sequencer_cycles);
+        writer.u8(self.frame_sequencer_step);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.bytes_into(&mut self.registers)?;
+        self.square1.load_state(reader)?;
+        self.square2.load_state(reader)?;
+        self.wave.load_state(reader)?;
+        self.noise.load_state(reader)?;
+        self.frame_sequencer_cycles = reader.u32()? % FRAME_SEQUENCER_CYCLES;
+        self.frame_sequencer_step = reader.u8()? % 8;
+        self.capacitors = [0.0; 2];
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// an APU just powered on, with the frame sequencer at step 0
+    fn powered_apu() -> APU {
+        let mut apu = APU::new();
+        apu.write(NR52, 0);
+        apu.write(NR52, NR52_POWER);
+        apu
+    }
+
+    #[test]
+    fn length_counter_switches_a_channel_off() {
+        let mut apu = powered_apu();
+        apu.write(NR22, 0xF0);
+        // a length of 63 leaves one step on the counter
+        apu.write(NR21, 0x3F);
+        apu.write(NR24, NRX4_TRIGGER | NRX4_LENGTH_ENABLE);
+        assert_eq!(apu.read(NR52) & 0x02, 0x02);
+
+        apu.tick(FRAME_SEQUENCER_CYCLES - 1);
+        assert_eq!(apu.read(NR52) & 0x02, 0x02);
+        apu.tick(1);
+        assert_eq!(apu.read(NR52) & 0x02, 0);
+    }
+
+    #[test]
+    fn sweep_overflow_switches_channel_1_off() {
+        let mut apu = powered_apu();
+        apu.write(NR12, 0xF0);
+        // period 1, adding the frequency shifted right by one
+        apu.write(NR10, 0x11);
+
+        // 1536 + 768 overflows in the check made on trigger
+        apu.write(NR13, 0x00);
+        apu.write(NR14, NRX4_TRIGGER | 0x06);
+        assert_eq!(apu.read(NR52) & 0x01, 0);
+
+        // 1280 + 640 fits, but the check after the first sweep step doesn't
+        apu.write(NR14, NRX4_TRIGGER | 0x05);
+        assert_eq!(apu.read(NR52) & 0x01, 0x01);
+        apu.tick(3 * FRAME_SEQUENCER_CYCLES);
+        assert_eq!(apu.read(NR52) & 0x01, 0);
+    }
+
+    #[test]
+    fn nr52_reports_channels_and_power() {
+        let mut apu = powered_apu();
+        assert_eq!(apu.read(NR52), 0xF0);
+        apu.write(NR22, 0xF0);
+        apu.write(NR24, NRX4_TRIGGER);
+        apu.write(NR42, 0xF0);
+        apu.write(NR44, NRX4_TRIGGER);
+        assert_eq!(apu.read(NR52), 0xFA);
+
+        // a DAC switched off takes its channel with it
+        apu.write(NR22, 0x00);
+        assert_eq!(apu.read(NR52), 0xF8);
+
+        apu.write(NR50, 0x77);
+        apu.write(WAVE_RAM_START, 0x12);
+        apu.write(NR52, 0);
+        assert_eq!(apu.read(NR52), 0x70);
+        assert_eq!(apu.read(NR50), 0x00);
+        apu.write(NR50, 0x77);
+        assert_eq!(apu.read(NR50), 0x00);
+        assert_eq!(apu.read(WAVE_RAM_START), 0x12);
+    }
+}
@@ -0,0 +1,168 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// size of the RIFF header up to the start of the sample data
+const WAV_HEADER_SIZE: u32 = 44;
+const CHANNELS: u16 = 2;
+const BYTES_PER_SAMPLE: u16 = 2;
+/// the most sample data a WAV file can hold, the RIFF size field covers
+/// it plus most of the header. rounded down to whole stereo frames
+const MAX_DATA_SIZE: u32 = (u32::MAX - (WAV_HEADER_SIZE - 8)) / 4 * 4;
+
+/// Where the [APU](crate::apu::APU) sends its output. Samples are interleaved
+/// stereo, left then right, in the range -1.0 to 1.0
+pub trait AudioSink {
+    fn push_samples(&mut self, samples: &[f32], sample_rate: u32);
+
+    /// called once the emulator is done, sinks that write
+    /// somewhere report any error that happened along the way here
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Throws the audio away
+#[derive(Default)]
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn push_samples(&mut self, _samples: &[f32], _sample_rate: u32) {}
+}
+
+/// Records the audio to a 16-bit PCM WAV file. The sizes in the header
+/// are filled in by [finish](AudioSink::finish), or when the sink is dropped
+pub struct WavSink {
+    writer: BufWriter<File>,
+    sample_rate: Option<u32>,
+    data_size: u32,
+    /// the first write error, pushing samples can't fail so it is kept for finish
+    error: Option<io::Error>,
+    finished: bool,
+}
+
+impl WavSink {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<WavSink> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        // the header is written again with the real sizes when finishing
+        writer.write_all(&wav_header(0, 0))?;
+        Ok(WavSink {
+            writer,
+            sample_rate: None,
+            data_size: 0,
+            error: None,
+            finished: false,
+        })
+    }
+
+    /// samples past the largest size a WAV file can describe are dropped
+    fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        let room = ((MAX_DATA_SIZE - self.data_size) / BYTES_PER_SAMPLE as u32) as usize;
+        let samples = &samples[..samples.len().min(room)];
+        for sample in samples {
+            let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.writer.write_all(&value.to_le_bytes())?;
+        }
+        self.data_size += samples.len() as u32 * BYTES_PER_SAMPLE as u32;
+        Ok(())
+    }
+}
+
+impl AudioSink for WavSink {
+    /// the file takes the sample rate of the first samples it gets
+    fn push_samples(&mut self, samples: &[f32], sample_rate: u32) {
+        if self.error.is_some() || self.finished {
+            return;
+        }
+        let file_rate = *self.sample_rate.get_or_insert(sample_rate);
+        if file_rate != sample_rate {
+            self.error = Some(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("sample rate changed from {} to {} while recording", file_rate, sample_rate),
+            ));
+            return;
+        }
+        if let Err(err) = self.write_samples(samples) {
+            self.error = Some(err);
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(&wav_header(self.sample_rate.unwrap_or(0), self.data_size))?;
+        self.writer.flush()
+    }
+}
+
+impl Drop for WavSink {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+/// the canonical 44 byte header for stereo 16-bit PCM
+fn wav_header(sample_rate: u32, data_size: u32) -> Vec<u8> {
+    let block_align = CHANNELS * BYTES_PER_SAMPLE;
+    let mut header = Vec::with_capacity(WAV_HEADER_SIZE as usize);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(WAV_HEADER_SIZE - 8).saturating_add(data_size).to_le_bytes());
+    header.extend_from_slice(b"WAVE");
+    header.extend_from_slice(b"fmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    // format 1 is uncompressed PCM
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&CHANNELS.to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&(BYTES_PER_SAMPLE * 8).to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_size.to_le_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn finish_fills_in_the_header() {
+        let path = std::env::temp_dir().join(format!("gbr-audio-{}.wav", std::process::id()));
+        let mut sink = WavSink::create(&path).unwrap();
+        sink.push_samples(&[0.0, 1.0, -1.0, 0.5], 32_768);
+        sink.push_samples(&[2.0, 0.0], 32_768);
+        sink.finish().unwrap();
+        drop(sink);
+
+        let file = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(file.len(), WAV_HEADER_SIZE as usize + 12);
+        assert_eq!(&file[0..4], b"RIFF");
+        assert_eq!(u32_at(&file, 4), WAV_HEADER_SIZE - 8 + 12);
+        assert_eq!(u32_at(&file, 24), 32_768);
+        assert_eq!(u32_at(&file, 28), 32_768 * 4);
+        assert_eq!(&file[36..40], b"data");
+        assert_eq!(u32_at(&file, 40), 12);
+        let samples: Vec<i16> = file[44..].chunks_exact(2).map(|pair| i16::from_le_bytes([pair[0], pair[1]])).collect();
+        assert_eq!(samples, [0, i16::MAX, -i16::MAX, i16::MAX / 2, i16::MAX, 0]);
+    }
+
+    #[test]
+    fn header_sizes_dont_overflow() {
+        let header = wav_header(44_100, MAX_DATA_SIZE);
+        assert_eq!(u32_at(&header, 4), MAX_DATA_SIZE + (WAV_HEADER_SIZE - 8));
+        assert_eq!(u32_at(&wav_header(44_100, u32::MAX), 4), u32::MAX);
+    }
+}
@@ -0,0 +1,220 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::cartridge::Cartridge;
+use crate::mbc3::{RTC_FOOTER_SIZE, SHORT_RTC_FOOTER_SIZE};
+
+/// how long RAM writes are allowed to pile up before they are flushed
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Problems with a save file that aren't worth stopping the emulator for
+#[derive(Debug)]
+pub enum SaveWarning {
+    /// there was no save to load, the cartridge RAM starts out blank
+    Missing(PathBuf),
+    /// the save doesn't fit the cartridge, whatever overlaps was still loaded
+    SizeMismatch { path: PathBuf, expected: usize, actual: usize },
+    Io(PathBuf, io::Error),
+}
+
+impl fmt::Display for SaveWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveWarning::Missing(path) => {
+                write!(f, "no save file at {}, starting with blank cartridge RAM", path.display())
+            },
+            SaveWarning::SizeMismatch { path, expected, actual } => write!(
+                f,
+                "save file {} is {} bytes but the cartridge expects {}, only the overlapping part was loaded",
+                path.display(),
+                actual,
+                expected
+            ),
+            SaveWarning::Io(path, err) => write!(f, "could not access save file {}: {}", path.display(), err),
+        }
+    }
+}
+
+/// Keeps a battery backed cartridge's RAM in sync with a .sav file
+pub struct BatterySave {
+    path: PathBuf,
+    last_flush: Instant,
+}
+
+impl BatterySave {
+    pub fn new(path: impl Into<PathBuf>) -> BatterySave {
+        BatterySave {
+            path: path.into(),
+            last_flush: Instant::now(),
+        }
+    }
+
+    /// the default save location, next to the ROM with a .sav extension
+    pub fn for_rom(rom_path: impl AsRef<Path>) -> BatterySave {
+        BatterySave::new(rom_path.as_ref().with_extension("sav"))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// loads the save into the cartridge. carts without a battery are left alone
+    pub fn load(&self, cartridge: &mut Cartridge) -> Result<(), SaveWarning> {
+        if !cartridge.header.cartridge_type.battery {
+            return Ok(());
+        }
+
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(SaveWarning::Missing(self.path.clone()));
+            },
+            Err(err) => return Err(SaveWarning::Io(self.path.clone(), err)),
+        };
+
+        cartridge.load_battery_data(&data);
+
+        let ram_size = cartridge.ram.len();
+        let accepted = if cartridge.rtc().is_some() {
+            // saves made without the clock footer are fine too
+            [ram_size, ram_size + SHORT_RTC_FOOTER_SIZE, ram_size + RTC_FOOTER_SIZE].contains(&data.len())
+        } else {
+            data.len() == ram_size
+        };
+        if !accepted {
+            let expected = cartridge.battery_data().len();
+            return Err(SaveWarning::SizeMismatch {
+                path: self.path.clone(),
+                expected,
+                actual: data.len(),
+            });
+        }
+        Ok(())
+    }
+
+    /// writes the save if the RAM changed and enough time has
+    /// passed since the last flush, meant to be called every frame
+    pub fn flush_if_due(&mut self, cartridge: &mut Cartridge) -> io::Result<()> {
+        if self.last_flush.elapsed() < FLUSH_INTERVAL || !cartridge.ram_dirty() {
+            return Ok(());
+        }
+        self.flush(cartridge)
+    }
+
+    /// writes the save unconditionally, for example on exit. the RAM stays
+    /// dirty if the write fails, so the next flush tries again
+    pub fn flush(&mut self, cartridge: &mut Cartridge) -> io::Result<()> {
+        if !cartridge.header.cartridge_type.battery {
+            return Ok(());
+        }
+        self.last_flush = Instant::now();
+        write_atomic(&self.path, &cartridge.battery_data())?;
+        cartridge.clear_ram_dirty();
+        Ok(())
+    }
+}
+
+/// writes to a temporary file next to path and renames it into place, so a
+/// crash part way through never leaves a truncated save behind
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+
+    let mut file = File::create(&temporary)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::test_rom;
+    use crate::mbc3::RtcClock;
+
+    /// MBC3+TIMER+RAM+BATTERY with 8KiB of RAM, RAM enabled
+    fn mbc3_cartridge() -> Cartridge {
+        let mut cartridge = Cartridge::from_bytes(test_rom(0x10, 0x00, 0x02)).unwrap();
+        cartridge.rtc().unwrap().set_clock(RtcClock::Emulated);
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge
+    }
+
+    fn temporary_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("gbr-{}-{}.sav", name, std::process::id()))
+    }
+
+    #[test]
+    fn flushed_save_loads_back() {
+        let path = temporary_path("round-trip");
+        let mut cartridge = mbc3_cartridge();
+        cartridge.write_ram(0xA010, 0x42);
+        let mut battery = BatterySave::new(&path);
+        battery.flush(&mut cartridge).unwrap();
+        assert!(!cartridge.ram_dirty());
+
+        let mut loaded = mbc3_cartridge();
+        let result = battery.load(&mut loaded);
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_ok());
+        assert_eq!(loaded.read_ram(0xA010), 0x42);
+    }
+
+    #[test]
+    fn failed_flush_leaves_ram_dirty() {
+        let path = std::env::temp_dir().join("gbr-missing-directory").join("save.sav");
+        let mut cartridge = mbc3_cartridge();
+        cartridge.write_ram(0xA000, 0x42);
+        let mut battery = BatterySave::new(path);
+        assert!(battery.flush(&mut cartridge).is_err());
+        assert!(cartridge.ram_dirty());
+    }
+
+    #[test]
+    fn clean_ram_is_not_flushed() {
+        let path = temporary_path("clean");
+        let mut cartridge = mbc3_cartridge();
+        let mut battery = BatterySave::new(&path);
+        battery.last_flush -= FLUSH_INTERVAL;
+        battery.flush_if_due(&mut cartridge).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn short_rtc_footer_is_loaded() {
+        let mut cartridge = mbc3_cartridge();
+        let mut data = vec![0; 0x2000];
+        // live then latched registers, then the 32-bit timestamp
+        for value in [12u32, 34, 5, 6, 1, 11, 33, 4, 5, 0] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&0u32.to_le_bytes());
+        assert_eq!(data.len(), 0x2000 + SHORT_RTC_FOOTER_SIZE);
+
+        let path = temporary_path("short-footer");
+        fs::write(&path, &data).unwrap();
+        let result = BatterySave::new(&path).load(&mut cartridge);
+        fs::remove_file(&path).unwrap();
+
+        assert!(result.is_ok());
+        let rtc = cartridge.rtc().unwrap();
+        assert_eq!((rtc.registers.seconds, rtc.registers.minutes, rtc.registers.hours), (12, 34, 5));
+        assert_eq!((rtc.latched.seconds, rtc.latched.minutes), (11, 33));
+    }
+
+    #[test]
+    fn mismatched_save_size_warns() {
+        let path = temporary_path("mismatch");
+        fs::write(&path, [0x42; 0x100]).unwrap();
+        let mut cartridge = mbc3_cartridge();
+        let result = BatterySave::new(&path).load(&mut cartridge);
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(result, Err(SaveWarning::SizeMismatch { actual: 0x100, .. })));
+        assert_eq!(cartridge.read_ram(0xA0FF), 0x42);
+    }
+}
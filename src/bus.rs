@@ -0,0 +1,96 @@
+use crate::cpu::Event;
+
+/// Everything the SM83 core knows about the outside world. The CPU only ever
+/// touches memory through this trait, so it can be attached to the Game Boy
+/// [MMU](crate::mmu::MMU) or to any other memory map
+pub trait Bus {
+    fn read8(&mut self, address: u16) -> u8;
+    fn write8(&mut self, address: u16, value: u8);
+
+    /// called as the CPU spends machine cycles, so that the rest of
+    /// the system can be kept in step with it
+    fn tick(&mut self, _cycles: u32) {}
+
+    /// moves anything the hardware on the bus wants to report into events,
+    /// called once per [step](crate::cpu::CPU::step)
+    fn drain_events(&mut self, _events: &mut Vec<Event>) {}
+
+    /// called when the CPU executes STOP
+    fn stop(&mut self) {}
+
+    /// polled while the CPU is stopped, returning true brings it back
+    fn stop_wake(&mut self) -> bool {
+        false
+    }
+
+    /// reads a little endian u16, the low byte is at address
+    fn read16(&mut self, address: u16) -> u16 {
+        let lo = self.read8(address);
+        let hi = self.read8(address.wrapping_add(1));
+        u16::from_le_bytes([lo, hi])
+    }
+
+    /// writes a little endian u16, the low byte goes to address
+    fn write16(&mut self, address: u16, value: u16) {
+        let [lo, hi] = value.to_le_bytes();
+        self.write8(address, lo);
+        self.write8(address.wrapping_add(1), hi);
+    }
+}
+
+/// 64KiB of plain RAM with no memory mapped hardware, for running the
+/// core in test rigs where a program is loaded straight into memory
+pub struct FlatBus {
+    pub memory: Box<[u8; 0x10000]>,
+}
+
+impl FlatBus {
+    pub fn new() -> FlatBus {
+        FlatBus { memory: Box::new([0; 0x10000]) }
+    }
+
+    /// builds a bus with program copied in starting at address
+    pub fn with_program(address: u16, program: &[u8]) -> FlatBus {
+        let mut bus = FlatBus::new();
+        let start = address as usize;
+        bus.memory[start..start + program.len()].copy_from_slice(program);
+        bus
+    }
+}
+
+impl Default for FlatBus {
+    fn default() -> Self {
+        FlatBus::new()
+    }
+}
+
+impl Bus for FlatBus {
+    fn read8(&mut self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    fn write8(&mut self, address: u16, value: u8) {
+        self.memory[address as usize] = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn with_program_copies_to_the_address() {
+        let mut bus = FlatBus::with_program(0x0100, &[0x12, 0x34]);
+        assert_eq!(bus.read8(0x00FF), 0x00);
+        assert_eq!(bus.read16(0x0100), 0x3412);
+    }
+
+    #[test]
+    fn sixteen_bit_accesses_wrap_around_the_address_space() {
+        let mut bus = FlatBus::new();
+        bus.write16(0xFFFF, 0xBEEF);
+        assert_eq!(bus.memory[0xFFFF], 0xEF);
+        assert_eq!(bus.memory[0x0000], 0xBE);
+        assert_eq!(bus.read16(0xFFFF), 0xBEEF);
+    }
+}
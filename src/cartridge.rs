@@ -0,0 +1,603 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::cpu::Event;
+use crate::mbc1::Mbc1;
+use crate::mbc2::{Mbc2, MBC2_RAM_SIZE};
+use crate::mbc3::{Mbc3, Rtc, RTC_FOOTER_SIZE};
+use crate::mbc5::Mbc5;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+const HEADER_END: usize = 0x014F;
+const TITLE_START: usize = 0x0134;
+const TITLE_END: usize = 0x0143;
+const MANUFACTURER_START: usize = 0x013F;
+const MANUFACTURER_END: usize = 0x0142;
+const CGB_FLAG: usize = 0x0143;
+const NEW_LICENSEE_START: usize = 0x0144;
+const NEW_LICENSEE_END: usize = 0x0145;
+const SGB_FLAG: usize = 0x0146;
+const CARTRIDGE_TYPE: usize = 0x0147;
+const ROM_SIZE: usize = 0x0148;
+const RAM_SIZE: usize = 0x0149;
+const DESTINATION_CODE: usize = 0x014A;
+const OLD_LICENSEE: usize = 0x014B;
+const VERSION: usize = 0x014C;
+const HEADER_CHECKSUM: usize = 0x014D;
+const GLOBAL_CHECKSUM_START: usize = 0x014E;
+const GLOBAL_CHECKSUM_END: usize = 0x014F;
+
+/// an old licensee code of 0x33 means the new licensee code is used instead
+const USE_NEW_LICENSEE: u8 = 0x33;
+
+/// Everything that can go wrong while loading a ROM image
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(io::Error),
+    /// the image is too small to hold the header at 0x0100-0x014F
+    MissingHeader { length: usize },
+    InvalidRomSize(u8),
+    InvalidRamSize(u8),
+    UnknownCartridgeType(u8),
+    /// the header is valid, but the bank controller isn't emulated
+    UnsupportedCartridgeType(CartridgeType),
+    /// the image size doesn't match the ROM size in the header
+    RomSizeMismatch { expected: usize, actual: usize },
+    /// the boot ROM refuses to start a cartridge with a bad header checksum
+    HeaderChecksumMismatch { expected: u8, computed: u8 },
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::Io(err) => write!(f, "failed to read ROM: {}", err),
+            CartridgeError::MissingHeader { length } => write!(
+                f,
+                "ROM is {} bytes, too small to contain a header (needs at least {:#x})",
+                length,
+                HEADER_END + 1
+            ),
+            CartridgeError::InvalidRomSize(code) => write!(f, "invalid ROM size code {:#04x}", code),
+            CartridgeError::InvalidRamSize(code) => write!(f, "invalid RAM size code {:#04x}", code),
+            CartridgeError::UnknownCartridgeType(code) => write!(f, "unknown cartridge type {:#04x}", code),
+            CartridgeError::UnsupportedCartridgeType(cartridge_type) => {
+                write!(f, "{:?} cartridges are not supported", cartridge_type.mbc)
+            },
+            CartridgeError::RomSizeMismatch { expected, actual } => write!(
+                f,
+                "header declares a {} byte ROM but the image is {} bytes",
+                expected, actual
+            ),
+            CartridgeError::HeaderChecksumMismatch { expected, computed } => write!(
+                f,
+                "header checksum is {:#04x} but the header sums to {:#04x}",
+                expected, computed
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+impl From<io::Error> for CartridgeError {
+    fn from(err: io::Error) -> Self {
+        CartridgeError::Io(err)
+    }
+}
+
+/// The memory bank controller family picked by the cartridge type byte
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MbcKind {
+    None,
+    Mbc1,
+    Mbc2,
+    Mmm01,
+    Mbc3,
+    Mbc5,
+    Mbc6,
+    Mbc7,
+    PocketCamera,
+    Tama5,
+    HuC3,
+    HuC1,
+}
+
+/// Decoded cartridge type byte at 0x0147
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CartridgeType {
+    pub code: u8,
+    pub mbc: MbcKind,
+    pub ram: bool,
+    pub battery: bool,
+    pub timer: bool,
+    pub rumble: bool,
+}
+
+impl CartridgeType {
+    pub fn from_byte(code: u8) -> Option<CartridgeType> {
+            //       mbc                    ram    batt   timer  rumble
+        let (mbc, ram, battery, timer, rumble) = match code {
+            0x00 => (MbcKind::None,         false, false, false, false),
+            0x01 => (MbcKind::Mbc1,         false, false, false, false),
+            0x02 => (MbcKind::Mbc1,         true,  false, false, false),
+            0x03 => (MbcKind::Mbc1,         true,  true,  false, false),
+            0x05 => (MbcKind::Mbc2,         false, false, false, false),
+            0x06 => (MbcKind::Mbc2,         false, true,  false, false),
+            0x08 => (MbcKind::None,         true,  false, false, false),
+            0x09 => (MbcKind::None,         true,  true,  false, false),
+            0x0B => (MbcKind::Mmm01,        false, false, false, false),
+            0x0C => (MbcKind::Mmm01,        true,  false, false, false),
+            0x0D => (MbcKind::Mmm01,        true,  true,  false, false),
+            0x0F => (MbcKind::Mbc3,         false, true,  true,  false),
+            0x10 => (MbcKind::Mbc3,         true,  true,  true,  false),
+            0x11 => (MbcKind::Mbc3,         false, false, false, false),
+            0x12 => (MbcKind::Mbc3,         true,  false, false, false),
+            0x13 => (MbcKind::Mbc3,         true,  true,  false, false),
+            0x19 => (MbcKind::Mbc5,         false, false, false, false),
+            0x1A => (MbcKind::Mbc5,         true,  false, false, false),
+            0x1B => (MbcKind::Mbc5,         true,  true,  false, false),
+            0x1C => (MbcKind::Mbc5,         false, false, false, true),
+            0x1D => (MbcKind::Mbc5,         true,  false, false, true),
+            0x1E => (MbcKind::Mbc5,         true,  true,  false, true),
+            0x20 => (MbcKind::Mbc6,         true,  true,  false, false),
+            0x22 => (MbcKind::Mbc7,         true,  true,  false, true),
+            0xFC => (MbcKind::PocketCamera, true,  true,  false, false),
+            0xFD => (MbcKind::Tama5,        true,  true,  true,  false),
+            0xFE => (MbcKind::HuC3,         true,  true,  true,  false),
+            0xFF => (MbcKind::HuC1,         true,  true,  false, false),
+            _ => return None,
+        };
+        Some(CartridgeType { code, mbc, ram, battery, timer, rumble })
+    }
+}
+
+/// Colour support declared at 0x0143
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CgbSupport {
+    DmgOnly,
+    /// runs on both, with colour on a CGB
+    Enhanced,
+    CgbOnly,
+}
+
+/// The cartridge header at 0x0100-0x014F
+#[derive(Clone, Debug)]
+pub struct Header {
+    pub title: String,
+    /// only present on later cartridges, which shortened the title to make room
+    pub manufacturer_code: Option<String>,
+    pub cgb: CgbSupport,
+    pub sgb: bool,
+    pub cartridge_type: CartridgeType,
+    pub rom_size: usize,
+    pub ram_size: usize,
+    /// false for Japan, true for everywhere else
+    pub overseas: bool,
+    pub old_licensee_code: u8,
+    /// only used when the old licensee code is 0x33
+    pub new_licensee_code: Option<String>,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+impl Header {
+    /// parses and validates the header, rom must be at least 0x150 bytes long
+    pub fn parse(rom: &[u8]) -> Result<Header, CartridgeError> {
+        if rom.len() <= HEADER_END {
+            return Err(CartridgeError::MissingHeader { length: rom.len() });
+        }
+
+        let computed = header_checksum(rom);
+        if computed != rom[HEADER_CHECKSUM] {
+            return Err(CartridgeError::HeaderChecksumMismatch {
+                expected: rom[HEADER_CHECKSUM],
+                computed,
+            });
+        }
+
+        let cgb = match rom[CGB_FLAG] {
+            0x80 => CgbSupport::Enhanced,
+            0xC0 => CgbSupport::CgbOnly,
+            _ => CgbSupport::DmgOnly,
+        };
+
+        // later cartridges shortened the title to make room for a manufacturer
+        // code, and CGB cartridges always give up the last byte to the CGB flag
+        let code = &rom[MANUFACTURER_START..=MANUFACTURER_END];
+        let manufacturer_code = match cgb {
+            CgbSupport::DmgOnly => None,
+            _ if code.iter().all(|byte| byte.is_ascii_uppercase()) => Some(ascii_string(code)),
+            _ => None,
+        };
+        let title_end = match (&manufacturer_code, cgb) {
+            (Some(_), _) => MANUFACTURER_START - 1,
+            (None, CgbSupport::DmgOnly) => TITLE_END,
+            (None, _) => CGB_FLAG - 1,
+        };
+
+        let rom_size_code = rom[ROM_SIZE];
+        if rom_size_code > 0x08 {
+            return Err(CartridgeError::InvalidRomSize(rom_size_code));
+        }
+        let rom_size = (2 * ROM_BANK_SIZE) << rom_size_code;
+
+        let ram_size_code = rom[RAM_SIZE];
+        let ram_size = match ram_size_code {
+            0x00 => 0,
+            0x01 => 2 * 1024,
+            0x02 => RAM_BANK_SIZE,
+            0x03 => 4 * RAM_BANK_SIZE,
+            0x04 => 16 * RAM_BANK_SIZE,
+            0x05 => 8 * RAM_BANK_SIZE,
+            _ => return Err(CartridgeError::InvalidRamSize(ram_size_code)),
+        };
+
+        let cartridge_type = CartridgeType::from_byte(rom[CARTRIDGE_TYPE])
+            .ok_or(CartridgeError::UnknownCartridgeType(rom[CARTRIDGE_TYPE]))?;
+
+        let old_licensee_code = rom[OLD_LICENSEE];
+        let new_licensee_code = if old_licensee_code == USE_NEW_LICENSEE {
+            Some(ascii_string(&rom[NEW_LICENSEE_START..=NEW_LICENSEE_END]))
+        } else {
+            None
+        };
+
+        Ok(Header {
+            title: ascii_string(&rom[TITLE_START..=title_end]),
+            manufacturer_code,
+            cgb,
+            sgb: rom[SGB_FLAG] == 0x03,
+            cartridge_type,
+            rom_size,
+            ram_size,
+            overseas: rom[DESTINATION_CODE] != 0x00,
+            old_licensee_code,
+            new_licensee_code,
+            version: rom[VERSION],
+            header_checksum: rom[HEADER_CHECKSUM],
+            global_checksum: u16::from_be_bytes([rom[GLOBAL_CHECKSUM_START], rom[GLOBAL_CHECKSUM_END]]),
+        })
+    }
+}
+
+/// the boot ROM's checksum over 0x0134-0x014C
+pub fn header_checksum(rom: &[u8]) -> u8 {
+    rom[TITLE_START..=VERSION]
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_sub(*byte).wrapping_sub(1))
+}
+
+/// sum of every byte in the image except the global checksum itself.
+/// nothing on hardware checks this, so a mismatch is not an error
+pub fn global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|(address, _)| !(GLOBAL_CHECKSUM_START..=GLOBAL_CHECKSUM_END).contains(address))
+        .fold(0u16, |sum, (_, byte)| sum.wrapping_add(*byte as u16))
+}
+
+/// header strings are padded with zeros
+fn ascii_string(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take_while(|byte| **byte != 0)
+        .map(|byte| if byte.is_ascii_graphic() || *byte == b' ' { *byte as char } else { '?' })
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+/// Banking logic of a cartridge. The ROM and RAM are owned by the
+/// [Cartridge] and handed in, the controller only tracks its registers,
+/// which it saves as part of the cartridge's save state
+pub trait Mbc: SaveState {
+    /// reads from 0x0000-0x7FFF
+    fn read_rom(&self, rom: &[u8], address: u16) -> u8;
+    /// writes to 0x0000-0x7FFF, which set the controller's registers
+    fn write_rom(&mut self, address: u16, value: u8);
+    /// reads from 0xA000-0xBFFF
+    fn read_ram(&self, ram: &[u8], address: u16) -> u8;
+    /// writes to 0xA000-0xBFFF, returns false if the write was dropped
+    fn write_ram(&mut self, ram: &mut [u8], address: u16, value: u8) -> bool;
+
+    /// machine cycles passed, for controllers with their own clock
+    fn tick(&mut self, _cycles: u32) {}
+
+    fn rtc(&mut self) -> Option<&mut Rtc> {
+        None
+    }
+
+    /// moves anything the cartridge hardware wants to report into events
+    fn drain_events(&mut self, _events: &mut Vec<Event>) {}
+}
+
+/// reads offset from a 16KiB ROM bank, bank numbers past the end of the
+/// ROM wrap around like the unconnected address lines on hardware
+pub fn rom_bank_byte(rom: &[u8], bank: usize, offset: usize) -> u8 {
+    if rom.is_empty() {
+        return 0xFF;
+    }
+    rom[(bank * ROM_BANK_SIZE + offset) % rom.len()]
+}
+
+/// same as [rom_bank_byte] for 8KiB RAM banks, reads with no RAM fitted return 0xFF
+pub fn ram_bank_index(ram: &[u8], bank: usize, offset: usize) -> Option<usize> {
+    if ram.is_empty() {
+        return None;
+    }
+    Some((bank * RAM_BANK_SIZE + offset) % ram.len())
+}
+
+/// 32KiB of ROM wired straight to the bus, optionally with up to 8KiB of RAM
+pub struct NoMbc;
+
+impl Mbc for NoMbc {
+    fn read_rom(&self, rom: &[u8], address: u16) -> u8 {
+        rom.get(address as usize).copied().unwrap_or(0xFF)
+    }
+
+    fn write_rom(&mut self, _address: u16, _value: u8) {}
+
+    fn read_ram(&self, ram: &[u8], address: u16) -> u8 {
+        match ram_bank_index(ram, 0, (address & 0x1FFF) as usize) {
+            Some(index) => ram[index],
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], address: u16, value: u8) -> bool {
+        match ram_bank_index(ram, 0, (address & 0x1FFF) as usize) {
+            Some(index) => {
+                ram[index] = value;
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+impl SaveState for NoMbc {
+    fn save_state(&self, _writer: &mut StateWriter) {}
+
+    fn load_state(&mut self, _reader: &mut StateReader) -> Result<(), StateError> {
+        Ok(())
+    }
+}
+
+/// A loaded ROM image along with its external RAM and bank controller
+pub struct Cartridge {
+    pub header: Header,
+    rom: Vec<u8>,
+    pub ram: Vec<u8>,
+    /// set by RAM writes the controller stored, so battery saves know when to flush
+    ram_dirty: bool,
+    mbc: Box<dyn Mbc>,
+}
+
+impl Cartridge {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Cartridge, CartridgeError> {
+        Cartridge::from_bytes(fs::read(path)?)
+    }
+
+    pub fn from_bytes(rom: Vec<u8>) -> Result<Cartridge, CartridgeError> {
+        let header = Header::parse(&rom)?;
+        if rom.len() != header.rom_size {
+            return Err(CartridgeError::RomSizeMismatch {
+                expected: header.rom_size,
+                actual: rom.len(),
+            });
+        }
+
+        let mbc: Box<dyn Mbc> = match header.cartridge_type.mbc {
+            MbcKind::None => Box::new(NoMbc),
+            MbcKind::Mbc1 => Box::new(Mbc1::new(&rom)),
+            MbcKind::Mbc2 => Box::new(Mbc2::new()),
+            MbcKind::Mbc3 => Box::new(Mbc3::new(header.cartridge_type.timer)),
+            MbcKind::Mbc5 => Box::new(Mbc5::new(header.cartridge_type.rumble)),
+            _ => return Err(CartridgeError::UnsupportedCartridgeType(header.cartridge_type)),
+        };
+
+        let ram_size = match header.cartridge_type.mbc {
+            // MBC2's RAM is inside the controller, so the header says there is none
+            MbcKind::Mbc2 => MBC2_RAM_SIZE,
+            _ if header.cartridge_type.ram => header.ram_size,
+            _ => 0,
+        };
+
+        Ok(Cartridge {
+            header,
+            rom,
+            ram: vec![0; ram_size],
+            ram_dirty: false,
+            mbc,
+        })
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    pub fn global_checksum_valid(&self) -> bool {
+        global_checksum(&self.rom) == self.header.global_checksum
+    }
+
+    pub fn read_rom(&self, address: u16) -> u8 {
+        self.mbc.read_rom(&self.rom, address)
+    }
+
+    pub fn write_rom(&mut self, address: u16, value: u8) {
+        self.mbc.write_rom(address, value);
+    }
+
+    pub fn read_ram(&self, address: u16) -> u8 {
+        self.mbc.read_ram(&self.ram, address)
+    }
+
+    pub fn write_ram(&mut self, address: u16, value: u8) {
+        if self.mbc.write_ram(&mut self.ram, address, value) {
+            self.ram_dirty = true;
+        }
+    }
+
+    pub fn ram_dirty(&self) -> bool {
+        self.ram_dirty
+    }
+
+    pub fn clear_ram_dirty(&mut self) {
+        self.ram_dirty = false;
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        self.mbc.tick(cycles);
+    }
+
+    pub fn drain_events(&mut self, events: &mut Vec<Event>) {
+        self.mbc.drain_events(events);
+    }
+
+    /// the cartridge's real time clock, if it has one
+    pub fn rtc(&mut self) -> Option<&mut Rtc> {
+        self.mbc.rtc()
+    }
+
+    /// the battery backed RAM followed by the RTC footer on carts with a clock
+    pub fn battery_data(&mut self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        if let Some(rtc) = self.mbc.rtc() {
+            data.extend(rtc.to_footer());
+        }
+        data
+    }
+
+    /// restores data written by [battery_data](Cartridge::battery_data)
+    pub fn load_battery_data(&mut self, data: &[u8]) {
+        let ram_size = self.ram.len().min(data.len());
+        self.ram[..ram_size].copy_from_slice(&data[..ram_size]);
+        if let Some(rtc) = self.mbc.rtc() {
+            let footer = &data[ram_size..];
+            rtc.load_footer(&footer[..footer.len().min(RTC_FOOTER_SIZE)]);
+        }
+    }
+}
+
+impl SaveState for Cartridge {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.sized_bytes(&self.ram);
+        self.mbc.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        let ram = reader.sized_bytes()?;
+        if ram.len() != self.ram.len() {
+            return Err(StateError::InvalidValue("cartridge RAM size"));
+        }
+        self.ram.copy_from_slice(ram);
+        self.mbc.load_state(reader)
+    }
+}
+
+/// a blank image of the size the header declares, with a valid header
+/// checksum, for tests that need a cartridge to plug in
+#[cfg(test)]
+pub fn test_rom(cartridge_type: u8, rom_size_code: u8, ram_size_code: u8) -> Vec<u8> {
+    let mut rom = vec![0; (2 * ROM_BANK_SIZE) << rom_size_code];
+    rom[CARTRIDGE_TYPE] = cartridge_type;
+    rom[ROM_SIZE] = rom_size_code;
+    rom[RAM_SIZE] = ram_size_code;
+    rom[HEADER_CHECKSUM] = header_checksum(&rom);
+    rom
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a ROM-only image with cgb at 0x0143, then title written from 0x0134
+    fn titled_rom(title: &[u8], cgb: u8) -> Vec<u8> {
+        let mut rom = test_rom(0x00, 0x00, 0x00);
+        rom[CGB_FLAG] = cgb;
+        rom[TITLE_START..TITLE_START + title.len()].copy_from_slice(title);
+        rom[HEADER_CHECKSUM] = header_checksum(&rom);
+        rom
+    }
+
+    #[test]
+    fn rom_only_cartridge_loads() {
+        let cartridge = Cartridge::from_bytes(test_rom(0x00, 0x00, 0x00)).unwrap();
+        assert_eq!(cartridge.header.cartridge_type.mbc, MbcKind::None);
+        assert_eq!(cartridge.header.rom_size, 0x8000);
+        assert_eq!(cartridge.read_ram(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn bad_header_checksum_is_rejected() {
+        let mut rom = test_rom(0x00, 0x00, 0x00);
+        rom[HEADER_CHECKSUM] ^= 0xFF;
+        assert!(matches!(
+            Cartridge::from_bytes(rom),
+            Err(CartridgeError::HeaderChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn rom_size_mismatch_is_rejected() {
+        let mut rom = test_rom(0x00, 0x00, 0x00);
+        rom.truncate(0x4000);
+        assert!(matches!(
+            Cartridge::from_bytes(rom),
+            Err(CartridgeError::RomSizeMismatch { expected: 0x8000, actual: 0x4000 })
+        ));
+    }
+
+    #[test]
+    fn unknown_cartridge_type_is_rejected() {
+        assert!(matches!(
+            Cartridge::from_bytes(test_rom(0x04, 0x00, 0x00)),
+            Err(CartridgeError::UnknownCartridgeType(0x04))
+        ));
+    }
+
+    #[test]
+    fn dmg_title_uses_the_whole_title_area() {
+        let header = Header::parse(&titled_rom(b"POKEMON RED\0ABCD", 0x00)).unwrap();
+        assert_eq!(header.title, "POKEMON RED");
+        let header = Header::parse(&titled_rom(b"SIXTEEN CHAR NAM", 0x00)).unwrap();
+        assert_eq!(header.title, "SIXTEEN CHAR NAM");
+        assert_eq!(header.manufacturer_code, None);
+    }
+
+    #[test]
+    fn cgb_title_stops_before_the_manufacturer_code() {
+        let header = Header::parse(&titled_rom(b"POKEMON CRYABXJ", 0x80)).unwrap();
+        assert_eq!(header.title, "POKEMON CRY");
+        assert_eq!(header.manufacturer_code.as_deref(), Some("ABXJ"));
+        assert_eq!(header.cgb, CgbSupport::Enhanced);
+    }
+
+    #[test]
+    fn cgb_title_without_a_manufacturer_code_stops_before_the_flag() {
+        let header = Header::parse(&titled_rom(b"FIFTEEN CHARS 1", 0xC0)).unwrap();
+        assert_eq!(header.title, "FIFTEEN CHARS 1");
+        assert_eq!(header.manufacturer_code, None);
+        assert_eq!(header.cgb, CgbSupport::CgbOnly);
+    }
+
+    #[test]
+    fn dropped_ram_writes_leave_ram_clean() {
+        // MBC1+RAM+BATTERY with 8KiB of RAM
+        let mut cartridge = Cartridge::from_bytes(test_rom(0x03, 0x00, 0x02)).unwrap();
+        cartridge.write_ram(0xA000, 0x42);
+        assert!(!cartridge.ram_dirty());
+
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_ram(0xA000, 0x42);
+        assert!(cartridge.ram_dirty());
+        assert_eq!(cartridge.read_ram(0xA000), 0x42);
+    }
+}
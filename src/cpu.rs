@@ -1,43 +1,295 @@
-use crate::display;
+use crate::bus::Bus;
+use crate::interrupts::{self, Interrupt};
+use crate::opcodes::decode;
 use crate::registers;
-type Byte = u8;
-// both ram areas are 8KiB in size so using the same type alias makes sense
-type RAMArea = [Byte; 8192];
-type Tile = [u8; 2];
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
-const TILE_DATA_START: u16 = 0x8000;
-const TILE_DATA_END: u16 = 0x97FF;
+/// machine cycles the DMG takes to draw one frame, 154 lines of 114 cycles
+pub const CYCLES_PER_FRAME: u32 = 17556;
 
 /// Represents the state of the CPU
 pub enum CpuState {
     STOP,
     HALT,
     CONTINUE,
+    /// entered by executing one of the unused opcodes, the CPU stops
+    /// fetching instructions until it is reset
+    LOCKED,
 }
 
-pub struct CPU {
-    pub work_ram: RAMArea,
-    pub video_ram: RAMArea,
-    pub display: display::Display,
-    pub stack_ptr: usize,
-    pub program_counter: usize,
+/// Things that happened while stepping that a frontend may want to react to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    InterruptServiced(Interrupt),
+    Halted,
+    Stopped,
+    Locked,
+    /// the PPU finished a frame and entered VBlank
+    FrameReady,
+    /// a rumble cartridge switched its motor on or off
+    Rumble(bool),
+}
+
+/// what a call to [CPU::step] or one of the run functions did
+#[derive(Debug, Default)]
+pub struct StepResult {
+    pub cycles: u32,
+    pub events: Vec<Event>,
+}
+
+/// The SM83 core, generic over the [Bus] it is attached to
+pub struct CPU<B: Bus> {
+    pub bus: B,
+    pub stack_ptr: u16,
+    pub program_counter: u16,
     pub registers: registers::Registers,
     pub state: CpuState,
+    /// Interrupt Master Enable, set by EI/RETI and cleared by DI
+    pub ime: bool,
+    /// EI only sets IME once the instruction after it has finished
+    pub ime_pending: bool,
+    /// set when HALT is executed with IME cleared and an interrupt already
+    /// pending. the CPU does not halt, but fails to increment the PC after
+    /// fetching the next byte, so that byte gets read twice
+    pub halt_bug: bool,
+    /// total machine cycles executed since power on, other subsystems
+    /// are stepped against this
+    pub cycles: u64,
 }
 
-impl CPU {
-    /// moves the PC 2 bytes, returning a u16 of the two passed bytes
+impl<B: Bus> CPU<B> {
+    /// creates a CPU in the state the DMG boot ROM leaves it in,
+    /// ready to start executing the cartridge at 0x0100
+    pub fn new(bus: B) -> CPU<B> {
+        CPU {
+            bus,
+            stack_ptr: 0xFFFE,
+            program_counter: 0x0100,
+            registers: registers::Registers::new(),
+            state: CpuState::CONTINUE,
+            ime: false,
+            ime_pending: false,
+            halt_bug: false,
+            cycles: 0,
+        }
+    }
+
+    /// runs a single instruction, or a single idle cycle while halted, stopped
+    /// or locked up. pending interrupts are serviced before the instruction,
+    /// but not while stopped or locked up
+    pub fn step(&mut self) -> StepResult {
+        let start = self.cycles;
+        let mut events = Vec::new();
+
+        match self.state {
+            // a locked CPU never runs again
+            CpuState::LOCKED => self.tick(1),
+            CpuState::STOP => {
+                if self.bus.stop_wake() {
+                    self.state = CpuState::CONTINUE;
+                }
+                self.tick(1);
+            },
+            CpuState::CONTINUE | CpuState::HALT => {
+                if let Some(interrupt) = interrupts::handle_interrupts(self) {
+                    events.push(Event::InterruptServiced(interrupt));
+                }
+                if let CpuState::HALT = self.state {
+                    self.tick(1);
+                } else {
+                    let opcode = self.get_next_one_byte();
+                    decode(opcode, self);
+                    match self.state {
+                        CpuState::HALT => events.push(Event::Halted),
+                        CpuState::STOP => events.push(Event::Stopped),
+                        CpuState::LOCKED => events.push(Event::Locked),
+                        CpuState::CONTINUE => {},
+                    }
+                }
+            },
+        }
+
+        self.bus.drain_events(&mut events);
+        StepResult {
+            cycles: (self.cycles - start) as u32,
+            events,
+        }
+    }
+
+    /// steps until at least cycles machine cycles have passed. the last
+    /// instruction may run a few cycles over
+    pub fn run_for_cycles(&mut self, cycles: u32) -> StepResult {
+        let mut result = StepResult::default();
+        while result.cycles < cycles {
+            let step = self.step();
+            result.cycles += step.cycles;
+            result.events.extend(step.events);
+        }
+        result
+    }
+
+    /// steps until the bus reports a finished frame. with the LCD off no
+    /// frame ever finishes, so this gives up after a frame's worth of cycles
+    pub fn run_until_frame(&mut self) -> StepResult {
+        let mut result = StepResult::default();
+        while result.cycles < CYCLES_PER_FRAME {
+            let step = self.step();
+            result.cycles += step.cycles;
+            let frame_ready = step.events.contains(&Event::FrameReady);
+            result.events.extend(step.events);
+            if frame_ready {
+                break;
+            }
+        }
+        result
+    }
+
+    /// advances the cycle counter and lets the bus catch up
+    pub fn tick(&mut self, cycles: u8) {
+        self.cycles += cycles as u64;
+        self.bus.tick(cycles as u32);
+    }
+
+    /// moves the PC 2 bytes, returning a u16 of the two passed bytes.
+    /// operands are stored little endian, so the first byte is the low half
     pub fn get_next_two_bytes(&mut self) -> u16 {
-        self.program_counter += 1;
-        let lo = self.work_ram[self.program_counter];
-        self.program_counter += 1;
-        let hi = self.work_ram[self.program_counter];
-        return u16::from_le_bytes([hi, lo]);
+        let lo = self.get_next_one_byte();
+        let hi = self.get_next_one_byte();
+        u16::from_le_bytes([lo, hi])
     }
 
-    /// moves the PC 1 byte, returning a u8 of the passed byte
+    /// moves the PC 1 byte, returning a u8 of the passed byte.
+    /// the PC always points at the next byte to be fetched
     pub fn get_next_one_byte(&mut self) -> u8 {
-        self.program_counter += 1;
-        return self.work_ram[self.program_counter];
+        let value = self.bus.read8(self.program_counter);
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.program_counter = self.program_counter.wrapping_add(1);
+        }
+        value
+    }
+}
+
+impl<B: Bus> SaveState for CPU<B> {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.registers.save_state(writer);
+        writer.u16(self.stack_ptr);
+        writer.u16(self.program_counter);
+        writer.u8(match self.state {
+            CpuState::STOP => 0,
+            CpuState::HALT => 1,
+            CpuState::CONTINUE => 2,
+            CpuState::LOCKED => 3,
+        });
+        writer.bool(self.ime);
+        writer.bool(self.ime_pending);
+        writer.bool(self.halt_bug);
+        writer.u64(self.cycles);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.registers.load_state(reader)?;
+        self.stack_ptr = reader.u16()?;
+        self.program_counter = reader.u16()?;
+        self.state = match reader.u8()? {
+            0 => CpuState::STOP,
+            1 => CpuState::HALT,
+            2 => CpuState::CONTINUE,
+            3 => CpuState::LOCKED,
+            _ => return Err(StateError::InvalidValue("CPU state")),
+        };
+        self.ime = reader.bool()?;
+        self.ime_pending = reader.bool()?;
+        self.halt_bug = reader.bool()?;
+        self.cycles = reader.u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::FlatBus;
+    use crate::interrupts::INTERRUPT_ENABLE;
+
+    /// a CPU at 0x0100 with IME set and VBlank enabled
+    fn cpu_with_vblank_enabled(program: &[u8]) -> CPU<FlatBus> {
+        let mut cpu = CPU::new(FlatBus::with_program(0x0100, program));
+        cpu.ime = true;
+        cpu.bus.write8(INTERRUPT_ENABLE, Interrupt::VBlank.mask());
+        cpu
+    }
+
+    #[test]
+    fn step_reports_instruction_cycles_and_events() {
+        // NOP; LD BC,d16; HALT
+        let mut cpu = CPU::new(FlatBus::with_program(0x0100, &[0x00, 0x01, 0x34, 0x12, 0x76]));
+        assert_eq!(cpu.step().cycles, 1);
+        assert_eq!(cpu.step().cycles, 3);
+        assert_eq!(cpu.registers.get_bc(), 0x1234);
+        assert_eq!(cpu.step().events, vec![Event::Halted]);
+        assert_eq!(cpu.step().cycles, 1);
+        assert_eq!(cpu.program_counter, 0x0105);
+    }
+
+    #[test]
+    fn locked_cpu_ignores_interrupts() {
+        // 0xD3 is one of the unused opcodes
+        let mut cpu = cpu_with_vblank_enabled(&[0xD3]);
+        cpu.ime = false;
+        assert_eq!(cpu.step().events, vec![Event::Locked]);
+
+        cpu.ime = true;
+        interrupts::request(Interrupt::VBlank, &mut cpu.bus);
+        let result = cpu.step();
+        assert!(result.events.is_empty());
+        assert_eq!(result.cycles, 1);
+        assert!(matches!(cpu.state, CpuState::LOCKED));
+        assert_eq!(cpu.program_counter, 0x0101);
+    }
+
+    #[test]
+    fn stopped_cpu_ignores_interrupts() {
+        let mut cpu = cpu_with_vblank_enabled(&[0x10, 0x00]);
+        assert_eq!(cpu.step().events, vec![Event::Stopped]);
+
+        interrupts::request(Interrupt::VBlank, &mut cpu.bus);
+        assert!(cpu.step().events.is_empty());
+        assert!(matches!(cpu.state, CpuState::STOP));
+        assert_eq!(cpu.bus.read8(interrupts::INTERRUPT_FLAG), Interrupt::VBlank.mask());
+    }
+
+    #[test]
+    fn halted_cpu_is_woken_and_dispatches() {
+        // HALT
+        let mut cpu = cpu_with_vblank_enabled(&[0x76]);
+        assert_eq!(cpu.step().events, vec![Event::Halted]);
+
+        interrupts::request(Interrupt::VBlank, &mut cpu.bus);
+        let result = cpu.step();
+        assert_eq!(result.events, vec![Event::InterruptServiced(Interrupt::VBlank)]);
+        // dispatch, then the NOP at the vector
+        assert_eq!(result.cycles, 6);
+        assert_eq!(cpu.program_counter, Interrupt::VBlank.vector() + 1);
+    }
+
+    #[test]
+    fn run_for_cycles_finishes_the_last_instruction() {
+        // NOP; JP 0x0100
+        let mut cpu = CPU::new(FlatBus::with_program(0x0100, &[0x00, 0xC3, 0x00, 0x01]));
+        let result = cpu.run_for_cycles(7);
+        assert_eq!(result.cycles, 10);
+        assert_eq!(cpu.cycles, 10);
+        assert_eq!(cpu.program_counter, 0x0100);
+    }
+
+    #[test]
+    fn run_until_frame_gives_up_without_a_frame() {
+        // JR -2
+        let mut cpu = CPU::new(FlatBus::with_program(0x0100, &[0x18, 0xFE]));
+        let result = cpu.run_until_frame();
+        assert!(result.cycles >= CYCLES_PER_FRAME);
+        assert!(!result.events.contains(&Event::FrameReady));
     }
 }
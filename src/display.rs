@@ -1,6 +1,60 @@
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+/// The frame the PPU draws into, one shade index per pixel
+/// from 0 (lightest) to 3 (darkest)
 pub struct Display {
-    /// the definition of the display pixels
-    display: [[u8; 160]; 144],
-    // 8x8 areas of the display
-    tiles: [[u8; 20]; 18],
+    pixels: [[u8; SCREEN_WIDTH]; SCREEN_HEIGHT],
+}
+
+impl Display {
+    pub fn new() -> Display {
+        Display {
+            pixels: [[0; SCREEN_WIDTH]; SCREEN_HEIGHT],
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        self.pixels[y][x]
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, shade: u8) {
+        self.pixels[y][x] = shade;
+    }
+
+    /// the whole frame, row by row
+    pub fn rows(&self) -> &[[u8; SCREEN_WIDTH]; SCREEN_HEIGHT] {
+        &self.pixels
+    }
+
+    /// what the LCD shows while it is off or blanked
+    pub fn clear(&mut self) {
+        self.pixels = [[0; SCREEN_WIDTH]; SCREEN_HEIGHT];
+    }
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Display::new()
+    }
+}
+
+impl SaveState for Display {
+    fn save_state(&self, writer: &mut StateWriter) {
+        for row in &self.pixels {
+            writer.bytes(row);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        for row in self.pixels.iter_mut() {
+            reader.bytes_into(row)?;
+            if row.iter().any(|shade| *shade > 3) {
+                return Err(StateError::InvalidValue("pixel shade"));
+            }
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,190 @@
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+/// writing the high byte of a source address here starts a transfer
+pub const DMA: u16 = 0xFF46;
+
+/// bytes copied by one transfer, all of OAM
+const TRANSFER_LENGTH: u8 = 160;
+
+/// OAM DMA. Copies 160 bytes from XX00-XX9F to OAM, one byte per machine
+/// cycle after one cycle of setup. While the copy runs the CPU can only
+/// reach HRAM
+pub struct Dma {
+    /// the last value written to 0xFF46, which is also what it reads back
+    register: u8,
+    /// a write was seen and the transfer starts after the current cycle
+    starting: bool,
+    /// bytes copied so far, None while no transfer is running
+    progress: Option<u8>,
+    /// the byte on the bus the transfer is using, which is what
+    /// the CPU sees when it reads from the same bus
+    bus_value: u8,
+}
+
+impl Dma {
+    pub fn new() -> Dma {
+        Dma {
+            register: 0xFF,
+            starting: false,
+            progress: None,
+            bus_value: 0xFF,
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        self.register
+    }
+
+    /// starts a transfer one cycle from now, restarting any that is
+    /// already running. the old one keeps the bus during that cycle
+    pub fn write(&mut self, value: u8) {
+        self.register = value;
+        self.starting = true;
+    }
+
+    /// true while bytes are being copied, which is when the CPU loses the bus
+    pub fn active(&self) -> bool {
+        self.progress.is_some()
+    }
+
+    /// true while a transfer is copying or about to start
+    pub fn busy(&self) -> bool {
+        self.starting || self.active()
+    }
+
+    /// ends a machine cycle, starting a transfer set up during it
+    pub fn end_cycle(&mut self) {
+        if self.starting {
+            self.starting = false;
+            self.progress = Some(0);
+        }
+    }
+
+    pub fn bus_value(&self) -> u8 {
+        self.bus_value
+    }
+
+    /// the source address and OAM index of the next byte to copy, if any.
+    /// sources above 0xDFFF see echo RAM, just like the CPU does
+    pub fn next_transfer(&self) -> Option<(u16, usize)> {
+        let index = self.progress?;
+        let page = if self.register >= 0xE0 {
+            self.register - 0x20
+        } else {
+            self.register
+        };
+        Some((u16::from_be_bytes([page, index]), index as usize))
+    }
+
+    /// records a byte as copied, ending the transfer after the last one
+    pub fn transferred(&mut self, value: u8) {
+        self.bus_value = value;
+        self.progress = match self.progress {
+            Some(index) if index + 1 < TRANSFER_LENGTH => Some(index + 1),
+            _ => None,
+        };
+    }
+
+    /// true for addresses the CPU can still reach while a transfer runs
+    pub fn cpu_accessible(address: u16) -> bool {
+        (0xFF80..=0xFFFE).contains(&address)
+    }
+}
+
+impl Default for Dma {
+    fn default() -> Self {
+        Dma::new()
+    }
+}
+
+impl SaveState for Dma {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.register);
+        writer.bool(self.starting);
+        writer.bool(self.progress.is_some());
+        writer.u8(self.progress.unwrap_or(0));
+        writer.u8(self.bus_value);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.register = reader.u8()?;
+        self.starting = reader.bool()?;
+        let active = reader.bool()?;
+        let progress = reader.u8()?;
+        if progress >= TRANSFER_LENGTH {
+            return Err(StateError::InvalidValue("DMA progress"));
+        }
+        self.progress = active.then_some(progress);
+        self.bus_value = reader.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::{test_rom, Cartridge};
+    use crate::mmu::MMU;
+
+    /// an MMU with 0xC000-0xC09F holding 0x01, 0x02 and so on
+    fn mmu_with_source() -> MMU {
+        let mut mmu = MMU::new(Cartridge::from_bytes(test_rom(0x00, 0x00, 0x00)).unwrap());
+        for index in 0..TRANSFER_LENGTH as u16 {
+            mmu.write8(0xC000 + index, index as u8 + 1);
+        }
+        mmu
+    }
+
+    #[test]
+    fn transfer_starts_one_cycle_late_and_takes_160_cycles() {
+        let mut mmu = mmu_with_source();
+        mmu.write8(DMA, 0xC0);
+        assert!(!mmu.dma.active());
+        mmu.tick(1);
+        assert!(mmu.dma.active());
+        assert_eq!(mmu.oam[0], 0);
+
+        mmu.tick(TRANSFER_LENGTH as u32 - 1);
+        assert!(mmu.dma.active());
+        mmu.tick(1);
+        assert!(!mmu.dma.active());
+        assert_eq!(mmu.read8(0xFE00), 0x01);
+        assert_eq!(mmu.read8(0xFE9F), 0xA0);
+    }
+
+    #[test]
+    fn blocked_reads_see_the_byte_on_the_bus() {
+        let mut mmu = mmu_with_source();
+        mmu.write8(0xFF80, 0x99);
+        mmu.write8(DMA, 0xC0);
+        mmu.tick(1 + 5);
+        // the fifth byte copied was 0x05
+        assert_eq!(mmu.read8(0x0000), 0x05);
+        assert_eq!(mmu.read8(0xC0FF), 0x05);
+        assert_eq!(mmu.read8(0xFF46), 0x05);
+
+        mmu.write8(0xC0FF, 0x77);
+        mmu.tick(TRANSFER_LENGTH as u32);
+        assert_eq!(mmu.read8(0xC0FF), 0x00);
+    }
+
+    #[test]
+    fn hram_stays_reachable() {
+        let mut mmu = mmu_with_source();
+        mmu.write8(DMA, 0xC0);
+        mmu.tick(2);
+        mmu.write8(0xFF80, 0x42);
+        assert_eq!(mmu.read8(0xFF80), 0x42);
+        mmu.write8(0xFFFE, 0x24);
+        assert_eq!(mmu.read8(0xFFFE), 0x24);
+    }
+
+    #[test]
+    fn echo_ram_sources_read_work_ram() {
+        let mut mmu = mmu_with_source();
+        mmu.write8(DMA, 0xE0);
+        mmu.tick(1 + TRANSFER_LENGTH as u32);
+        assert_eq!(mmu.oam[..3], [0x01, 0x02, 0x03]);
+    }
+}
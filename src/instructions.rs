@@ -1,4 +1,6 @@
 use crate::cpu::CPU;
+use crate::bus::Bus;
+use crate::registers::Flags;
 
 /// Load register with value. value can be from a 8-bit register
 /// or it can be an immediate value
@@ -12,255 +14,311 @@ pub fn ld16(register: &mut u16, value: u16) {
     *register = value;
 }
 
-/// loads the value in work ram[address] into register.
-/// invalid memory accesses will cause a panic
-pub fn ld_from_memory(register: &mut u8, address: usize, ram: [u8; 8192]) {
-    if address >= ram.len() { 
-        panic!("Address {:#x} outside of valid memory range. Max range {:#x}", address, ram.len()); 
-    }
-    *register = ram[address];
+/// loads the value at address into register
+pub fn ld_from_memory<B: Bus>(register: &mut u8, address: u16, bus: &mut B) {
+    *register = bus.read8(address);
 }
 
-/// loads the value of register into work ram[address]
-pub fn ld_to_memory(register: u8, address: usize, cpu: &mut CPU) {
-    if address >= cpu.work_ram.len() { 
-        panic!("Address {:#x} outside of valid memory range. Max range {:#x}", address, cpu.work_ram.len()); 
-    }
-    cpu.work_ram[address] = register;
+/// loads the value of register into memory at address
+pub fn ld_to_memory<B: Bus>(register: u8, address: u16, cpu: &mut CPU<B>) {
+    cpu.bus.write8(address, register);
 }
 
-pub fn inc8(register: &mut u8) { *register += 1; }
-pub fn dec8(register: &mut u8) { *register -= 1;}
+/// increment register, the carry flag is left untouched
+pub fn inc8(register: &mut u8, flags: &mut Flags) {
+    flags.h = (*register & 0xF) == 0xF;
+    *register = register.wrapping_add(1);
+    flags.z = *register == 0;
+    flags.n = false;
+}
+/// decrement register, the carry flag is left untouched
+pub fn dec8(register: &mut u8, flags: &mut Flags) {
+    flags.h = (*register & 0xF) == 0;
+    *register = register.wrapping_sub(1);
+    flags.z = *register == 0;
+    flags.n = true;
+}
 
-/// push 16-bit register onto stack
-pub fn push(register: &mut u16, cpu: &mut CPU) {
-    let most_significant = ((*register >> 8) & 0xFF) as u8;
-    let least_significant = (*register & 0xFF) as u8;
-    cpu.stack_ptr -= 1;
-    cpu.work_ram[cpu.stack_ptr] = most_significant;
-    cpu.stack_ptr -= 1;
-    cpu.work_ram[cpu.stack_ptr] = least_significant;
+/// push 16-bit register onto stack, high byte first so that
+/// the value ends up little endian in memory
+pub fn push<B: Bus>(register: u16, cpu: &mut CPU<B>) {
+    let most_significant = ((register >> 8) & 0xFF) as u8;
+    let least_significant = (register & 0xFF) as u8;
+    cpu.stack_ptr = cpu.stack_ptr.wrapping_sub(1);
+    cpu.bus.write8(cpu.stack_ptr, most_significant);
+    cpu.stack_ptr = cpu.stack_ptr.wrapping_sub(1);
+    cpu.bus.write8(cpu.stack_ptr, least_significant);
 }
 
 /// pop 16-bit register off of stack
-pub fn pop(register: &mut u16, cpu: &mut CPU) {
-    let most_significant = cpu.work_ram[cpu.stack_ptr] as u16;
-    cpu.stack_ptr += 1;
-    let least_significant = cpu.work_ram[cpu.stack_ptr] as u16;
+pub fn pop<B: Bus>(register: &mut u16, cpu: &mut CPU<B>) {
+    let least_significant = cpu.bus.read8(cpu.stack_ptr) as u16;
+    cpu.stack_ptr = cpu.stack_ptr.wrapping_add(1);
+    let most_significant = cpu.bus.read8(cpu.stack_ptr) as u16;
     *register = (most_significant << 8) | least_significant;
-    cpu.stack_ptr += 1;
+    cpu.stack_ptr = cpu.stack_ptr.wrapping_add(1);
 }
 
-/// add value of register B into register A
-/// register B could also be an immediate value
-pub fn add8(register_a: &mut u8, register_b: u8, cpu: &mut CPU) {
-    let (res, _) = register_a.overflowing_add(register_b);
-    *register_a = res;
+/// add value into register A
+/// value could be another register or an immediate value
+pub fn add8<B: Bus>(value: u8, cpu: &mut CPU<B>) {
+    let register_a = cpu.registers.a;
+    let (res, carry) = register_a.overflowing_add(value);
+    cpu.registers.a = res;
 
-    cpu.registers.flags.z = *register_a == 0;
+    cpu.registers.flags.z = res == 0;
     cpu.registers.flags.n = false;
-    cpu.registers.flags.h = ((*register_a & 0xF) + (register_b & 0xF)) > 0xF;
+    cpu.registers.flags.h = ((register_a & 0xF) + (value & 0xF)) > 0xF;
+    cpu.registers.flags.c = carry;
 }
-pub fn add16(register_a: &mut u16, register_b: u16, cpu: &mut CPU) {
-    let (res, _) = register_a.overflowing_add(register_b);
-    *register_a = res;
+/// add value into register HL, the zero flag is left untouched
+/// and the half carry is taken from bit 11
+pub fn add16<B: Bus>(value: u16, cpu: &mut CPU<B>) {
+    let register_hl = cpu.registers.get_hl();
+    let (res, carry) = register_hl.overflowing_add(value);
+    cpu.registers.set_hl(res);
 
-    cpu.registers.flags.z = *register_a == 0;
     cpu.registers.flags.n = false;
-    cpu.registers.flags.h = ((*register_a & 0xF) + (register_b & 0xF)) > 0xF;
+    cpu.registers.flags.h = ((register_hl & 0xFFF) + (value & 0xFFF)) > 0xFFF;
+    cpu.registers.flags.c = carry;
 }
 
-/// add value at memory address to register, panicing if address is out of bounds
-pub fn add_from_memory(register: &mut u8, address: usize, cpu: &mut CPU) {
-    if address >= cpu.work_ram.len() {
-        panic!("Address {:#x} outside of valid memory range. Max range {:#x}", address, cpu.work_ram.len());
-    }
-    let (res, _) = register.overflowing_add(cpu.work_ram[address]);
-    *register = res;
-
-    cpu.registers.flags.z = *register == 0;
-    cpu.registers.flags.n = false;
-    cpu.registers.flags.h = ((*register & 0xF) + (cpu.work_ram[address] & 0xF)) > 0xF;
+/// add value at memory address to register A
+pub fn add_from_memory<B: Bus>(address: u16, cpu: &mut CPU<B>) {
+    add8(cpu.bus.read8(address), cpu);
 }
 
 /// ADD with carry. If there is overflow, set carry flag to true, else false
-pub fn addc(register: &mut u8, register_b: u8, cpu: &mut CPU) {
-    let carry = if cpu.registers.flags.c { 1 } else { 0 };
-    let (res, overflow) = register.overflowing_add(register_b.wrapping_add(carry));
-    *register = res;
+pub fn addc<B: Bus>(value: u8, cpu: &mut CPU<B>) {
+    let register_a = cpu.registers.a;
+    let carry = cpu.registers.flags.c as u8;
+    let res = register_a.wrapping_add(value).wrapping_add(carry);
+    cpu.registers.a = res;
 
-    cpu.registers.flags.z = *register == 0;
+    cpu.registers.flags.z = res == 0;
     cpu.registers.flags.n = false;
-    cpu.registers.flags.h = ((*register & 0xF) + (register_b & 0xF)) > 0xF;
-    cpu.registers.flags.c = overflow;
+    cpu.registers.flags.h = ((register_a & 0xF) + (value & 0xF) + carry) > 0xF;
+    cpu.registers.flags.c = (register_a as u16 + value as u16 + carry as u16) > 0xFF;
 }
 
 /// ADD from memory with carry. If there is overflow, set carry flag to true, else false
-pub fn addc_from_memory(register: &mut u8, address: usize, cpu: &mut CPU) {
-    let carry = if cpu.registers.flags.c { 1 } else { 0 };
-    let (res, overflow) = register.overflowing_add(cpu.work_ram[address].wrapping_add(carry));
-    *register = res;
-
-    cpu.registers.flags.z = *register == 0;
-    cpu.registers.flags.n = false;
-    cpu.registers.flags.h = ((*register & 0xF) + (cpu.work_ram[address] & 0xF)) > 0xF;
-    cpu.registers.flags.c = overflow;
+pub fn addc_from_memory<B: Bus>(address: u16, cpu: &mut CPU<B>) {
+    addc(cpu.bus.read8(address), cpu);
 }
 
-/// sub the value of register in to register A without carry
-pub fn sub(register: &mut u8, cpu: &mut CPU) {
-    let (res, _) = register.overflowing_sub(cpu.registers.a);
+/// sub value from register A without carry
+pub fn sub<B: Bus>(value: u8, cpu: &mut CPU<B>) {
+    let register_a = cpu.registers.a;
+    let (res, borrow) = register_a.overflowing_sub(value);
     cpu.registers.a = res;
 
-    cpu.registers.flags.z = *register == 0;
+    cpu.registers.flags.z = res == 0;
     cpu.registers.flags.n = true;
-    cpu.registers.flags.h = (*register & 0xF) < (cpu.registers.a & 0xF)
+    cpu.registers.flags.h = (register_a & 0xF) < (value & 0xF);
+    cpu.registers.flags.c = borrow;
 }
 
 /// [sub] with carry
-pub fn sbc(register: &mut u8, cpu: &mut CPU) {
-    let carry = if cpu.registers.flags.c { 1 } else { 0 };
-    let (res, borrow) = register.overflowing_sub(cpu.registers.a);
-    let (res, borrow2) = res.overflowing_sub(carry);
+pub fn sbc<B: Bus>(value: u8, cpu: &mut CPU<B>) {
+    let register_a = cpu.registers.a;
+    let carry = cpu.registers.flags.c as u8;
+    let res = register_a.wrapping_sub(value).wrapping_sub(carry);
+    cpu.registers.a = res;
 
     cpu.registers.flags.z = res == 0;
     cpu.registers.flags.n = true;
-    cpu.registers.flags.h = (cpu.registers.a & 0xF) < (*register & 0xF) || ((res & 0xF) + carry) > (cpu.registers.a & 0xF);
-    cpu.registers.flags.c = borrow || borrow2;
-    cpu.registers.a = res;
+    cpu.registers.flags.h = (register_a & 0xF) < (value & 0xF) + carry;
+    cpu.registers.flags.c = (register_a as u16) < value as u16 + carry as u16;
 }
 
 /// logical AND with register into register A
-pub fn and(register: u8, cpu: &mut CPU) {
+pub fn and<B: Bus>(register: u8, cpu: &mut CPU<B>) {
     cpu.registers.a &= register;
+
+    cpu.registers.flags.z = cpu.registers.a == 0;
+    cpu.registers.flags.n = false;
+    cpu.registers.flags.h = true;
+    cpu.registers.flags.c = false;
 }
 /// logical OR with register into register A
-pub fn or(register: u8, cpu: &mut CPU) {
+pub fn or<B: Bus>(register: u8, cpu: &mut CPU<B>) {
     cpu.registers.a |= register;
+
+    cpu.registers.flags.z = cpu.registers.a == 0;
+    cpu.registers.flags.n = false;
+    cpu.registers.flags.h = false;
+    cpu.registers.flags.c = false;
 }
 /// logical XOR with A into A
-pub fn xor(register: u8, cpu: &mut CPU) {
+pub fn xor<B: Bus>(register: u8, cpu: &mut CPU<B>) {
     cpu.registers.a ^= register;
+
+    cpu.registers.flags.z = cpu.registers.a == 0;
+    cpu.registers.flags.n = false;
+    cpu.registers.flags.h = false;
+    cpu.registers.flags.c = false;
 }
 /// compare, compares register with A.
-/// Effectively a [sub] with the while ignoring the result
-pub fn cp(register: u8, cpu: &mut CPU) {
-    let (_, carry) = register.overflowing_sub(cpu.registers.a);
-    cpu.registers.flags.z = register == 0;
+/// Effectively a [sub] while ignoring the result
+pub fn cp<B: Bus>(register: u8, cpu: &mut CPU<B>) {
+    let register_a = cpu.registers.a;
+    let (res, borrow) = register_a.overflowing_sub(register);
+    cpu.registers.flags.z = res == 0;
     cpu.registers.flags.n = true;
-    cpu.registers.flags.h = (register & 0xF) < (cpu.registers.a & 0xF);
-    cpu.registers.flags.c = carry;
+    cpu.registers.flags.h = (register_a & 0xF) < (register & 0xF);
+    cpu.registers.flags.c = borrow;
 }
 
-/// Jumps to address in register, may panic if attempting to access out of bounds memory
-pub fn jp(register: u16, cpu: &mut CPU) {
-    if register as usize >= cpu.work_ram.len() {
-        panic!("out of bounds jump: attemped to jump to {}", register);
-    }
-    cpu.program_counter = register as usize;
+/// adds a signed immediate to the stack pointer, returning the result.
+/// shared by ADD SP,e8 and LD HL,SP+e8, which set the flags from the
+/// unsigned addition of the low byte
+pub fn add_sp_offset<B: Bus>(offset: i8, cpu: &mut CPU<B>) -> u16 {
+    let sp = cpu.stack_ptr;
+    let unsigned = offset as u8 as u16;
+
+    cpu.registers.flags.z = false;
+    cpu.registers.flags.n = false;
+    cpu.registers.flags.h = ((sp & 0xF) + (unsigned & 0xF)) > 0xF;
+    cpu.registers.flags.c = ((sp & 0xFF) + unsigned) > 0xFF;
+
+    sp.wrapping_add_signed(offset as i16)
 }
 
-/// Jumps to address in 8-bit register relative to program counter
-pub fn jr(offset: i8, cpu: &mut CPU) {
-    let target_address;
-    if offset > 0 {
-        target_address = cpu.program_counter.wrapping_add(offset as usize);
-    }
-    else {
-        target_address = cpu.program_counter.wrapping_sub(offset as usize);
-    }
+/// Jumps to address in register
+pub fn jp<B: Bus>(register: u16, cpu: &mut CPU<B>) {
+    cpu.program_counter = register;
+}
 
-    if target_address >= cpu.work_ram.len() {
-        panic!("Out of bounds jump: attempted to jump to address 0x{:04X}", target_address);
-    }
-    cpu.program_counter = target_address;
+/// Jumps to address in 8-bit register relative to program counter.
+/// the offset is relative to the instruction following the JR
+pub fn jr<B: Bus>(offset: i8, cpu: &mut CPU<B>) {
+    cpu.program_counter = cpu.program_counter.wrapping_add_signed(offset as i16);
 }
 
 /// pushes PC onto stack, then sets PC to address
-pub fn call(address: u16, cpu: &mut CPU) {
-    // push current pc onto the stack. since the stack is &[u8] and pc is u16 
-    // the pc is split into two u8's
-    cpu.stack_ptr -= 1;
-    cpu.work_ram[cpu.stack_ptr] = (cpu.program_counter & 0xFF) as u8;
-    cpu.stack_ptr -= 1;
-    cpu.work_ram[cpu.stack_ptr] = ((cpu.program_counter >> 8) & 0xFF) as u8;
-
-    // check if address is within range
-    // since nothing can be done if the
-    // address is outside of range, the
-    // program panics
-    if address as usize >= cpu.work_ram.len() {
-        panic!("Out of bounds jump: attempted to jump to address 0x{:04X}", address);
-    }
-
-    cpu.program_counter = address as usize;
+pub fn call<B: Bus>(address: u16, cpu: &mut CPU<B>) {
+    // the pc already points past the call, which is where ret needs to come back to
+    push(cpu.program_counter, cpu);
+    cpu.program_counter = address;
 }
 
 /// returns from a subroutine
 /// incrementing the stack ptr
 /// by two in the process
-pub fn ret(cpu: &mut CPU) {
-    cpu.stack_ptr += 1;
-    let low_half = cpu.work_ram[cpu.stack_ptr];
-    cpu.stack_ptr += 1;
-    let high_half = cpu.work_ram[cpu.stack_ptr];
-    
-    cpu.program_counter = u16::from_le_bytes([high_half, low_half]) as usize;
-}
-
-/// increments the program counter by one
-pub fn nop(cpu: &mut CPU) {
-    cpu.program_counter += 1;
+pub fn ret<B: Bus>(cpu: &mut CPU<B>) {
+    let mut address = 0;
+    pop(&mut address, cpu);
+    cpu.program_counter = address;
 }
 
-pub fn rlca(cpu: &mut CPU) {
-    let carry = (cpu.registers.a & 0x80) != 0;
+/// does nothing, the program counter has already moved past the opcode
+pub fn nop<B: Bus>(_cpu: &mut CPU<B>) {}
 
-    cpu.registers.a = (cpu.registers.a << 1) | (carry as u8);
+/// rotate value left, bit 7 goes into both bit 0 and the carry flag
+pub fn rlc<B: Bus>(value: u8, cpu: &mut CPU<B>) -> u8 {
+    let carry = (value & 0x80) != 0;
+    let res = value.rotate_left(1);
+    set_shift_flags(res, carry, cpu);
+    res
+}
+/// rotate value right, bit 0 goes into both bit 7 and the carry flag
+pub fn rrc<B: Bus>(value: u8, cpu: &mut CPU<B>) -> u8 {
+    let carry = (value & 0x01) != 0;
+    let res = value.rotate_right(1);
+    set_shift_flags(res, carry, cpu);
+    res
+}
+/// rotate value left through the carry flag
+pub fn rl<B: Bus>(value: u8, cpu: &mut CPU<B>) -> u8 {
+    let carry = (value & 0x80) != 0;
+    let res = (value << 1) | (cpu.registers.flags.c as u8);
+    set_shift_flags(res, carry, cpu);
+    res
+}
+/// rotate value right through the carry flag
+pub fn rr<B: Bus>(value: u8, cpu: &mut CPU<B>) -> u8 {
+    let carry = (value & 0x01) != 0;
+    let res = (value >> 1) | ((cpu.registers.flags.c as u8) << 7);
+    set_shift_flags(res, carry, cpu);
+    res
+}
+/// arithmetic shift left, bit 0 is cleared
+pub fn sla<B: Bus>(value: u8, cpu: &mut CPU<B>) -> u8 {
+    let carry = (value & 0x80) != 0;
+    let res = value << 1;
+    set_shift_flags(res, carry, cpu);
+    res
+}
+/// arithmetic shift right, bit 7 keeps its value
+pub fn sra<B: Bus>(value: u8, cpu: &mut CPU<B>) -> u8 {
+    let carry = (value & 0x01) != 0;
+    let res = (value >> 1) | (value & 0x80);
+    set_shift_flags(res, carry, cpu);
+    res
+}
+/// logical shift right, bit 7 is cleared
+pub fn srl<B: Bus>(value: u8, cpu: &mut CPU<B>) -> u8 {
+    let carry = (value & 0x01) != 0;
+    let res = value >> 1;
+    set_shift_flags(res, carry, cpu);
+    res
+}
+/// swaps the upper and lower nibbles of value
+pub fn swap<B: Bus>(value: u8, cpu: &mut CPU<B>) -> u8 {
+    let res = value.rotate_left(4);
+    set_shift_flags(res, false, cpu);
+    res
+}
 
-    cpu.registers.flags.z = cpu.registers.a == 0;
+/// every rotate and shift sets Z from the result, the carry from
+/// the bit that was shifted out, and clears N and H
+fn set_shift_flags<B: Bus>(res: u8, carry: bool, cpu: &mut CPU<B>) {
+    cpu.registers.flags.z = res == 0;
     cpu.registers.flags.n = false;
     cpu.registers.flags.h = false;
     cpu.registers.flags.c = carry;
 }
+
+/// test bit of value, setting Z if the bit is 0
+pub fn bit<B: Bus>(bit: u8, value: u8, cpu: &mut CPU<B>) {
+    cpu.registers.flags.z = value & (1 << bit) == 0;
+    cpu.registers.flags.n = false;
+    cpu.registers.flags.h = true;
+}
+/// reset bit of value, flags are unaffected
+pub fn res(bit: u8, value: u8) -> u8 {
+    value & !(1 << bit)
+}
+/// set bit of value, flags are unaffected
+pub fn set(bit: u8, value: u8) -> u8 {
+    value | (1 << bit)
+}
+
+/// the accumulator rotates behave like the CB prefixed versions
+/// on register A, except that Z is always cleared
+pub fn rlca<B: Bus>(cpu: &mut CPU<B>) {
+    cpu.registers.a = rlc(cpu.registers.a, cpu);
+    cpu.registers.flags.z = false;
+}
 /// ex A = b1011010 and carry = 1
 /// = b10110101
 /// = b0110101 and c = 1
-pub fn rla(cpu: &mut CPU) {
-    let carry_after_rotate = (cpu.registers.a & 0x80) != 0;
-    
-    cpu.registers.a = (cpu.registers.a << 1) | (cpu.registers.flags.c as u8);
-
-    cpu.registers.a &= cpu.registers.c;
-    cpu.registers.flags.c = carry_after_rotate;
-
-    cpu.registers.flags.z = cpu.registers.a == 0;
-    cpu.registers.flags.n = false;
-    cpu.registers.flags.h = false;
+pub fn rla<B: Bus>(cpu: &mut CPU<B>) {
+    cpu.registers.a = rl(cpu.registers.a, cpu);
+    cpu.registers.flags.z = false;
 }
 /// rotate contents of register a to the right, with the circular bit
 /// going into carry flag
-pub fn rrca(cpu: &mut CPU) {
-    let carry = cpu.registers.a & 0x01;
-    cpu.registers.a = (cpu.registers.a >> 1) | (cpu.registers.a << 7) & 0xFF;
-    cpu.registers.flags.c = carry == 1;
-
+pub fn rrca<B: Bus>(cpu: &mut CPU<B>) {
+    cpu.registers.a = rrc(cpu.registers.a, cpu);
     cpu.registers.flags.z = false;
-    cpu.registers.flags.n = false;
-    cpu.registers.flags.h = false;
 }
-pub fn rra(cpu: &mut CPU) {
-    let carry_after_rotate = (cpu.registers.a & 0x01) != 0;
-    
-    cpu.registers.a = (cpu.registers.a >> 1) | (cpu.registers.a << 7) & 0xFF;
-    cpu.registers.flags.c = carry_after_rotate;
-
-    cpu.registers.flags.z = cpu.registers.a == 0;
-    cpu.registers.flags.n = false;
-    cpu.registers.flags.h = false;
+pub fn rra<B: Bus>(cpu: &mut CPU<B>) {
+    cpu.registers.a = rr(cpu.registers.a, cpu);
+    cpu.registers.flags.z = false;
 }
 
-pub fn daa(cpu: &mut CPU) {
+pub fn daa<B: Bus>(cpu: &mut CPU<B>) {
     let mut offset: u8 = 0;
 
     let a_val = &mut cpu.registers.a;
@@ -289,6 +347,8 @@ pub fn daa(cpu: &mut CPU) {
     cpu.registers.flags.h = false;
 }
 
-pub fn cpl(cpu: &mut CPU) {
+pub fn cpl<B: Bus>(cpu: &mut CPU<B>) {
     cpu.registers.a = !cpu.registers.a;
+    cpu.registers.flags.n = true;
+    cpu.registers.flags.h = true;
 }
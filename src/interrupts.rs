@@ -0,0 +1,132 @@
+use crate::bus::Bus;
+use crate::cpu::{CpuState, CPU};
+use crate::instructions::push;
+
+/// IF, each bit is set when the matching interrupt is requested
+pub const INTERRUPT_FLAG: u16 = 0xFF0F;
+/// IE, each bit allows the matching interrupt to be serviced
+pub const INTERRUPT_ENABLE: u16 = 0xFFFF;
+
+/// machine cycles taken to push the PC and jump to the handler
+const DISPATCH_CYCLES: u8 = 5;
+
+/// The five interrupt sources, in priority order. The discriminant is
+/// the bit each one occupies in IE and IF
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+    VBlank = 0,
+    LcdStat = 1,
+    Timer = 2,
+    Serial = 3,
+    Joypad = 4,
+}
+
+impl Interrupt {
+    pub const ALL: [Interrupt; 5] = [
+        Interrupt::VBlank,
+        Interrupt::LcdStat,
+        Interrupt::Timer,
+        Interrupt::Serial,
+        Interrupt::Joypad,
+    ];
+
+    pub fn mask(self) -> u8 {
+        1 << (self as u8)
+    }
+
+    /// address of the handler the CPU jumps to
+    pub fn vector(self) -> u16 {
+        0x40 + 8 * (self as u16)
+    }
+
+    /// the lowest set bit wins when several interrupts are pending
+    pub fn highest_priority(pending: u8) -> Option<Interrupt> {
+        Interrupt::ALL.into_iter().find(|interrupt| pending & interrupt.mask() != 0)
+    }
+}
+
+/// interrupts that are both requested and enabled
+pub fn pending<B: Bus>(bus: &mut B) -> u8 {
+    bus.read8(INTERRUPT_ENABLE) & bus.read8(INTERRUPT_FLAG) & 0x1F
+}
+
+/// sets the interrupt's bit in IF
+pub fn request<B: Bus>(interrupt: Interrupt, bus: &mut B) {
+    let flags = bus.read8(INTERRUPT_FLAG);
+    bus.write8(INTERRUPT_FLAG, flags | interrupt.mask());
+}
+
+/// run between instructions. any pending interrupt wakes the CPU from HALT,
+/// even with IME cleared, but is only dispatched when IME is set. dispatching
+/// clears IME and the IF bit, pushes the PC and jumps to the handler
+pub fn handle_interrupts<B: Bus>(cpu: &mut CPU<B>) -> Option<Interrupt> {
+    let pending = pending(&mut cpu.bus);
+    if pending == 0 {
+        return None;
+    }
+
+    if let CpuState::HALT = cpu.state {
+        cpu.state = CpuState::CONTINUE;
+    }
+    if !cpu.ime {
+        return None;
+    }
+
+    let interrupt = Interrupt::highest_priority(pending)?;
+    cpu.ime = false;
+    cpu.ime_pending = false;
+    let flags = cpu.bus.read8(INTERRUPT_FLAG);
+    cpu.bus.write8(INTERRUPT_FLAG, flags & !interrupt.mask());
+
+    push(cpu.program_counter, cpu);
+    cpu.program_counter = interrupt.vector();
+    cpu.tick(DISPATCH_CYCLES);
+
+    Some(interrupt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::FlatBus;
+
+    fn cpu_with_enabled(enabled: u8) -> CPU<FlatBus> {
+        let mut cpu = CPU::new(FlatBus::new());
+        cpu.bus.write8(INTERRUPT_ENABLE, enabled);
+        cpu
+    }
+
+    #[test]
+    fn highest_priority_interrupt_is_dispatched() {
+        let mut cpu = cpu_with_enabled(0x1F);
+        cpu.ime = true;
+        request(Interrupt::Timer, &mut cpu.bus);
+        request(Interrupt::LcdStat, &mut cpu.bus);
+
+        assert_eq!(handle_interrupts(&mut cpu), Some(Interrupt::LcdStat));
+        assert_eq!(cpu.program_counter, 0x0048);
+        assert_eq!(cpu.bus.read16(cpu.stack_ptr), 0x0100);
+        assert_eq!(cpu.bus.read8(INTERRUPT_FLAG), Interrupt::Timer.mask());
+        assert_eq!(cpu.cycles, DISPATCH_CYCLES as u64);
+        assert!(!cpu.ime);
+    }
+
+    #[test]
+    fn disabled_interrupts_are_not_dispatched() {
+        let mut cpu = cpu_with_enabled(Interrupt::VBlank.mask());
+        cpu.ime = true;
+        request(Interrupt::Serial, &mut cpu.bus);
+        assert_eq!(handle_interrupts(&mut cpu), None);
+        assert_eq!(cpu.program_counter, 0x0100);
+    }
+
+    #[test]
+    fn pending_interrupt_wakes_halt_without_ime() {
+        let mut cpu = cpu_with_enabled(Interrupt::Joypad.mask());
+        cpu.state = CpuState::HALT;
+        request(Interrupt::Joypad, &mut cpu.bus);
+        assert_eq!(handle_interrupts(&mut cpu), None);
+        assert!(matches!(cpu.state, CpuState::CONTINUE));
+        assert_eq!(cpu.program_counter, 0x0100);
+    }
+}
@@ -0,0 +1,214 @@
+use crate::interrupts::Interrupt;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+pub const P1: u16 = 0xFF00;
+
+/// writing 0 to one of these bits selects that group of buttons
+const SELECT_DIRECTIONS: u8 = 0x10;
+const SELECT_ACTIONS: u8 = 0x20;
+
+/// Which buttons are held down
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ButtonState {
+    pub right: bool,
+    pub left: bool,
+    pub up: bool,
+    pub down: bool,
+    pub a: bool,
+    pub b: bool,
+    pub select: bool,
+    pub start: bool,
+}
+
+impl ButtonState {
+    /// the four input lines for a group, a pressed button pulls its line to 0
+    fn lines(buttons: [bool; 4]) -> u8 {
+        buttons
+            .iter()
+            .enumerate()
+            .fold(0x0F, |lines, (bit, pressed)| if *pressed { lines & !(1 << bit) } else { lines })
+    }
+
+    fn direction_lines(&self) -> u8 {
+        ButtonState::lines([self.right, self.left, self.up, self.down])
+    }
+
+    fn action_lines(&self) -> u8 {
+        ButtonState::lines([self.a, self.b, self.select, self.start])
+    }
+}
+
+/// The P1/JOYP register. The buttons are wired as a 2x4 matrix, the
+/// program picks a row with bits 4 and 5 and reads the columns in bits 0-3
+pub struct Joypad {
+    select: u8,
+    buttons: ButtonState,
+    interrupts: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            select: SELECT_DIRECTIONS | SELECT_ACTIONS,
+            buttons: ButtonState::default(),
+            interrupts: 0,
+        }
+    }
+
+    /// the input lines as the CPU sees them. with both groups selected a
+    /// line is low if a button in either group pulls it low
+    fn lines(&self) -> u8 {
+        let mut lines = 0x0F;
+        if self.select & SELECT_DIRECTIONS == 0 {
+            lines &= self.buttons.direction_lines();
+        }
+        if self.select & SELECT_ACTIONS == 0 {
+            lines &= self.buttons.action_lines();
+        }
+        lines
+    }
+
+    pub fn read(&self) -> u8 {
+        // the top two bits are unused and read as 1
+        0xC0 | self.select | self.lines()
+    }
+
+    pub fn write(&mut self, value: u8) {
+        let before = self.lines();
+        self.select = value & (SELECT_DIRECTIONS | SELECT_ACTIONS);
+        self.check_falling(before);
+    }
+
+    pub fn buttons(&self) -> ButtonState {
+        self.buttons
+    }
+
+    pub fn set_buttons(&mut self, buttons: ButtonState) {
+        let before = self.lines();
+        self.buttons = buttons;
+        self.check_falling(before);
+    }
+
+    /// true while a pressed button in a selected group holds its line low,
+    /// which is what takes the CPU out of STOP
+    pub fn any_line_low(&self) -> bool {
+        self.lines() != 0x0F
+    }
+
+    /// the joypad interrupt is requested when any line goes from high to low
+    fn check_falling(&mut self, before: u8) {
+        if before & !self.lines() != 0 {
+            self.interrupts |= Interrupt::Joypad.mask();
+        }
+    }
+
+    /// hands over the interrupts requested since the last call, as IF bits
+    pub fn take_interrupts(&mut self) -> u8 {
+        std::mem::take(&mut self.interrupts)
+    }
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Joypad::new()
+    }
+}
+
+/// the buttons are input from the frontend and aren't saved
+impl SaveState for Joypad {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.select);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.select = reader.u8()? & (SELECT_DIRECTIONS | SELECT_ACTIONS);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::{test_rom, Cartridge};
+    use crate::cpu::{CpuState, CPU};
+    use crate::interrupts::INTERRUPT_FLAG;
+    use crate::mmu::MMU;
+    use crate::timer::DIV;
+
+    #[test]
+    fn p1_reads_the_selected_group() {
+        let mut joypad = Joypad::new();
+        joypad.set_buttons(ButtonState {
+            right: true,
+            down: true,
+            a: true,
+            ..ButtonState::default()
+        });
+        assert_eq!(joypad.read(), 0xFF);
+
+        joypad.write(SELECT_ACTIONS);
+        assert_eq!(joypad.read(), 0xC0 | SELECT_ACTIONS | 0b0110);
+        joypad.write(SELECT_DIRECTIONS);
+        assert_eq!(joypad.read(), 0xC0 | SELECT_DIRECTIONS | 0b1110);
+        // both groups pull the shared lines down
+        joypad.write(0x00);
+        assert_eq!(joypad.read(), 0xC0 | 0b0110);
+    }
+
+    #[test]
+    fn interrupt_when_a_line_falls() {
+        let mut joypad = Joypad::new();
+        let start = ButtonState {
+            start: true,
+            ..ButtonState::default()
+        };
+        // nothing selected, so the line stays high
+        joypad.set_buttons(start);
+        assert_eq!(joypad.take_interrupts(), 0);
+
+        // selecting the group with start held pulls the line down
+        joypad.write(SELECT_DIRECTIONS);
+        assert_eq!(joypad.take_interrupts(), Interrupt::Joypad.mask());
+
+        joypad.set_buttons(ButtonState::default());
+        assert_eq!(joypad.take_interrupts(), 0);
+        joypad.set_buttons(start);
+        assert_eq!(joypad.take_interrupts(), Interrupt::Joypad.mask());
+    }
+
+    #[test]
+    fn a_button_press_ends_stop() {
+        let mut rom = test_rom(0x00, 0x00, 0x00);
+        // STOP and its padding byte, then NOP
+        rom[0x100..0x103].copy_from_slice(&[0x10, 0x00, 0x00]);
+        let mut cpu = CPU::new(MMU::new(Cartridge::from_bytes(rom).unwrap()));
+        cpu.bus.write8(P1, SELECT_DIRECTIONS);
+        cpu.bus.tick(1000);
+        assert_ne!(cpu.bus.read8(DIV), 0);
+
+        cpu.step();
+        assert!(matches!(cpu.state, CpuState::STOP));
+        assert_eq!(cpu.bus.read8(DIV), 0);
+        cpu.step();
+        assert!(matches!(cpu.state, CpuState::STOP));
+
+        // a press in the group that isn't selected doesn't count
+        cpu.bus.set_buttons(ButtonState {
+            up: true,
+            ..ButtonState::default()
+        });
+        cpu.step();
+        assert!(matches!(cpu.state, CpuState::STOP));
+
+        cpu.bus.set_buttons(ButtonState {
+            a: true,
+            ..ButtonState::default()
+        });
+        assert_ne!(cpu.bus.read8(INTERRUPT_FLAG) & Interrupt::Joypad.mask(), 0);
+        cpu.step();
+        assert!(matches!(cpu.state, CpuState::CONTINUE));
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x0103);
+    }
+}
@@ -0,0 +1,25 @@
+#![allow(dead_code)]
+#![allow(clippy::upper_case_acronyms)]
+pub mod bus;
+pub mod display;
+pub mod ppu;
+pub mod dma;
+pub mod timer;
+pub mod apu;
+pub mod audio;
+pub mod joypad;
+pub mod serial;
+pub mod printer;
+pub mod cpu;
+pub mod registers;
+pub mod instructions;
+pub mod opcodes;
+pub mod mmu;
+pub mod interrupts;
+pub mod cartridge;
+pub mod battery;
+pub mod savestate;
+pub mod mbc1;
+pub mod mbc2;
+pub mod mbc3;
+pub mod mbc5;
@@ -1,10 +1,146 @@
-#![allow(dead_code)]
-mod display;
-mod cpu;
-mod registers;
-mod instructions;
-mod opcodes;
+use std::env;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use gbr::audio::WavSink;
+use gbr::battery::BatterySave;
+use gbr::cartridge::Cartridge;
+use gbr::cpu::CPU;
+use gbr::mbc3::RtcClock;
+use gbr::mmu::MMU;
+use gbr::ppu::Renderer;
+use gbr::printer::Printer;
+use gbr::serial::{CaptureLink, SerialLink, TcpLink};
+
+/// set by Ctrl-C or SIGTERM, the main loop stops at the end of the frame so the save and WAV file still get written
+static EXIT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+const USAGE: &str = "usage: gbr <rom> [frames] [--save <path>] [--unlimited-sprites] [--renderer scanline|fifo] [--wav <path>] [--rtc wall|emulated] [--serial stdout|printer:<dir>|listen:<addr>|connect:<addr>]";
 
 fn main() {
-    unimplemented!();
+    let mut args = env::args().skip(1);
+    let mut rom_path = None;
+    let mut frames = None;
+    let mut save_path = None;
+    let mut unlimited_sprites = false;
+    let mut rtc_clock = RtcClock::Wall;
+    let mut renderer = Renderer::Scanline;
+    let mut wav_path = None;
+    let mut serial = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--save" => save_path = Some(args.next().unwrap_or_else(|| exit_with(USAGE))),
+            "--unlimited-sprites" => unlimited_sprites = true,
+            "--wav" => wav_path = Some(args.next().unwrap_or_else(|| exit_with(USAGE))),
+            "--rtc" => {
+                rtc_clock = match args.next().as_deref() {
+                    Some("wall") => RtcClock::Wall,
+                    Some("emulated") => RtcClock::Emulated,
+                    _ => exit_with(USAGE),
+                }
+            },
+            "--serial" => serial = Some(args.next().unwrap_or_else(|| exit_with(USAGE))),
+            "--renderer" => {
+                renderer = match args.next().as_deref() {
+                    Some("scanline") => Renderer::Scanline,
+                    Some("fifo") => Renderer::Fifo,
+                    _ => exit_with(USAGE),
+                }
+            },
+            _ if rom_path.is_none() => rom_path = Some(arg),
+            // without a frame count the emulator runs until it is killed
+            _ if frames.is_none() => {
+                frames = Some(arg.parse::<u64>().unwrap_or_else(|_| exit_with(&format!("invalid frame count {}", arg))));
+            },
+            _ => exit_with(USAGE),
+        }
+    }
+    let rom_path = rom_path.unwrap_or_else(|| exit_with(USAGE));
+
+    let mut cartridge = Cartridge::from_file(&rom_path).unwrap_or_else(|err| {
+        exit_with(&format!("failed to load {}: {}", rom_path, err))
+    });
+    // set before loading the save, only the wall clock catches up on time passed since it was written
+    if let Some(rtc) = cartridge.rtc() {
+        rtc.set_clock(rtc_clock);
+    }
+
+    let mut battery = match save_path {
+        Some(path) => BatterySave::new(path),
+        None => BatterySave::for_rom(&rom_path),
+    };
+    if let Err(warning) = battery.load(&mut cartridge) {
+        eprintln!("warning: {}", warning);
+    }
+
+    let mut cpu = CPU::new(MMU::new(cartridge));
+    cpu.bus.ppu.unlimited_sprites = unlimited_sprites;
+    cpu.bus.ppu.renderer = renderer;
+    // listening blocks until the other emulator connects, which Ctrl-C should still be able to cut short
+    if let Some(serial) = &serial {
+        cpu.bus.serial.set_link(open_link(serial));
+    }
+    if let Some(path) = &wav_path {
+        let sink = WavSink::create(path).unwrap_or_else(|err| exit_with(&format!("failed to create {}: {}", path, err)));
+        cpu.bus.apu.set_sink(Box::new(sink));
+    }
+
+    install_exit_handler();
+    let mut frame = 0;
+    while frames.is_none_or(|frames| frame < frames) && !EXIT_REQUESTED.load(Ordering::Relaxed) {
+        cpu.run_until_frame();
+        if let Err(err) = battery.flush_if_due(&mut cpu.bus.cartridge) {
+            eprintln!("warning: failed to write {}: {}", battery.path().display(), err);
+        }
+        frame += 1;
+    }
+
+    if let Err(err) = battery.flush(&mut cpu.bus.cartridge) {
+        eprintln!("warning: failed to write {}: {}", battery.path().display(), err);
+    }
+    if let Err(err) = cpu.bus.apu.finish_audio() {
+        eprintln!("warning: failed to write audio: {}", err);
+    }
+}
+
+/// the serial link named on the command line. listening blocks until the other emulator connects
+fn open_link(spec: &str) -> Box<dyn SerialLink> {
+    if spec == "stdout" {
+        return Box::new(CaptureLink::stdout());
+    }
+    let link = match spec.split_once(':') {
+        Some(("printer", directory)) => return Box::new(Printer::new(directory)),
+        Some(("listen", address)) => TcpLink::listen(address),
+        Some(("connect", address)) => TcpLink::connect(address),
+        _ => exit_with(USAGE),
+    };
+    Box::new(link.unwrap_or_else(|err| exit_with(&format!("failed to open serial link {}: {}", spec, err))))
+}
+
+fn exit_with(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
 }
+
+#[cfg(unix)]
+fn install_exit_handler() {
+    const SIGINT: i32 = 2;
+    const SIGTERM: i32 = 15;
+
+    extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+    }
+
+    extern "C" fn request_exit(_signum: i32) {
+        EXIT_REQUESTED.store(true, Ordering::Relaxed);
+    }
+
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        signal(SIGINT, request_exit);
+        signal(SIGTERM, request_exit);
+    }
+}
+
+#[cfg(not(unix))]
+fn install_exit_handler() {}
@@ -0,0 +1,218 @@
+use crate::cartridge::{ram_bank_index, rom_bank_byte, Mbc, ROM_BANK_SIZE};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+/// the Nintendo logo in each game's header, used to spot MBC1M multicarts
+const LOGO_START: usize = 0x0104;
+const LOGO_END: usize = 0x0133;
+/// MBC1M carts are all 1MiB, made up of four 256KiB games
+const MULTICART_ROM_SIZE: usize = 0x100000;
+
+/// MBC1, up to 2MiB of ROM and 32KiB of RAM.
+///
+/// BANK1 (0x2000-0x3FFF) is 5 bits wide and BANK2 (0x4000-0x5FFF) is 2 bits.
+/// BANK2 supplies either the upper ROM bank bits on 1MiB+ carts or the RAM
+/// bank on 32KiB RAM carts, since both are wired to the same pins. In mode 1
+/// BANK2 is also applied to 0x0000-0x3FFF and the RAM area.
+pub struct Mbc1 {
+    ram_enabled: bool,
+    bank1: u8,
+    bank2: u8,
+    /// banking mode select, written at 0x6000-0x7FFF
+    mode: bool,
+    /// MBC1M multicarts don't connect bit 4 of BANK1, so BANK2
+    /// starts at bit 4 of the bank number instead of bit 5
+    multicart: bool,
+}
+
+impl Mbc1 {
+    pub fn new(rom: &[u8]) -> Mbc1 {
+        Mbc1 {
+            ram_enabled: false,
+            bank1: 1,
+            bank2: 0,
+            mode: false,
+            multicart: is_multicart(rom),
+        }
+    }
+
+    fn bank2_shift(&self) -> u8 {
+        if self.multicart { 4 } else { 5 }
+    }
+
+    fn bank1_mask(&self) -> u8 {
+        if self.multicart { 0x0F } else { 0x1F }
+    }
+
+    fn low_rom_bank(&self) -> usize {
+        if self.mode {
+            (self.bank2 << self.bank2_shift()) as usize
+        } else {
+            0
+        }
+    }
+
+    fn high_rom_bank(&self) -> usize {
+        ((self.bank2 << self.bank2_shift()) | (self.bank1 & self.bank1_mask())) as usize
+    }
+
+    fn ram_bank(&self) -> usize {
+        if self.mode { self.bank2 as usize } else { 0 }
+    }
+}
+
+/// an MBC1M cart has a second game, with its own copy of the logo, at bank 0x10
+fn is_multicart(rom: &[u8]) -> bool {
+    if rom.len() != MULTICART_ROM_SIZE {
+        return false;
+    }
+    let second_game = 0x10 * ROM_BANK_SIZE;
+    rom[LOGO_START..=LOGO_END] == rom[second_game + LOGO_START..=second_game + LOGO_END]
+}
+
+impl Mbc for Mbc1 {
+    fn read_rom(&self, rom: &[u8], address: u16) -> u8 {
+        let offset = (address & 0x3FFF) as usize;
+        match address {
+            0x0000..=0x3FFF => rom_bank_byte(rom, self.low_rom_bank(), offset),
+            _ => rom_bank_byte(rom, self.high_rom_bank(), offset),
+        }
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                // bank 0 can't be selected here, the check looks at all 5
+                // bits so 0x20, 0x40 and 0x60 map to 0x21, 0x41 and 0x61
+                self.bank1 = value & 0x1F;
+                if self.bank1 == 0 {
+                    self.bank1 = 1;
+                }
+            },
+            0x4000..=0x5FFF => self.bank2 = value & 0x03,
+            _ => self.mode = value & 0x01 != 0,
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        match ram_bank_index(ram, self.ram_bank(), (address & 0x1FFF) as usize) {
+            Some(index) => ram[index],
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], address: u16, value: u8) -> bool {
+        if !self.ram_enabled {
+            return false;
+        }
+        match ram_bank_index(ram, self.ram_bank(), (address & 0x1FFF) as usize) {
+            Some(index) => {
+                ram[index] = value;
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+impl SaveState for Mbc1 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.ram_enabled);
+        writer.u8(self.bank1);
+        writer.u8(self.bank2);
+        writer.bool(self.mode);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enabled = reader.bool()?;
+        self.bank1 = reader.u8()? & 0x1F;
+        self.bank2 = reader.u8()? & 0x03;
+        self.mode = reader.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// an image of 16KiB banks, each starting with its bank number
+    fn numbered_rom(banks: usize) -> Vec<u8> {
+        let mut rom = vec![0; banks * ROM_BANK_SIZE];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+        }
+        rom
+    }
+
+    #[test]
+    fn bank_0x20_0x40_0x60_map_to_the_next_bank() {
+        let rom = numbered_rom(128);
+        let mut mbc = Mbc1::new(&rom);
+        for (bank2, expected) in [(1, 0x21), (2, 0x41), (3, 0x61)] {
+            mbc.write_rom(0x4000, bank2);
+            mbc.write_rom(0x2000, 0x00);
+            assert_eq!(mbc.read_rom(&rom, 0x4000), expected);
+        }
+    }
+
+    #[test]
+    fn mode_1_banks_the_low_rom_area() {
+        let rom = numbered_rom(128);
+        let mut mbc = Mbc1::new(&rom);
+        mbc.write_rom(0x4000, 0x02);
+        assert_eq!(mbc.read_rom(&rom, 0x0000), 0x00);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_rom(&rom, 0x0000), 0x40);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x41);
+    }
+
+    #[test]
+    fn mode_1_banks_ram() {
+        let rom = numbered_rom(4);
+        let mut ram = vec![0; 0x8000];
+        let mut mbc = Mbc1::new(&rom);
+        mbc.write_ram(&mut ram, 0xA000, 0x11);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), 0xFF);
+
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_rom(0x4000, 0x03);
+        mbc.write_ram(&mut ram, 0xA000, 0x11);
+        mbc.write_rom(0x6000, 0x01);
+        mbc.write_ram(&mut ram, 0xA000, 0x33);
+        assert_eq!(ram[0x0000], 0x11);
+        assert_eq!(ram[0x6000], 0x33);
+    }
+
+    #[test]
+    fn multicart_uses_four_bit_bank1() {
+        let mut rom = numbered_rom(64);
+        for game in [0x00, 0x10, 0x20, 0x30] {
+            let start = game * ROM_BANK_SIZE;
+            rom[start + LOGO_START..=start + LOGO_END].fill(0xCE);
+        }
+        let mut mbc = Mbc1::new(&rom);
+        assert!(mbc.multicart);
+
+        mbc.write_rom(0x4000, 0x01);
+        mbc.write_rom(0x2000, 0x12);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x12);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_rom(&rom, 0x0000), 0x10);
+    }
+
+    #[test]
+    fn one_mib_cart_without_a_second_logo_is_not_a_multicart() {
+        let mut rom = numbered_rom(64);
+        rom[LOGO_START..=LOGO_END].fill(0xCE);
+        let mut mbc = Mbc1::new(&rom);
+        assert!(!mbc.multicart);
+
+        mbc.write_rom(0x4000, 0x01);
+        mbc.write_rom(0x2000, 0x12);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x32);
+    }
+}
@@ -0,0 +1,136 @@
+use crate::cartridge::{rom_bank_byte, Mbc};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+/// MBC2 has 512 half-bytes of RAM built into the controller
+pub const MBC2_RAM_SIZE: usize = 512;
+
+/// address bit 8 picks which register a write to 0x0000-0x3FFF hits
+const REGISTER_SELECT: u16 = 0x0100;
+
+/// MBC2, up to 256KiB of ROM and 512x4 bits of built in RAM. The RAM is
+/// stored one nibble per byte, which is also the layout of its save files
+pub struct Mbc2 {
+    ram_enabled: bool,
+    rom_bank: u8,
+}
+
+impl Mbc2 {
+    pub fn new() -> Mbc2 {
+        Mbc2 {
+            ram_enabled: false,
+            rom_bank: 1,
+        }
+    }
+}
+
+impl Default for Mbc2 {
+    fn default() -> Self {
+        Mbc2::new()
+    }
+}
+
+impl Mbc for Mbc2 {
+    fn read_rom(&self, rom: &[u8], address: u16) -> u8 {
+        let offset = (address & 0x3FFF) as usize;
+        match address {
+            0x0000..=0x3FFF => rom_bank_byte(rom, 0, offset),
+            _ => rom_bank_byte(rom, self.rom_bank as usize, offset),
+        }
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x3FFF if address & REGISTER_SELECT == 0 => {
+                self.ram_enabled = value & 0x0F == 0x0A;
+            },
+            0x0000..=0x3FFF => {
+                self.rom_bank = value & 0x0F;
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1;
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /// only the low 9 address bits are decoded, so the RAM repeats
+    /// across 0xA000-0xBFFF. the upper nibble isn't connected and reads as 1s
+    fn read_ram(&self, ram: &[u8], address: u16) -> u8 {
+        if !self.ram_enabled || ram.is_empty() {
+            return 0xFF;
+        }
+        ram[(address as usize) % MBC2_RAM_SIZE] | 0xF0
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], address: u16, value: u8) -> bool {
+        if !self.ram_enabled || ram.is_empty() {
+            return false;
+        }
+        ram[(address as usize) % MBC2_RAM_SIZE] = value & 0x0F;
+        true
+    }
+}
+
+impl SaveState for Mbc2 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.ram_enabled);
+        writer.u8(self.rom_bank);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enabled = reader.bool()?;
+        self.rom_bank = reader.u8()? & 0x0F;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::ROM_BANK_SIZE;
+
+    #[test]
+    fn address_bit_8_selects_the_register() {
+        let mut rom = vec![0; 16 * ROM_BANK_SIZE];
+        rom[5 * ROM_BANK_SIZE] = 0x05;
+        let mut mbc = Mbc2::new();
+
+        // bit 8 clear enables RAM and leaves the bank alone
+        mbc.write_rom(0x2000, 0x0A);
+        assert!(mbc.ram_enabled);
+        assert_eq!(mbc.rom_bank, 1);
+
+        // bit 8 set selects the bank, anywhere in 0x0000-0x3FFF
+        mbc.write_rom(0x0100, 0x05);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x05);
+        assert!(mbc.ram_enabled);
+        mbc.write_rom(0x3F00, 0x00);
+        assert_eq!(mbc.rom_bank, 1);
+    }
+
+    #[test]
+    fn ram_stores_four_bits_and_reads_upper_bits_set() {
+        let mut ram = vec![0; MBC2_RAM_SIZE];
+        let mut mbc = Mbc2::new();
+        mbc.write_ram(&mut ram, 0xA000, 0x0C);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), 0xFF);
+
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(&mut ram, 0xA000, 0x5C);
+        assert_eq!(ram[0], 0x0C);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), 0xFC);
+    }
+
+    #[test]
+    fn ram_echoes_across_the_ram_area() {
+        let mut ram = vec![0; MBC2_RAM_SIZE];
+        let mut mbc = Mbc2::new();
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(&mut ram, 0xA123, 0x07);
+        for address in [0xA123, 0xA323, 0xB123, 0xBF23] {
+            assert_eq!(mbc.read_ram(&ram, address), 0xF7, "{address:#06x}");
+        }
+        mbc.write_ram(&mut ram, 0xBFFF, 0x03);
+        assert_eq!(ram[MBC2_RAM_SIZE - 1], 0x03);
+    }
+}
@@ -0,0 +1,570 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cartridge::{ram_bank_index, rom_bank_byte, Mbc};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+/// machine cycles in one emulated second
+const CYCLES_PER_SECOND: u32 = 1_048_576;
+
+/// size of the RTC footer appended to battery saves
+pub const RTC_FOOTER_SIZE: usize = 48;
+/// some emulators write the RTC footer with a 32-bit timestamp
+pub const SHORT_RTC_FOOTER_SIZE: usize = 44;
+
+/// selecting one of these in the RAM bank register maps
+/// that RTC register into 0xA000-0xBFFF instead of RAM
+const RTC_SECONDS: u8 = 0x08;
+const RTC_MINUTES: u8 = 0x09;
+const RTC_HOURS: u8 = 0x0A;
+const RTC_DAY_LOW: u8 = 0x0B;
+const RTC_DAY_HIGH: u8 = 0x0C;
+
+/// bits of the day high register
+const DAY_HIGH_BIT_8: u8 = 0x01;
+const DAY_HIGH_HALT: u8 = 0x40;
+const DAY_HIGH_CARRY: u8 = 0x80;
+
+/// What drives the real time clock
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RtcClock {
+    /// follows the host's clock, including time passed while the emulator was closed
+    Wall,
+    /// counts emulated cycles, so runs and replays are deterministic
+    Emulated,
+}
+
+/// The RTC registers in the order they are selected and saved
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RtcRegisters {
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    pub day_low: u8,
+    pub day_high: u8,
+}
+
+impl RtcRegisters {
+    fn days(&self) -> u64 {
+        ((self.day_high & DAY_HIGH_BIT_8) as u64) << 8 | self.day_low as u64
+    }
+
+    /// a game can write values the counters never reach on their own
+    fn in_range(&self) -> bool {
+        self.seconds < 60 && self.minutes < 60 && self.hours < 24
+    }
+
+    /// counts up one second. each counter carries into the next only when it
+    /// rolls over from its normal maximum, one written out of range keeps
+    /// counting to the top of its bits and wraps to 0 without a carry
+    fn tick_second(&mut self) {
+        self.seconds = match self.seconds {
+            59 => {
+                self.tick_minute();
+                0
+            },
+            seconds => (seconds + 1) & 0x3F,
+        };
+    }
+
+    fn tick_minute(&mut self) {
+        self.minutes = match self.minutes {
+            59 => {
+                self.tick_hour();
+                0
+            },
+            minutes => (minutes + 1) & 0x3F,
+        };
+    }
+
+    fn tick_hour(&mut self) {
+        self.hours = match self.hours {
+            23 => {
+                self.set_days(self.days() + 1);
+                0
+            },
+            hours => (hours + 1) & 0x1F,
+        };
+    }
+
+    /// sets the 9-bit day counter, setting the carry bit if it overflows
+    fn set_days(&mut self, days: u64) {
+        self.day_low = (days & 0xFF) as u8;
+        self.day_high = (self.day_high & !DAY_HIGH_BIT_8) | ((days >> 8) & 0x01) as u8;
+        if days > 0x1FF {
+            self.day_high |= DAY_HIGH_CARRY;
+        }
+    }
+
+    fn read(&self, select: u8) -> u8 {
+        match select {
+            RTC_SECONDS => self.seconds,
+            RTC_MINUTES => self.minutes,
+            RTC_HOURS => self.hours,
+            RTC_DAY_LOW => self.day_low,
+            _ => self.day_high,
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bytes(&[self.seconds, self.minutes, self.hours, self.day_low, self.day_high]);
+    }
+
+    fn load_state(reader: &mut StateReader) -> Result<RtcRegisters, StateError> {
+        let mut registers = [0; 5];
+        reader.bytes_into(&mut registers)?;
+        Ok(RtcRegisters {
+            seconds: registers[0] & 0x3F,
+            minutes: registers[1] & 0x3F,
+            hours: registers[2] & 0x1F,
+            day_low: registers[3],
+            day_high: registers[4] & (DAY_HIGH_BIT_8 | DAY_HIGH_HALT | DAY_HIGH_CARRY),
+        })
+    }
+
+    fn to_footer(self, footer: &mut Vec<u8>) {
+        for value in [self.seconds, self.minutes, self.hours, self.day_low, self.day_high] {
+            footer.extend_from_slice(&(value as u32).to_le_bytes());
+        }
+    }
+
+    fn from_footer(footer: &[u8]) -> RtcRegisters {
+        let field = |index: usize| footer[index * 4];
+        RtcRegisters {
+            seconds: field(0) & 0x3F,
+            minutes: field(1) & 0x3F,
+            hours: field(2) & 0x1F,
+            day_low: field(3),
+            day_high: field(4) & (DAY_HIGH_BIT_8 | DAY_HIGH_HALT | DAY_HIGH_CARRY),
+        }
+    }
+}
+
+/// The MBC3 real time clock. Reads see a latched copy of the
+/// registers which is refreshed by writing 0x00 then 0x01 to 0x6000-0x7FFF
+pub struct Rtc {
+    clock: RtcClock,
+    pub registers: RtcRegisters,
+    pub latched: RtcRegisters,
+    /// the last write to the latch register was 0x00
+    latch_armed: bool,
+    /// emulated cycles into the current second
+    cycles: u32,
+    /// unix time the wall clock was last caught up to
+    last_update: u64,
+}
+
+impl Rtc {
+    pub fn new(clock: RtcClock) -> Rtc {
+        Rtc {
+            clock,
+            registers: RtcRegisters::default(),
+            latched: RtcRegisters::default(),
+            latch_armed: false,
+            cycles: 0,
+            last_update: unix_time(),
+        }
+    }
+
+    pub fn clock(&self) -> RtcClock {
+        self.clock
+    }
+
+    pub fn set_clock(&mut self, clock: RtcClock) {
+        self.update();
+        self.clock = clock;
+        self.last_update = unix_time();
+    }
+
+    pub fn halted(&self) -> bool {
+        self.registers.day_high & DAY_HIGH_HALT != 0
+    }
+
+    /// moves the registers forward by however much wall time has passed
+    pub fn update(&mut self) {
+        if self.clock != RtcClock::Wall {
+            return;
+        }
+        let now = unix_time();
+        if now > self.last_update {
+            self.advance(now - self.last_update);
+        }
+        self.last_update = now;
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        if self.clock != RtcClock::Emulated || self.halted() {
+            return;
+        }
+        self.cycles += cycles;
+        while self.cycles >= CYCLES_PER_SECOND {
+            self.cycles -= CYCLES_PER_SECOND;
+            self.advance(1);
+        }
+    }
+
+    /// adds seconds to the clock, setting the carry bit if the 9-bit day counter overflows
+    fn advance(&mut self, mut seconds: u64) {
+        if self.halted() {
+            return;
+        }
+        let registers = &mut self.registers;
+
+        // out of range counters don't follow the usual arithmetic, step
+        // through seconds one at a time until they have wrapped back around
+        while seconds > 0 && !registers.in_range() {
+            registers.tick_second();
+            seconds -= 1;
+        }
+        if seconds == 0 {
+            return;
+        }
+
+        let total_seconds = registers.seconds as u64 + seconds;
+        registers.seconds = (total_seconds % 60) as u8;
+        let total_minutes = registers.minutes as u64 + total_seconds / 60;
+        registers.minutes = (total_minutes % 60) as u8;
+        let total_hours = registers.hours as u64 + total_minutes / 60;
+        registers.hours = (total_hours % 24) as u8;
+        registers.set_days(registers.days() + total_hours / 24);
+    }
+
+    fn write_latch(&mut self, value: u8) {
+        if self.latch_armed && value == 0x01 {
+            self.update();
+            self.latched = self.registers;
+        }
+        self.latch_armed = value == 0x00;
+    }
+
+    fn write(&mut self, select: u8, value: u8) {
+        self.update();
+        match select {
+            RTC_SECONDS => {
+                self.registers.seconds = value & 0x3F;
+                // writing the seconds resets the divider feeding the clock
+                self.cycles = 0;
+            },
+            RTC_MINUTES => self.registers.minutes = value & 0x3F,
+            RTC_HOURS => self.registers.hours = value & 0x1F,
+            RTC_DAY_LOW => self.registers.day_low = value,
+            _ => self.registers.day_high = value & (DAY_HIGH_BIT_8 | DAY_HIGH_HALT | DAY_HIGH_CARRY),
+        }
+    }
+
+    /// the 48-byte footer used by BGB, VBA-M and others: the live and latched
+    /// registers as little endian u32s, followed by a 64-bit unix timestamp
+    pub fn to_footer(&mut self) -> Vec<u8> {
+        self.update();
+        let mut footer = Vec::with_capacity(RTC_FOOTER_SIZE);
+        self.registers.to_footer(&mut footer);
+        self.latched.to_footer(&mut footer);
+        footer.extend_from_slice(&unix_time().to_le_bytes());
+        footer
+    }
+
+    /// restores the clock from a footer, either the full one or the short
+    /// one with a 32-bit timestamp. a wall clock also catches up on the
+    /// time that passed since it was saved, an emulated one doesn't
+    pub fn load_footer(&mut self, footer: &[u8]) {
+        let saved_at = if footer.len() >= RTC_FOOTER_SIZE {
+            let mut timestamp = [0; 8];
+            timestamp.copy_from_slice(&footer[40..48]);
+            u64::from_le_bytes(timestamp)
+        } else if footer.len() >= SHORT_RTC_FOOTER_SIZE {
+            let mut timestamp = [0; 4];
+            timestamp.copy_from_slice(&footer[40..44]);
+            u32::from_le_bytes(timestamp) as u64
+        } else {
+            return;
+        };
+        self.registers = RtcRegisters::from_footer(&footer[0..20]);
+        self.latched = RtcRegisters::from_footer(&footer[20..40]);
+
+        self.cycles = 0;
+        if self.clock == RtcClock::Wall {
+            self.last_update = saved_at;
+            self.update();
+        }
+    }
+}
+
+/// the clock source is left as configured, it belongs to the frontend
+impl SaveState for Rtc {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.registers.save_state(writer);
+        self.latched.save_state(writer);
+        writer.bool(self.latch_armed);
+        writer.u32(self.cycles);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.registers = RtcRegisters::load_state(reader)?;
+        self.latched = RtcRegisters::load_state(reader)?;
+        self.latch_armed = reader.bool()?;
+        self.cycles = reader.u32()? % CYCLES_PER_SECOND;
+        self.last_update = unix_time();
+        Ok(())
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// MBC3, up to 2MiB of ROM, 32KiB of RAM and an optional real time clock
+pub struct Mbc3 {
+    ram_and_rtc_enabled: bool,
+    rom_bank: u8,
+    /// 0x00-0x03 select a RAM bank, 0x08-0x0C an RTC register
+    ram_bank: u8,
+    rtc: Option<Rtc>,
+}
+
+impl Mbc3 {
+    /// the clock follows the host's, frontends pick another through [Cartridge::rtc](crate::cartridge::Cartridge::rtc)
+    pub fn new(has_rtc: bool) -> Mbc3 {
+        Mbc3 {
+            ram_and_rtc_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            rtc: if has_rtc { Some(Rtc::new(RtcClock::Wall)) } else { None },
+        }
+    }
+
+    fn selected_rtc_register(&self) -> Option<u8> {
+        match self.ram_bank {
+            RTC_SECONDS..=RTC_DAY_HIGH if self.rtc.is_some() => Some(self.ram_bank),
+            _ => None,
+        }
+    }
+}
+
+impl Mbc for Mbc3 {
+    fn read_rom(&self, rom: &[u8], address: u16) -> u8 {
+        let offset = (address & 0x3FFF) as usize;
+        match address {
+            0x0000..=0x3FFF => rom_bank_byte(rom, 0, offset),
+            _ => rom_bank_byte(rom, self.rom_bank as usize, offset),
+        }
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_and_rtc_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                // unlike MBC1 only an exact 0 maps to bank 1
+                self.rom_bank = value & 0x7F;
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1;
+                }
+            },
+            0x4000..=0x5FFF => self.ram_bank = value & 0x0F,
+            _ => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write_latch(value);
+                }
+            },
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], address: u16) -> u8 {
+        if !self.ram_and_rtc_enabled {
+            return 0xFF;
+        }
+        if let (Some(select), Some(rtc)) = (self.selected_rtc_register(), &self.rtc) {
+            return rtc.latched.read(select);
+        }
+        if self.ram_bank > 0x03 {
+            return 0xFF;
+        }
+        match ram_bank_index(ram, self.ram_bank as usize, (address & 0x1FFF) as usize) {
+            Some(index) => ram[index],
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], address: u16, value: u8) -> bool {
+        if !self.ram_and_rtc_enabled {
+            return false;
+        }
+        // the clock is saved along with the RAM
+        if let Some(select) = self.selected_rtc_register() {
+            if let Some(rtc) = &mut self.rtc {
+                rtc.write(select, value);
+            }
+            return true;
+        }
+        if self.ram_bank > 0x03 {
+            return false;
+        }
+        match ram_bank_index(ram, self.ram_bank as usize, (address & 0x1FFF) as usize) {
+            Some(index) => {
+                ram[index] = value;
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.tick(cycles);
+        }
+    }
+
+    fn rtc(&mut self) -> Option<&mut Rtc> {
+        self.rtc.as_mut()
+    }
+}
+
+impl SaveState for Mbc3 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.ram_and_rtc_enabled);
+        writer.u8(self.rom_bank);
+        writer.u8(self.ram_bank);
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(writer);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.ram_and_rtc_enabled = reader.bool()?;
+        self.rom_bank = reader.u8()? & 0x7F;
+        self.ram_bank = reader.u8()? & 0x0F;
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load_state(reader)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// an MBC3 with 32KiB of RAM and an emulated clock, with RAM and the RTC enabled
+    fn mbc3() -> (Mbc3, Vec<u8>) {
+        let mut mbc = Mbc3::new(true);
+        mbc.rtc().unwrap().set_clock(RtcClock::Emulated);
+        mbc.write_rom(0x0000, 0x0A);
+        (mbc, vec![0; 0x8000])
+    }
+
+    fn write_register(mbc: &mut Mbc3, ram: &mut [u8], select: u8, value: u8) {
+        mbc.write_rom(0x4000, select);
+        mbc.write_ram(ram, 0xA000, value);
+    }
+
+    fn latch(mbc: &mut Mbc3) {
+        mbc.write_rom(0x6000, 0x00);
+        mbc.write_rom(0x6000, 0x01);
+    }
+
+    fn read_register(mbc: &mut Mbc3, ram: &[u8], select: u8) -> u8 {
+        mbc.write_rom(0x4000, select);
+        mbc.read_ram(ram, 0xA000)
+    }
+
+    #[test]
+    fn reads_see_the_latched_registers() {
+        let (mut mbc, mut ram) = mbc3();
+        write_register(&mut mbc, &mut ram, RTC_SECONDS, 10);
+        latch(&mut mbc);
+        mbc.tick(CYCLES_PER_SECOND * 5);
+        assert_eq!(read_register(&mut mbc, &ram, RTC_SECONDS), 10);
+
+        // writing 0x01 without the 0x00 first doesn't latch
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(read_register(&mut mbc, &ram, RTC_SECONDS), 10);
+        latch(&mut mbc);
+        assert_eq!(read_register(&mut mbc, &ram, RTC_SECONDS), 15);
+    }
+
+    #[test]
+    fn halted_clock_does_not_count() {
+        let (mut mbc, mut ram) = mbc3();
+        write_register(&mut mbc, &mut ram, RTC_DAY_HIGH, DAY_HIGH_HALT);
+        mbc.tick(CYCLES_PER_SECOND * 3);
+        write_register(&mut mbc, &mut ram, RTC_DAY_HIGH, 0);
+        mbc.tick(CYCLES_PER_SECOND);
+        latch(&mut mbc);
+        assert_eq!(read_register(&mut mbc, &ram, RTC_SECONDS), 1);
+    }
+
+    #[test]
+    fn day_counter_overflow_sets_the_carry() {
+        let (mut mbc, mut ram) = mbc3();
+        write_register(&mut mbc, &mut ram, RTC_SECONDS, 59);
+        write_register(&mut mbc, &mut ram, RTC_MINUTES, 59);
+        write_register(&mut mbc, &mut ram, RTC_HOURS, 23);
+        write_register(&mut mbc, &mut ram, RTC_DAY_LOW, 0xFF);
+        write_register(&mut mbc, &mut ram, RTC_DAY_HIGH, DAY_HIGH_BIT_8);
+        mbc.tick(CYCLES_PER_SECOND);
+        latch(&mut mbc);
+
+        let registers = mbc.rtc().unwrap().latched;
+        assert_eq!((registers.seconds, registers.minutes, registers.hours), (0, 0, 0));
+        assert_eq!(registers.day_low, 0);
+        assert_eq!(registers.day_high, DAY_HIGH_CARRY);
+    }
+
+    #[test]
+    fn out_of_range_counters_wrap_without_carrying() {
+        let mut rtc = Rtc::new(RtcClock::Emulated);
+        rtc.registers.seconds = 62;
+        rtc.advance(1);
+        assert_eq!((rtc.registers.seconds, rtc.registers.minutes), (63, 0));
+        rtc.advance(1);
+        assert_eq!((rtc.registers.seconds, rtc.registers.minutes), (0, 0));
+        rtc.advance(60);
+        assert_eq!((rtc.registers.seconds, rtc.registers.minutes), (0, 1));
+
+        rtc.registers.hours = 31;
+        rtc.registers.minutes = 59;
+        rtc.registers.seconds = 59;
+        rtc.advance(1);
+        assert_eq!((rtc.registers.hours, rtc.registers.minutes, rtc.registers.seconds), (0, 0, 0));
+        assert_eq!(rtc.registers.days(), 0);
+    }
+
+    /// a footer with seconds 12 live and 11 latched, then the timestamp
+    fn footer(timestamp: &[u8]) -> Vec<u8> {
+        let mut footer = Vec::new();
+        for value in [12u32, 34, 5, 6, 1, 11, 33, 4, 5, 0] {
+            footer.extend_from_slice(&value.to_le_bytes());
+        }
+        footer.extend_from_slice(timestamp);
+        footer
+    }
+
+    #[test]
+    fn both_footer_sizes_are_loaded() {
+        for footer in [footer(&0u64.to_le_bytes()), footer(&0u32.to_le_bytes())] {
+            let mut rtc = Rtc::new(RtcClock::Emulated);
+            rtc.load_footer(&footer);
+            assert_eq!(rtc.registers.seconds, 12);
+            assert_eq!(rtc.registers.minutes, 34);
+            assert_eq!(rtc.registers.days(), 0x106);
+            assert_eq!(rtc.latched.seconds, 11);
+        }
+
+        let mut rtc = Rtc::new(RtcClock::Emulated);
+        rtc.load_footer(&footer(&[0; 2]));
+        assert_eq!(rtc.registers, RtcRegisters::default());
+    }
+
+    #[test]
+    fn footer_round_trips() {
+        let mut rtc = Rtc::new(RtcClock::Emulated);
+        rtc.registers = RtcRegisters { seconds: 1, minutes: 2, hours: 3, day_low: 4, day_high: DAY_HIGH_CARRY };
+        let footer = rtc.to_footer();
+        assert_eq!(footer.len(), RTC_FOOTER_SIZE);
+
+        let mut loaded = Rtc::new(RtcClock::Emulated);
+        loaded.load_footer(&footer);
+        assert_eq!(loaded.registers, rtc.registers);
+    }
+}
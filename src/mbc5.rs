@@ -0,0 +1,165 @@
+use crate::cartridge::{ram_bank_index, rom_bank_byte, Mbc};
+use crate::cpu::Event;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+/// on rumble carts bit 3 of the RAM bank register drives the motor
+const RUMBLE_MOTOR: u8 = 0x08;
+
+/// MBC5, up to 8MiB of ROM through a 9-bit bank number and 128KiB of RAM
+/// in 16 banks. Unlike the older controllers bank 0 can be mapped to
+/// 0x4000-0x7FFF
+pub struct Mbc5 {
+    ram_enabled: bool,
+    rom_bank: u16,
+    ram_bank: u8,
+    has_rumble: bool,
+    rumble_on: bool,
+    events: Vec<Event>,
+}
+
+impl Mbc5 {
+    pub fn new(has_rumble: bool) -> Mbc5 {
+        Mbc5 {
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            has_rumble,
+            rumble_on: false,
+            events: Vec::new(),
+        }
+    }
+
+    fn write_ram_bank(&mut self, value: u8) {
+        if !self.has_rumble {
+            self.ram_bank = value & 0x0F;
+            return;
+        }
+
+        // the motor takes the place of the top RAM bank bit
+        self.ram_bank = value & 0x07;
+        let rumble_on = value & RUMBLE_MOTOR != 0;
+        if rumble_on != self.rumble_on {
+            self.rumble_on = rumble_on;
+            self.events.push(Event::Rumble(rumble_on));
+        }
+    }
+}
+
+impl Mbc for Mbc5 {
+    fn read_rom(&self, rom: &[u8], address: u16) -> u8 {
+        let offset = (address & 0x3FFF) as usize;
+        match address {
+            0x0000..=0x3FFF => rom_bank_byte(rom, 0, offset),
+            _ => rom_bank_byte(rom, self.rom_bank as usize, offset),
+        }
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | value as u16,
+            0x3000..=0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | ((value as u16 & 0x01) << 8),
+            0x4000..=0x5FFF => self.write_ram_bank(value),
+            _ => {},
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        match ram_bank_index(ram, self.ram_bank as usize, (address & 0x1FFF) as usize) {
+            Some(index) => ram[index],
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], address: u16, value: u8) -> bool {
+        if !self.ram_enabled {
+            return false;
+        }
+        match ram_bank_index(ram, self.ram_bank as usize, (address & 0x1FFF) as usize) {
+            Some(index) => {
+                ram[index] = value;
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn drain_events(&mut self, events: &mut Vec<Event>) {
+        events.append(&mut self.events);
+    }
+}
+
+impl SaveState for Mbc5 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.ram_enabled);
+        writer.u16(self.rom_bank);
+        writer.u8(self.ram_bank);
+        writer.bool(self.rumble_on);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enabled = reader.bool()?;
+        self.rom_bank = reader.u16()? & 0x1FF;
+        self.ram_bank = reader.u8()? & 0x0F;
+        let rumble_on = reader.bool()?;
+        // let the frontend know if the motor state jumped
+        if rumble_on != self.rumble_on {
+            self.rumble_on = rumble_on;
+            self.events.push(Event::Rumble(rumble_on));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::ROM_BANK_SIZE;
+
+    fn drain(mbc: &mut Mbc5) -> Vec<Event> {
+        let mut events = Vec::new();
+        mbc.drain_events(&mut events);
+        events
+    }
+
+    #[test]
+    fn nine_bit_rom_bank_including_bank_0() {
+        let mut rom = vec![0; 512 * ROM_BANK_SIZE];
+        rom[0x1FF * ROM_BANK_SIZE] = 0xAA;
+        rom[0] = 0x55;
+        let mut mbc = Mbc5::new(false);
+        mbc.write_rom(0x2000, 0xFF);
+        mbc.write_rom(0x3000, 0x01);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0xAA);
+        mbc.write_rom(0x2000, 0x00);
+        mbc.write_rom(0x3000, 0x00);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x55);
+    }
+
+    #[test]
+    fn rumble_motor_reports_each_change() {
+        let mut mbc = Mbc5::new(true);
+        mbc.write_rom(0x4000, 0x08);
+        mbc.write_rom(0x4000, 0x0A);
+        mbc.write_rom(0x4000, 0x02);
+        mbc.write_rom(0x4000, 0x00);
+        assert_eq!(drain(&mut mbc), vec![Event::Rumble(true), Event::Rumble(false)]);
+
+        mbc.write_rom(0x4000, 0x0B);
+        assert_eq!(drain(&mut mbc), vec![Event::Rumble(true)]);
+        assert!(drain(&mut mbc).is_empty());
+        // the motor bit isn't part of the RAM bank
+        assert_eq!(mbc.ram_bank, 0x03);
+    }
+
+    #[test]
+    fn no_rumble_without_a_motor() {
+        let mut mbc = Mbc5::new(false);
+        mbc.write_rom(0x4000, 0x08);
+        assert!(drain(&mut mbc).is_empty());
+        assert_eq!(mbc.ram_bank, 0x08);
+    }
+}
@@ -0,0 +1,280 @@
+use crate::apu::{self, APU};
+use crate::bus::Bus;
+use crate::cartridge::Cartridge;
+use crate::cpu::Event;
+use crate::dma::{self, Dma};
+use crate::interrupts::{self, Interrupt};
+use crate::joypad::{self, ButtonState, Joypad};
+use crate::ppu::{self, PPU};
+use crate::serial::{self, Serial};
+use crate::timer::{self, Timer};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+type Byte = u8;
+// VRAM and work RAM are both 8KiB in size
+type RAMArea = [Byte; 8192];
+
+pub const ROM_BANK_0_START: u16 = 0x0000;
+pub const ROM_BANK_0_END: u16 = 0x3FFF;
+pub const ROM_BANK_N_START: u16 = 0x4000;
+pub const ROM_BANK_N_END: u16 = 0x7FFF;
+pub const VRAM_START: u16 = 0x8000;
+pub const VRAM_END: u16 = 0x9FFF;
+pub const EXTERNAL_RAM_START: u16 = 0xA000;
+pub const EXTERNAL_RAM_END: u16 = 0xBFFF;
+pub const WORK_RAM_START: u16 = 0xC000;
+pub const WORK_RAM_END: u16 = 0xDFFF;
+pub const ECHO_RAM_START: u16 = 0xE000;
+pub const ECHO_RAM_END: u16 = 0xFDFF;
+pub const OAM_START: u16 = 0xFE00;
+pub const OAM_END: u16 = 0xFE9F;
+pub const UNUSABLE_START: u16 = 0xFEA0;
+pub const UNUSABLE_END: u16 = 0xFEFF;
+pub const IO_START: u16 = 0xFF00;
+pub const IO_END: u16 = 0xFF7F;
+pub const HRAM_START: u16 = 0xFF80;
+pub const HRAM_END: u16 = 0xFFFE;
+
+/// The Game Boy memory map, every memory access made by the CPU goes
+/// through here and gets routed to the area that owns the address
+pub struct MMU {
+    /// the ROM area and external RAM are both handled by the cartridge
+    pub cartridge: Cartridge,
+    pub video_ram: RAMArea,
+    pub work_ram: RAMArea,
+    pub oam: [Byte; 160],
+    pub io: [Byte; 128],
+    pub high_ram: [Byte; 127],
+    pub interrupt_flag: Byte,
+    pub interrupt_enable: Byte,
+    pub ppu: PPU,
+    pub dma: Dma,
+    pub timer: Timer,
+    pub apu: APU,
+    pub joypad: Joypad,
+    pub serial: Serial,
+}
+
+impl MMU {
+    pub fn new(cartridge: Cartridge) -> MMU {
+        MMU {
+            cartridge,
+            video_ram: [0; 8192],
+            work_ram: [0; 8192],
+            oam: [0; 160],
+            io: [0; 128],
+            high_ram: [0; 127],
+            interrupt_flag: 0,
+            interrupt_enable: 0,
+            ppu: PPU::new(),
+            dma: Dma::new(),
+            timer: Timer::new(),
+            apu: APU::new(),
+            joypad: Joypad::new(),
+            serial: Serial::new(),
+        }
+    }
+
+    /// sets the interrupt's bit in IF, for hardware on the bus to raise interrupts
+    pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt_flag |= interrupt.mask();
+    }
+
+    /// updates the held buttons. a button going down on a selected
+    /// group requests the joypad interrupt
+    pub fn set_buttons(&mut self, buttons: ButtonState) {
+        self.joypad.set_buttons(buttons);
+        self.interrupt_flag |= self.joypad.take_interrupts();
+    }
+
+    /// reads the memory map as is, without OAM DMA getting in the way
+    // the registers in the IO area are matched before the
+    // catch all IO arm, P1 just happens to sit at its start
+    #[allow(clippy::match_overlapping_arm)]
+    pub fn read_memory(&mut self, address: u16) -> u8 {
+        match address {
+            ROM_BANK_0_START..=ROM_BANK_N_END => self.cartridge.read_rom(address),
+            VRAM_START..=VRAM_END => self.video_ram[(address - VRAM_START) as usize],
+            EXTERNAL_RAM_START..=EXTERNAL_RAM_END => self.cartridge.read_ram(address),
+            WORK_RAM_START..=WORK_RAM_END => self.work_ram[(address - WORK_RAM_START) as usize],
+            // echo RAM mirrors the first 7.5KiB of work RAM
+            ECHO_RAM_START..=ECHO_RAM_END => self.work_ram[(address - ECHO_RAM_START) as usize],
+            OAM_START..=OAM_END => self.oam[(address - OAM_START) as usize],
+            // the DMG reads 0x00 from the unusable area
+            UNUSABLE_START..=UNUSABLE_END => 0x00,
+            // the unused upper bits of IF always read as 1
+            interrupts::INTERRUPT_FLAG => self.interrupt_flag | 0xE0,
+            ppu::LCDC..=ppu::LYC | ppu::BGP..=ppu::WX => self.ppu.read(address),
+            dma::DMA => self.dma.read(),
+            joypad::P1 => self.joypad.read(),
+            serial::SB | serial::SC => self.serial.read(address),
+            timer::DIV..=timer::TAC => self.timer.read(address),
+            apu::APU_START..=apu::APU_END => self.apu.read(address),
+            IO_START..=IO_END => self.io[(address - IO_START) as usize],
+            HRAM_START..=HRAM_END => self.high_ram[(address - HRAM_START) as usize],
+            interrupts::INTERRUPT_ENABLE => self.interrupt_enable,
+        }
+    }
+
+    #[allow(clippy::match_overlapping_arm)]
+    pub fn write_memory(&mut self, address: u16, value: u8) {
+        match address {
+            // the ROM itself is read only, writes go to the bank controller
+            ROM_BANK_0_START..=ROM_BANK_N_END => self.cartridge.write_rom(address, value),
+            VRAM_START..=VRAM_END => self.video_ram[(address - VRAM_START) as usize] = value,
+            EXTERNAL_RAM_START..=EXTERNAL_RAM_END => self.cartridge.write_ram(address, value),
+            WORK_RAM_START..=WORK_RAM_END => self.work_ram[(address - WORK_RAM_START) as usize] = value,
+            ECHO_RAM_START..=ECHO_RAM_END => self.work_ram[(address - ECHO_RAM_START) as usize] = value,
+            OAM_START..=OAM_END => self.oam[(address - OAM_START) as usize] = value,
+            UNUSABLE_START..=UNUSABLE_END => {},
+            interrupts::INTERRUPT_FLAG => self.interrupt_flag = value & 0x1F,
+            ppu::LCDC..=ppu::LYC | ppu::BGP..=ppu::WX => {
+                self.ppu.write(address, value);
+                self.interrupt_flag |= self.ppu.take_interrupts();
+            },
+            dma::DMA => self.dma.write(value),
+            joypad::P1 => {
+                self.joypad.write(value);
+                self.interrupt_flag |= self.joypad.take_interrupts();
+            },
+            serial::SB | serial::SC => self.serial.write(address, value),
+            timer::DIV..=timer::TAC => {
+                self.timer.write(address, value);
+                self.interrupt_flag |= self.timer.take_interrupts();
+            },
+            apu::APU_START..=apu::APU_END => self.apu.write(address, value),
+            IO_START..=IO_END => self.io[(address - IO_START) as usize] = value,
+            HRAM_START..=HRAM_END => self.high_ram[(address - HRAM_START) as usize] = value,
+            interrupts::INTERRUPT_ENABLE => self.interrupt_enable = value,
+        }
+    }
+
+    fn step_dma(&mut self, cycles: u32) {
+        for _ in 0..cycles {
+            if !self.dma.busy() {
+                break;
+            }
+            if let Some((source, index)) = self.dma.next_transfer() {
+                let value = self.read_memory(source);
+                self.oam[index] = value;
+                self.dma.transferred(value);
+            }
+            self.dma.end_cycle();
+        }
+    }
+}
+
+impl Bus for MMU {
+    /// while OAM DMA runs the CPU reads whatever the transfer
+    /// has on the bus, except from HRAM
+    fn read8(&mut self, address: u16) -> u8 {
+        if self.dma.active() && !Dma::cpu_accessible(address) {
+            return self.dma.bus_value();
+        }
+        self.read_memory(address)
+    }
+
+    fn write8(&mut self, address: u16, value: u8) {
+        if self.dma.active() && !Dma::cpu_accessible(address) {
+            return;
+        }
+        self.write_memory(address, value);
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.cartridge.tick(cycles);
+        self.step_dma(cycles);
+        self.ppu.tick(cycles, &self.video_ram, &self.oam);
+        self.interrupt_flag |= self.ppu.take_interrupts();
+        self.timer.tick(cycles);
+        self.interrupt_flag |= self.timer.take_interrupts();
+        self.apu.tick(cycles);
+        self.serial.tick(cycles);
+        self.interrupt_flag |= self.serial.take_interrupts();
+    }
+
+    fn drain_events(&mut self, events: &mut Vec<Event>) {
+        self.cartridge.drain_events(events);
+        self.ppu.drain_events(events);
+    }
+
+    /// STOP resets DIV, which can bump TIMA like any other DIV write
+    fn stop(&mut self) {
+        self.timer.write(timer::DIV, 0);
+        self.interrupt_flag |= self.timer.take_interrupts();
+    }
+
+    fn stop_wake(&mut self) -> bool {
+        self.joypad.any_line_low()
+    }
+}
+
+impl SaveState for MMU {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bytes(&self.video_ram);
+        writer.bytes(&self.work_ram);
+        writer.bytes(&self.oam);
+        writer.bytes(&self.io);
+        writer.bytes(&self.high_ram);
+        writer.u8(self.interrupt_flag);
+        writer.u8(self.interrupt_enable);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.bytes_into(&mut self.video_ram)?;
+        reader.bytes_into(&mut self.work_ram)?;
+        reader.bytes_into(&mut self.oam)?;
+        reader.bytes_into(&mut self.io)?;
+        reader.bytes_into(&mut self.high_ram)?;
+        self.interrupt_flag = reader.u8()?;
+        self.interrupt_enable = reader.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::test_rom;
+
+    fn mmu() -> MMU {
+        MMU::new(Cartridge::from_bytes(test_rom(0x00, 0x00, 0x00)).unwrap())
+    }
+
+    #[test]
+    fn echo_ram_mirrors_work_ram() {
+        let mut mmu = mmu();
+        mmu.write8(0xC123, 0x42);
+        assert_eq!(mmu.read8(0xE123), 0x42);
+        mmu.write8(0xFDFF, 0x24);
+        assert_eq!(mmu.read8(0xDDFF), 0x24);
+    }
+
+    #[test]
+    fn rom_and_unusable_area_ignore_writes() {
+        let mut mmu = mmu();
+        mmu.write8(0x0147, 0x55);
+        mmu.write8(0xFEA0, 0x55);
+        assert_eq!(mmu.read8(0x0147), 0x00);
+        assert_eq!(mmu.read8(0xFEA0), 0x00);
+    }
+
+    #[test]
+    fn sixteen_bit_accesses_are_little_endian() {
+        let mut mmu = mmu();
+        mmu.write16(0xFF80, 0xBEEF);
+        assert_eq!(mmu.read8(0xFF80), 0xEF);
+        assert_eq!(mmu.read8(0xFF81), 0xBE);
+        assert_eq!(mmu.read16(0xFF80), 0xBEEF);
+    }
+
+    #[test]
+    fn interrupt_flag_upper_bits_read_as_set() {
+        let mut mmu = mmu();
+        mmu.write8(interrupts::INTERRUPT_FLAG, 0xFF);
+        assert_eq!(mmu.interrupt_flag, 0x1F);
+        mmu.write8(interrupts::INTERRUPT_FLAG, 0x00);
+        mmu.request_interrupt(Interrupt::Timer);
+        assert_eq!(mmu.read8(interrupts::INTERRUPT_FLAG), 0xE4);
+    }
+}
@@ -1,72 +1,105 @@
+use crate::bus::Bus;
 use crate::cpu::CPU;
 use crate::instructions::*;
 use crate::cpu::CpuState;
+use crate::interrupts;
+
+/// machine cycles taken by each opcode. conditional jumps, calls and returns
+/// list the cost when the condition fails, decode adds the extra cycles when
+/// the branch is taken. 0xCB only counts the prefix, the rest comes from
+/// [decode_cb]
+#[rustfmt::skip]
+const CYCLES: [u8; 256] = [
+//  x0 x1 x2 x3 x4 x5 x6 x7 x8 x9 xA xB xC xD xE xF
+    1, 3, 2, 2, 1, 1, 2, 1, 5, 2, 2, 2, 1, 1, 2, 1, // 0x
+    1, 3, 2, 2, 1, 1, 2, 1, 3, 2, 2, 2, 1, 1, 2, 1, // 1x
+    2, 3, 2, 2, 1, 1, 2, 1, 2, 2, 2, 2, 1, 1, 2, 1, // 2x
+    2, 3, 2, 2, 3, 3, 3, 1, 2, 2, 2, 2, 1, 1, 2, 1, // 3x
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 4x
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 5x
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 6x
+    2, 2, 2, 2, 2, 2, 1, 2, 1, 1, 1, 1, 1, 1, 2, 1, // 7x
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 8x
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 9x
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // Ax
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // Bx
+    2, 3, 3, 4, 3, 4, 2, 4, 2, 4, 3, 1, 3, 6, 2, 4, // Cx
+    2, 3, 3, 1, 3, 4, 2, 4, 2, 4, 3, 1, 3, 1, 2, 4, // Dx
+    3, 3, 2, 1, 1, 4, 2, 4, 4, 1, 4, 1, 1, 1, 2, 4, // Ex
+    3, 3, 2, 1, 1, 4, 2, 4, 3, 2, 4, 1, 1, 1, 2, 4, // Fx
+];
 
 /// All opcode information can be found at
 /// [this beautiful opcode table](https://meganesu.github.io/generate-gb-opcodes/)
+///
+/// decode expects the program counter to already point past the opcode.
+/// returns the number of machine cycles the instruction took, which are
+/// also added to the CPU's running total
+pub fn decode<B: Bus>(opcode: u8, cpu: &mut CPU<B>) -> u8 {
+    let mut cycles = CYCLES[opcode as usize];
+    // an EI from the previous instruction takes effect once this one is done
+    let enable_interrupts = cpu.ime_pending;
 
-fn decode(opcode: u8, cpu: &mut CPU) {
     match opcode {
         // 0x0N instructions
         0x00 => nop(cpu),
 
         0x01 => {
             let value = cpu.get_next_two_bytes();
-            let register = &mut cpu.registers.get_bc();
-            ld16(register, value);
+            cpu.registers.set_bc(value);
         },
 
         0x02 => {
-            let address = cpu.registers.get_bc() as usize;
+            let address = cpu.registers.get_bc();
             let register = cpu.registers.a;
 
             ld_to_memory(register, address, cpu);
         },
 
         0x03 => cpu.registers.inc_bc(),
-        0x04 => inc8(&mut cpu.registers.b),
-        0x05 => dec8(&mut cpu.registers.b),
+        0x04 => inc8(&mut cpu.registers.b, &mut cpu.registers.flags),
+        0x05 => dec8(&mut cpu.registers.b, &mut cpu.registers.flags),
         0x06 => {
-            cpu.program_counter += 1;
-            let d8 = cpu.work_ram[cpu.program_counter];
+            let d8 = cpu.get_next_one_byte();
             ld8(&mut cpu.registers.b, d8);
         },
         0x07 => rlca(cpu),
 
         0x08 => {
-            let address = cpu.get_next_two_bytes() as usize;      
-            let lo_sp = cpu.stack_ptr as u8;
-            ld_to_memory(lo_sp, address, cpu);
+            let address = cpu.get_next_two_bytes();
+            cpu.bus.write16(address, cpu.stack_ptr);
         },
-        0x09 => add16(&mut cpu.registers.get_hl(), cpu.registers.get_bc(), cpu),
+        0x09 => add16(cpu.registers.get_bc(), cpu),
         0x0A => {
             let reg_bc = cpu.registers.get_bc();
-            let reg_a = &mut cpu.registers.a;
-            let value = cpu.work_ram[reg_bc as usize] as u8;
-            ld8(reg_a, value);
+            ld_from_memory(&mut cpu.registers.a, reg_bc, &mut cpu.bus);
         },
         0x0B => cpu.registers.dec_bc(),
-        0x0C => inc8(&mut cpu.registers.c),
-        0x0D => dec8(&mut cpu.registers.c),
+        0x0C => inc8(&mut cpu.registers.c, &mut cpu.registers.flags),
+        0x0D => dec8(&mut cpu.registers.c, &mut cpu.registers.flags),
         
         0x0E => {
-            cpu.program_counter += 1;
-            let value = cpu.work_ram[cpu.program_counter];
+            let value = cpu.get_next_one_byte();
             ld8(&mut cpu.registers.c, value);
         },
 
         0x0F => rrca(cpu),
 
         // 0x1N Instructions
-        0x10 => cpu.state = CpuState::STOP,
+        0x10 => {
+            // STOP is followed by a padding byte that gets skipped
+            cpu.get_next_one_byte();
+            cpu.bus.stop();
+            cpu.state = CpuState::STOP;
+        },
         0x11 => {
             let value = cpu.get_next_two_bytes();
-            ld16(&mut cpu.registers.get_de(), value);
+            cpu.registers.set_de(value);
         },
-        0x12 => ld_to_memory(cpu.registers.a, cpu.work_ram[cpu.registers.get_de() as usize] as usize, cpu),
+        0x12 => ld_to_memory(cpu.registers.a, cpu.registers.get_de(), cpu),
         0x13 => cpu.registers.inc_de(),
-        0x14 => inc8(&mut cpu.registers.d),
-        0x15 => dec8(&mut cpu.registers.d),
+        0x14 => inc8(&mut cpu.registers.d, &mut cpu.registers.flags),
+        0x15 => dec8(&mut cpu.registers.d, &mut cpu.registers.flags),
         0x16 => {
             let value = cpu.get_next_one_byte();
             ld8(&mut cpu.registers.d, value);
@@ -76,16 +109,15 @@ fn decode(opcode: u8, cpu: &mut CPU) {
             let offset = cpu.get_next_one_byte() as i8;
             jr(offset, cpu);
         },
-        0x19 => add16(&mut cpu.registers.get_hl(), cpu.registers.get_de(), cpu),
+        0x19 => add16(cpu.registers.get_de(), cpu),
         0x1A => {
-            let reg_de = cpu.registers.get_de() as usize;
+            let reg_de = cpu.registers.get_de();
             let reg_a = &mut cpu.registers.a;
-            let ram = cpu.work_ram;
-            ld_from_memory(reg_a, reg_de, ram);
+            ld_from_memory(reg_a, reg_de, &mut cpu.bus);
         },
         0x1B => cpu.registers.dec_de(),
-        0x1C => inc8(&mut cpu.registers.e),
-        0x1D => dec8(&mut cpu.registers.e),
+        0x1C => inc8(&mut cpu.registers.e, &mut cpu.registers.flags),
+        0x1D => dec8(&mut cpu.registers.e, &mut cpu.registers.flags),
         0x1E => {
             let immediate = cpu.get_next_one_byte();
             ld8(&mut cpu.registers.e, immediate);
@@ -97,22 +129,23 @@ fn decode(opcode: u8, cpu: &mut CPU) {
             if !cpu.registers.flags.z {
                 let s8 = cpu.get_next_one_byte() as i8;
                 jr(s8, cpu);
+                cycles += 1;
             } else {
-                cpu.program_counter += 1;
+                cpu.program_counter = cpu.program_counter.wrapping_add(1);
             }
         },
         0x21 => {
             let d16 = cpu.get_next_two_bytes();
-            ld16(&mut cpu.registers.get_hl(), d16);
+            cpu.registers.set_hl(d16);
         },
         0x22 => {
             let hl = cpu.registers.get_hl();
-            ld_to_memory(cpu.registers.a, hl as usize, cpu);
+            ld_to_memory(cpu.registers.a, hl, cpu);
             cpu.registers.inc_hl();
         }
         0x23 => cpu.registers.inc_hl(),
-        0x24 => inc8(&mut cpu.registers.h),
-        0x25 => dec8(&mut cpu.registers.h),
+        0x24 => inc8(&mut cpu.registers.h, &mut cpu.registers.flags),
+        0x25 => dec8(&mut cpu.registers.h, &mut cpu.registers.flags),
         0x26 => {
             let d8 = cpu.get_next_one_byte();
             ld8(&mut cpu.registers.h, d8);
@@ -122,24 +155,21 @@ fn decode(opcode: u8, cpu: &mut CPU) {
             if cpu.registers.flags.z {
                 let s8 = cpu.get_next_one_byte() as i8;
                 jr(s8, cpu);
+                cycles += 1;
             } else {
-                cpu.program_counter += 1;
+                cpu.program_counter = cpu.program_counter.wrapping_add(1);
             }
         },
-        0x29 => {
-            let mut hl = cpu.registers.get_hl();
-            hl = hl.wrapping_add(hl);
-            cpu.registers.set_hl(hl);
-        },
+        0x29 => add16(cpu.registers.get_hl(), cpu),
         0x2A => {
             let hl = cpu.registers.get_hl();
             let a = &mut cpu.registers.a;
-            ld_from_memory(a, hl as usize, cpu.work_ram);
+            ld_from_memory(a, hl, &mut cpu.bus);
             cpu.registers.set_hl(hl.wrapping_add(1));
         },
         0x2B => cpu.registers.dec_hl(),
-        0x2C => inc8(&mut cpu.registers.l),
-        0x2D => dec8(&mut cpu.registers.l),
+        0x2C => inc8(&mut cpu.registers.l, &mut cpu.registers.flags),
+        0x2D => dec8(&mut cpu.registers.l, &mut cpu.registers.flags),
         0x2E => {
             let d8 = cpu.get_next_one_byte();
             ld8(&mut cpu.registers.l, d8);
@@ -151,64 +181,74 @@ fn decode(opcode: u8, cpu: &mut CPU) {
             if !cpu.registers.flags.c {
                 let s8 = cpu.get_next_one_byte() as i8;
                 jr(s8, cpu);
+                cycles += 1;
             } else {
-                cpu.program_counter += 1;
+                cpu.program_counter = cpu.program_counter.wrapping_add(1);
             }
         },
         0x31 => {
             let d16 = cpu.get_next_two_bytes();
-            cpu.stack_ptr = d16 as usize;
+            ld16(&mut cpu.stack_ptr, d16);
         },
         0x32 => {
-            ld_to_memory(cpu.registers.a, cpu.registers.get_hl() as usize, cpu);
+            ld_to_memory(cpu.registers.a, cpu.registers.get_hl(), cpu);
             cpu.registers.dec_hl();
         },
         0x33 => {
-            cpu.stack_ptr += 1;
+            cpu.stack_ptr = cpu.stack_ptr.wrapping_add(1);
         },
         0x34 => {
-            cpu.work_ram[cpu.registers.get_hl() as usize] += 1;
+            let hl = cpu.registers.get_hl();
+            let mut value = cpu.bus.read8(hl);
+            inc8(&mut value, &mut cpu.registers.flags);
+            cpu.bus.write8(hl, value);
         },
         0x35 => {
-            cpu.work_ram[cpu.registers.get_hl() as usize] -= 1;
+            let hl = cpu.registers.get_hl();
+            let mut value = cpu.bus.read8(hl);
+            dec8(&mut value, &mut cpu.registers.flags);
+            cpu.bus.write8(hl, value);
         },
         0x36 => {
             let d8 = cpu.get_next_one_byte();
-            ld_to_memory(d8, cpu.registers.get_hl() as usize, cpu);
+            ld_to_memory(d8, cpu.registers.get_hl(), cpu);
         },
         0x37 => {
+            cpu.registers.flags.n = false;
+            cpu.registers.flags.h = false;
             cpu.registers.flags.c = true;
         },
         0x38 => {
             if cpu.registers.flags.c {
                 let s8 = cpu.get_next_one_byte() as i8;
                 jr(s8, cpu);
+                cycles += 1;
             } else {
-                cpu.program_counter += 1;
+                cpu.program_counter = cpu.program_counter.wrapping_add(1);
             }
         },
-        0x39 => {
-            add16(&mut cpu.registers.get_hl(), cpu.stack_ptr as u16, cpu);
-        },
+        0x39 => add16(cpu.stack_ptr, cpu),
         0x3A => {
             let reg_hl = cpu.registers.get_hl();
-            ld8(&mut cpu.registers.a, cpu.work_ram[reg_hl as usize]);
+            ld_from_memory(&mut cpu.registers.a, reg_hl, &mut cpu.bus);
             cpu.registers.dec_hl();
         },
         0x3B => {
-            cpu.stack_ptr -= 1;
+            cpu.stack_ptr = cpu.stack_ptr.wrapping_sub(1);
         },
         0x3C => {
-            inc8(&mut cpu.registers.a);
+            inc8(&mut cpu.registers.a, &mut cpu.registers.flags);
         },
         0x3D => {
-            dec8(&mut cpu.registers.a);
+            dec8(&mut cpu.registers.a, &mut cpu.registers.flags);
         },
         0x3E => {
             let d8 = cpu.get_next_one_byte();
             ld8(&mut cpu.registers.a, d8);
         },
         0x3F => {
+            cpu.registers.flags.n = false;
+            cpu.registers.flags.h = false;
             cpu.registers.flags.c = !cpu.registers.flags.c;
         },
 
@@ -224,7 +264,7 @@ fn decode(opcode: u8, cpu: &mut CPU) {
         0x45 => ld8(&mut cpu.registers.b, cpu.registers.l),
         0x46 => {
             let reg_hl = cpu.registers.get_hl();
-            ld_from_memory(&mut cpu.registers.b, reg_hl as usize, cpu.work_ram);
+            ld_from_memory(&mut cpu.registers.b, reg_hl, &mut cpu.bus);
         }
         0x47 => ld8(&mut cpu.registers.b, cpu.registers.a),
         0x48 => ld8(&mut cpu.registers.c, cpu.registers.b),
@@ -238,7 +278,7 @@ fn decode(opcode: u8, cpu: &mut CPU) {
         0x4D => ld8(&mut cpu.registers.c, cpu.registers.l),
         0x4E => {
             let reg_hl = cpu.registers.get_hl();
-            ld_from_memory(&mut cpu.registers.b, reg_hl as usize, cpu.work_ram);
+            ld_from_memory(&mut cpu.registers.c, reg_hl, &mut cpu.bus);
         },
         0x4F => ld8(&mut cpu.registers.c, cpu.registers.a),
 
@@ -254,7 +294,7 @@ fn decode(opcode: u8, cpu: &mut CPU) {
         0x55 => ld8(&mut cpu.registers.d, cpu.registers.l),
         0x56 => {
             let reg_hl = cpu.registers.get_hl();
-            ld_from_memory(&mut cpu.registers.d, reg_hl as usize, cpu.work_ram);
+            ld_from_memory(&mut cpu.registers.d, reg_hl, &mut cpu.bus);
         },
         0x57 => ld8(&mut cpu.registers.d, cpu.registers.a),
         0x58 => ld8(&mut cpu.registers.e, cpu.registers.b),
@@ -268,7 +308,7 @@ fn decode(opcode: u8, cpu: &mut CPU) {
         0x5D => ld8(&mut cpu.registers.e, cpu.registers.l),
         0x5E => {
             let reg_hl = cpu.registers.get_hl();
-            ld_from_memory(&mut cpu.registers.e, reg_hl as usize, cpu.work_ram);
+            ld_from_memory(&mut cpu.registers.e, reg_hl, &mut cpu.bus);
         },
         0x5F => ld8(&mut cpu.registers.e, cpu.registers.a),
         
@@ -284,7 +324,7 @@ fn decode(opcode: u8, cpu: &mut CPU) {
         0x65 => ld8(&mut cpu.registers.h, cpu.registers.l),
         0x66 => {
             let reg_hl = cpu.registers.get_hl();
-            ld_from_memory(&mut cpu.registers.h, reg_hl as usize, cpu.work_ram);
+            ld_from_memory(&mut cpu.registers.h, reg_hl, &mut cpu.bus);
         },
         0x67 => ld8(&mut cpu.registers.h, cpu.registers.a),
         0x68 => ld8(&mut cpu.registers.l, cpu.registers.b),
@@ -298,19 +338,25 @@ fn decode(opcode: u8, cpu: &mut CPU) {
         },
         0x6E => {
             let reg_hl = cpu.registers.get_hl();
-            ld_from_memory(&mut cpu.registers.l, reg_hl as usize, cpu.work_ram);
+            ld_from_memory(&mut cpu.registers.l, reg_hl, &mut cpu.bus);
         },
         0x6F => ld8(&mut cpu.registers.l, cpu.registers.a),
         
         // 0x7N instructions
-        0x70 => ld_to_memory(cpu.registers.b, cpu.registers.get_hl() as usize, cpu),
-        0x71 => ld_to_memory(cpu.registers.c, cpu.registers.get_hl() as usize, cpu),
-        0x72 => ld_to_memory(cpu.registers.d, cpu.registers.get_hl() as usize, cpu),
-        0x73 => ld_to_memory(cpu.registers.e, cpu.registers.get_hl() as usize, cpu),
-        0x74 => ld_to_memory(cpu.registers.h, cpu.registers.get_hl() as usize, cpu),
-        0x75 => ld_to_memory(cpu.registers.l, cpu.registers.get_hl() as usize, cpu),
-        0x76 => cpu.state = CpuState::HALT,
-        0x77 => ld_to_memory(cpu.registers.a, cpu.registers.get_hl() as usize, cpu),
+        0x70 => ld_to_memory(cpu.registers.b, cpu.registers.get_hl(), cpu),
+        0x71 => ld_to_memory(cpu.registers.c, cpu.registers.get_hl(), cpu),
+        0x72 => ld_to_memory(cpu.registers.d, cpu.registers.get_hl(), cpu),
+        0x73 => ld_to_memory(cpu.registers.e, cpu.registers.get_hl(), cpu),
+        0x74 => ld_to_memory(cpu.registers.h, cpu.registers.get_hl(), cpu),
+        0x75 => ld_to_memory(cpu.registers.l, cpu.registers.get_hl(), cpu),
+        0x76 => {
+            if !cpu.ime && interrupts::pending(&mut cpu.bus) != 0 {
+                cpu.halt_bug = true;
+            } else {
+                cpu.state = CpuState::HALT;
+            }
+        },
+        0x77 => ld_to_memory(cpu.registers.a, cpu.registers.get_hl(), cpu),
         0x78 => ld8(&mut cpu.registers.a, cpu.registers.b),
         0x79 => ld8(&mut cpu.registers.a, cpu.registers.c),
         0x7A => ld8(&mut cpu.registers.a, cpu.registers.d),
@@ -319,28 +365,635 @@ fn decode(opcode: u8, cpu: &mut CPU) {
         0x7D => ld8(&mut cpu.registers.a, cpu.registers.l),
         0x7E => {
             let reg_hl = cpu.registers.get_hl();
-            ld_from_memory(&mut cpu.registers.a, reg_hl as usize, cpu.work_ram);
+            ld_from_memory(&mut cpu.registers.a, reg_hl, &mut cpu.bus);
         },
         0x7F => {
             let reg_a = cpu.registers.a;
             ld8(&mut cpu.registers.a, reg_a);
         },
         // 0x8N instructions
-        //
+        0x80 => add8(cpu.registers.b, cpu),
+        0x81 => add8(cpu.registers.c, cpu),
+        0x82 => add8(cpu.registers.d, cpu),
+        0x83 => add8(cpu.registers.e, cpu),
+        0x84 => add8(cpu.registers.h, cpu),
+        0x85 => add8(cpu.registers.l, cpu),
+        0x86 => add_from_memory(cpu.registers.get_hl(), cpu),
+        0x87 => add8(cpu.registers.a, cpu),
+        0x88 => addc(cpu.registers.b, cpu),
+        0x89 => addc(cpu.registers.c, cpu),
+        0x8A => addc(cpu.registers.d, cpu),
+        0x8B => addc(cpu.registers.e, cpu),
+        0x8C => addc(cpu.registers.h, cpu),
+        0x8D => addc(cpu.registers.l, cpu),
+        0x8E => addc_from_memory(cpu.registers.get_hl(), cpu),
+        0x8F => addc(cpu.registers.a, cpu),
+
         // 0x9N instructions
-        //
+        0x90 => sub(cpu.registers.b, cpu),
+        0x91 => sub(cpu.registers.c, cpu),
+        0x92 => sub(cpu.registers.d, cpu),
+        0x93 => sub(cpu.registers.e, cpu),
+        0x94 => sub(cpu.registers.h, cpu),
+        0x95 => sub(cpu.registers.l, cpu),
+        0x96 => {
+            let mut value = 0;
+            ld_from_memory(&mut value, cpu.registers.get_hl(), &mut cpu.bus);
+            sub(value, cpu);
+        },
+        0x97 => sub(cpu.registers.a, cpu),
+        0x98 => sbc(cpu.registers.b, cpu),
+        0x99 => sbc(cpu.registers.c, cpu),
+        0x9A => sbc(cpu.registers.d, cpu),
+        0x9B => sbc(cpu.registers.e, cpu),
+        0x9C => sbc(cpu.registers.h, cpu),
+        0x9D => sbc(cpu.registers.l, cpu),
+        0x9E => {
+            let mut value = 0;
+            ld_from_memory(&mut value, cpu.registers.get_hl(), &mut cpu.bus);
+            sbc(value, cpu);
+        },
+        0x9F => sbc(cpu.registers.a, cpu),
+
         // 0xAN instructions
-        //
+        0xA0 => and(cpu.registers.b, cpu),
+        0xA1 => and(cpu.registers.c, cpu),
+        0xA2 => and(cpu.registers.d, cpu),
+        0xA3 => and(cpu.registers.e, cpu),
+        0xA4 => and(cpu.registers.h, cpu),
+        0xA5 => and(cpu.registers.l, cpu),
+        0xA6 => {
+            let mut value = 0;
+            ld_from_memory(&mut value, cpu.registers.get_hl(), &mut cpu.bus);
+            and(value, cpu);
+        },
+        0xA7 => and(cpu.registers.a, cpu),
+        0xA8 => xor(cpu.registers.b, cpu),
+        0xA9 => xor(cpu.registers.c, cpu),
+        0xAA => xor(cpu.registers.d, cpu),
+        0xAB => xor(cpu.registers.e, cpu),
+        0xAC => xor(cpu.registers.h, cpu),
+        0xAD => xor(cpu.registers.l, cpu),
+        0xAE => {
+            let mut value = 0;
+            ld_from_memory(&mut value, cpu.registers.get_hl(), &mut cpu.bus);
+            xor(value, cpu);
+        },
+        0xAF => xor(cpu.registers.a, cpu),
+
         // 0xBN instructions
-        //
+        0xB0 => or(cpu.registers.b, cpu),
+        0xB1 => or(cpu.registers.c, cpu),
+        0xB2 => or(cpu.registers.d, cpu),
+        0xB3 => or(cpu.registers.e, cpu),
+        0xB4 => or(cpu.registers.h, cpu),
+        0xB5 => or(cpu.registers.l, cpu),
+        0xB6 => {
+            let mut value = 0;
+            ld_from_memory(&mut value, cpu.registers.get_hl(), &mut cpu.bus);
+            or(value, cpu);
+        },
+        0xB7 => or(cpu.registers.a, cpu),
+        0xB8 => cp(cpu.registers.b, cpu),
+        0xB9 => cp(cpu.registers.c, cpu),
+        0xBA => cp(cpu.registers.d, cpu),
+        0xBB => cp(cpu.registers.e, cpu),
+        0xBC => cp(cpu.registers.h, cpu),
+        0xBD => cp(cpu.registers.l, cpu),
+        0xBE => {
+            let mut value = 0;
+            ld_from_memory(&mut value, cpu.registers.get_hl(), &mut cpu.bus);
+            cp(value, cpu);
+        },
+        0xBF => cp(cpu.registers.a, cpu),
+
         // 0xCN instructions
-        //
+        0xC0 => {
+            if !cpu.registers.flags.z {
+                ret(cpu);
+                cycles += 3;
+            }
+        },
+        0xC1 => {
+            let mut bc = 0;
+            pop(&mut bc, cpu);
+            cpu.registers.set_bc(bc);
+        },
+        0xC2 => {
+            let a16 = cpu.get_next_two_bytes();
+            if !cpu.registers.flags.z {
+                jp(a16, cpu);
+                cycles += 1;
+            }
+        },
+        0xC3 => {
+            let a16 = cpu.get_next_two_bytes();
+            jp(a16, cpu);
+        },
+        0xC4 => {
+            let a16 = cpu.get_next_two_bytes();
+            if !cpu.registers.flags.z {
+                call(a16, cpu);
+                cycles += 3;
+            }
+        },
+        0xC5 => push(cpu.registers.get_bc(), cpu),
+        0xC6 => {
+            let d8 = cpu.get_next_one_byte();
+            add8(d8, cpu);
+        },
+        0xC7 => call(0x00, cpu),
+        0xC8 => {
+            if cpu.registers.flags.z {
+                ret(cpu);
+                cycles += 3;
+            }
+        },
+        0xC9 => ret(cpu),
+        0xCA => {
+            let a16 = cpu.get_next_two_bytes();
+            if cpu.registers.flags.z {
+                jp(a16, cpu);
+                cycles += 1;
+            }
+        },
+        0xCB => {
+            let cb_opcode = cpu.get_next_one_byte();
+            cycles += decode_cb(cb_opcode, cpu);
+        },
+        0xCC => {
+            let a16 = cpu.get_next_two_bytes();
+            if cpu.registers.flags.z {
+                call(a16, cpu);
+                cycles += 3;
+            }
+        },
+        0xCD => {
+            let a16 = cpu.get_next_two_bytes();
+            call(a16, cpu);
+        },
+        0xCE => {
+            let d8 = cpu.get_next_one_byte();
+            addc(d8, cpu);
+        },
+        0xCF => call(0x08, cpu),
+
         // 0xDN instructions
-        //
+        0xD0 => {
+            if !cpu.registers.flags.c {
+                ret(cpu);
+                cycles += 3;
+            }
+        },
+        0xD1 => {
+            let mut de = 0;
+            pop(&mut de, cpu);
+            cpu.registers.set_de(de);
+        },
+        0xD2 => {
+            let a16 = cpu.get_next_two_bytes();
+            if !cpu.registers.flags.c {
+                jp(a16, cpu);
+                cycles += 1;
+            }
+        },
+        0xD4 => {
+            let a16 = cpu.get_next_two_bytes();
+            if !cpu.registers.flags.c {
+                call(a16, cpu);
+                cycles += 3;
+            }
+        },
+        0xD5 => push(cpu.registers.get_de(), cpu),
+        0xD6 => {
+            let d8 = cpu.get_next_one_byte();
+            sub(d8, cpu);
+        },
+        0xD7 => call(0x10, cpu),
+        0xD8 => {
+            if cpu.registers.flags.c {
+                ret(cpu);
+                cycles += 3;
+            }
+        },
+        0xD9 => {
+            ret(cpu);
+            cpu.ime = true;
+        },
+        0xDA => {
+            let a16 = cpu.get_next_two_bytes();
+            if cpu.registers.flags.c {
+                jp(a16, cpu);
+                cycles += 1;
+            }
+        },
+        0xDC => {
+            let a16 = cpu.get_next_two_bytes();
+            if cpu.registers.flags.c {
+                call(a16, cpu);
+                cycles += 3;
+            }
+        },
+        0xDE => {
+            let d8 = cpu.get_next_one_byte();
+            sbc(d8, cpu);
+        },
+        0xDF => call(0x18, cpu),
+
         // 0xEN instructions
-        //
+        0xE0 => {
+            let a8 = cpu.get_next_one_byte();
+            ld_to_memory(cpu.registers.a, 0xFF00 | a8 as u16, cpu);
+        },
+        0xE1 => {
+            let mut hl = 0;
+            pop(&mut hl, cpu);
+            cpu.registers.set_hl(hl);
+        },
+        0xE2 => ld_to_memory(cpu.registers.a, 0xFF00 | cpu.registers.c as u16, cpu),
+        0xE5 => push(cpu.registers.get_hl(), cpu),
+        0xE6 => {
+            let d8 = cpu.get_next_one_byte();
+            and(d8, cpu);
+        },
+        0xE7 => call(0x20, cpu),
+        0xE8 => {
+            let s8 = cpu.get_next_one_byte() as i8;
+            cpu.stack_ptr = add_sp_offset(s8, cpu);
+        },
+        0xE9 => jp(cpu.registers.get_hl(), cpu),
+        0xEA => {
+            let a16 = cpu.get_next_two_bytes();
+            ld_to_memory(cpu.registers.a, a16, cpu);
+        },
+        0xEE => {
+            let d8 = cpu.get_next_one_byte();
+            xor(d8, cpu);
+        },
+        0xEF => call(0x28, cpu),
+
         // 0xFN instructions
+        0xF0 => {
+            let a8 = cpu.get_next_one_byte();
+            ld_from_memory(&mut cpu.registers.a, 0xFF00 | a8 as u16, &mut cpu.bus);
+        },
+        0xF1 => {
+            let mut af = 0;
+            pop(&mut af, cpu);
+            cpu.registers.set_af(af);
+        },
+        0xF2 => {
+            let address = 0xFF00 | cpu.registers.c as u16;
+            ld_from_memory(&mut cpu.registers.a, address, &mut cpu.bus);
+        },
+        0xF3 => {
+            cpu.ime = false;
+            cpu.ime_pending = false;
+        },
+        0xF5 => push(cpu.registers.get_af(), cpu),
+        0xF6 => {
+            let d8 = cpu.get_next_one_byte();
+            or(d8, cpu);
+        },
+        0xF7 => call(0x30, cpu),
+        0xF8 => {
+            let s8 = cpu.get_next_one_byte() as i8;
+            let hl = add_sp_offset(s8, cpu);
+            cpu.registers.set_hl(hl);
+        },
+        0xF9 => cpu.stack_ptr = cpu.registers.get_hl(),
+        0xFA => {
+            let a16 = cpu.get_next_two_bytes();
+            ld_from_memory(&mut cpu.registers.a, a16, &mut cpu.bus);
+        },
+        0xFB => cpu.ime_pending = true,
+        0xFE => {
+            let d8 = cpu.get_next_one_byte();
+            cp(d8, cpu);
+        },
+        0xFF => call(0x38, cpu),
+
+        // the remaining opcodes are unused, on hardware executing
+        // one of them locks up the CPU until it is reset
+        0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD => {
+            cpu.state = CpuState::LOCKED;
+        },
+    }
+
+    if enable_interrupts && cpu.ime_pending {
+        cpu.ime = true;
+        cpu.ime_pending = false;
+    }
+
+    cpu.tick(cycles);
+    cycles
+}
+
+/// CB prefixed opcodes are laid out regularly, the lowest 3 bits select the
+/// operand (B, C, D, E, H, L, (HL), A) and bits 3-5 select either the
+/// rotate/shift operation or the bit number for BIT/RES/SET.
+/// returns the cycles taken after the 0xCB prefix itself
+fn decode_cb<B: Bus>(opcode: u8, cpu: &mut CPU<B>) -> u8 {
+    let operand = opcode & 0x07;
+    let bit_index = (opcode >> 3) & 0x07;
+    let value = read_cb_operand(operand, cpu);
+
+    let result = match opcode {
+        0x00..=0x07 => rlc(value, cpu),
+        0x08..=0x0F => rrc(value, cpu),
+        0x10..=0x17 => rl(value, cpu),
+        0x18..=0x1F => rr(value, cpu),
+        0x20..=0x27 => sla(value, cpu),
+        0x28..=0x2F => sra(value, cpu),
+        0x30..=0x37 => swap(value, cpu),
+        0x38..=0x3F => srl(value, cpu),
+        0x40..=0x7F => {
+            // BIT only reads its operand, nothing is written back
+            bit(bit_index, value, cpu);
+            return if operand == 6 { 2 } else { 1 };
+        },
+        0x80..=0xBF => res(bit_index, value),
+        0xC0..=0xFF => set(bit_index, value),
+    };
+
+    write_cb_operand(operand, result, cpu);
+    if operand == 6 { 3 } else { 1 }
+}
+
+fn read_cb_operand<B: Bus>(operand: u8, cpu: &mut CPU<B>) -> u8 {
+    match operand {
+        0 => cpu.registers.b,
+        1 => cpu.registers.c,
+        2 => cpu.registers.d,
+        3 => cpu.registers.e,
+        4 => cpu.registers.h,
+        5 => cpu.registers.l,
+        6 => {
+            let mut value = 0;
+            ld_from_memory(&mut value, cpu.registers.get_hl(), &mut cpu.bus);
+            value
+        },
+        _ => cpu.registers.a,
+    }
+}
+
+fn write_cb_operand<B: Bus>(operand: u8, value: u8, cpu: &mut CPU<B>) {
+    match operand {
+        0 => cpu.registers.b = value,
+        1 => cpu.registers.c = value,
+        2 => cpu.registers.d = value,
+        3 => cpu.registers.e = value,
+        4 => cpu.registers.h = value,
+        5 => cpu.registers.l = value,
+        6 => ld_to_memory(value, cpu.registers.get_hl(), cpu),
+        _ => cpu.registers.a = value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::FlatBus;
+
+    fn cpu_with(program: &[u8]) -> CPU<FlatBus> {
+        CPU::new(FlatBus::with_program(0x0100, program))
+    }
+
+    fn step(cpu: &mut CPU<FlatBus>) -> u8 {
+        let opcode = cpu.get_next_one_byte();
+        decode(opcode, cpu)
+    }
+
+    /// steps once per expected count and checks the machine cycles each took
+    fn assert_cycles(cpu: &mut CPU<FlatBus>, expected: &[u8]) {
+        for cycles in expected {
+            assert_eq!(step(cpu), *cycles, "at {:#06x}", cpu.program_counter);
+        }
+    }
+
+    #[test]
+    fn add_sets_zero_half_carry_and_carry() {
+        // ADD A,B
+        let mut cpu = cpu_with(&[0x80]);
+        cpu.registers.a = 0x3A;
+        cpu.registers.b = 0xC6;
+        step(&mut cpu);
+        assert_eq!(cpu.registers.a, 0x00);
+        assert!(cpu.registers.flags.z && cpu.registers.flags.h && cpu.registers.flags.c);
+        assert!(!cpu.registers.flags.n);
+    }
+
+    #[test]
+    fn sbc_subtracts_the_carry() {
+        // SBC A,d8
+        let mut cpu = cpu_with(&[0xDE, 0x3A]);
+        cpu.registers.a = 0x3B;
+        cpu.registers.flags.c = true;
+        step(&mut cpu);
+        assert_eq!(cpu.registers.a, 0x00);
+        assert!(cpu.registers.flags.z && cpu.registers.flags.n);
+        assert!(!cpu.registers.flags.h && !cpu.registers.flags.c);
+    }
+
+    #[test]
+    fn cp_sets_flags_without_changing_a() {
+        // CP B
+        let mut cpu = cpu_with(&[0xB8]);
+        cpu.registers.a = 0x3C;
+        cpu.registers.b = 0x40;
+        step(&mut cpu);
+        assert_eq!(cpu.registers.a, 0x3C);
+        assert!(!cpu.registers.flags.z && cpu.registers.flags.n && cpu.registers.flags.c);
+    }
+
+    #[test]
+    fn and_sets_half_carry() {
+        // AND d8
+        let mut cpu = cpu_with(&[0xE6, 0x0F]);
+        cpu.registers.a = 0xF0;
+        step(&mut cpu);
+        assert_eq!(cpu.registers.a, 0x00);
+        assert!(cpu.registers.flags.z && cpu.registers.flags.h && !cpu.registers.flags.c);
+    }
+
+    #[test]
+    fn push_then_pop_round_trips() {
+        // PUSH BC, POP DE
+        let mut cpu = cpu_with(&[0xC5, 0xD1]);
+        cpu.registers.set_bc(0xBEEF);
+        step(&mut cpu);
+        step(&mut cpu);
+        assert_eq!(cpu.registers.get_de(), 0xBEEF);
+        assert_eq!(cpu.stack_ptr, 0xFFFE);
+    }
+
+    #[test]
+    fn unused_opcodes_lock_the_cpu() {
+        for opcode in [0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD] {
+            let mut cpu = cpu_with(&[opcode]);
+            step(&mut cpu);
+            assert!(matches!(cpu.state, CpuState::LOCKED), "{opcode:#04x}");
+        }
+    }
+
+    #[test]
+    fn cb_swap_exchanges_nibbles() {
+        // SWAP A
+        let mut cpu = cpu_with(&[0xCB, 0x37]);
+        cpu.registers.a = 0xF1;
+        step(&mut cpu);
+        assert_eq!(cpu.registers.a, 0x1F);
+        assert!(!cpu.registers.flags.z && !cpu.registers.flags.c);
+    }
+
+    #[test]
+    fn cb_rl_rotates_through_carry() {
+        // RL C
+        let mut cpu = cpu_with(&[0xCB, 0x11]);
+        cpu.registers.c = 0x80;
+        cpu.registers.flags.c = false;
+        step(&mut cpu);
+        assert_eq!(cpu.registers.c, 0x00);
+        assert!(cpu.registers.flags.z && cpu.registers.flags.c);
+    }
+
+    #[test]
+    fn cb_sra_keeps_the_sign_bit() {
+        // SRA B
+        let mut cpu = cpu_with(&[0xCB, 0x28]);
+        cpu.registers.b = 0x81;
+        step(&mut cpu);
+        assert_eq!(cpu.registers.b, 0xC0);
+        assert!(cpu.registers.flags.c);
+    }
+
+    #[test]
+    fn cb_bit_only_sets_flags() {
+        // BIT 7,H then BIT 0,H
+        let mut cpu = cpu_with(&[0xCB, 0x7C, 0xCB, 0x44]);
+        cpu.registers.h = 0x80;
+        cpu.registers.flags.c = true;
+        step(&mut cpu);
+        assert!(!cpu.registers.flags.z && cpu.registers.flags.h && !cpu.registers.flags.n);
+        step(&mut cpu);
+        assert!(cpu.registers.flags.z && cpu.registers.flags.c);
+        assert_eq!(cpu.registers.h, 0x80);
+    }
+
+    #[test]
+    fn cb_set_and_res_write_back_through_hl() {
+        // SET 3,(HL) then RES 3,(HL)
+        let mut cpu = cpu_with(&[0xCB, 0xDE, 0xCB, 0x9E]);
+        cpu.registers.set_hl(0xC100);
+        step(&mut cpu);
+        assert_eq!(cpu.bus.read8(0xC100), 0x08);
+        step(&mut cpu);
+        assert_eq!(cpu.bus.read8(0xC100), 0x00);
+    }
+
+    #[test]
+    fn ld_a16_sp_stores_both_bytes() {
+        // LD (0xC000),SP
+        let mut cpu = cpu_with(&[0x08, 0x00, 0xC0]);
+        step(&mut cpu);
+        assert_eq!(cpu.bus.read16(0xC000), 0xFFFE);
+    }
+
+    #[test]
+    fn ld_rr_d16_and_ld_c_hl_load_their_targets() {
+        // LD HL,0xC010 then LD C,(HL)
+        let mut cpu = cpu_with(&[0x21, 0x10, 0xC0, 0x4E]);
+        cpu.bus.write8(0xC010, 0x5A);
+        step(&mut cpu);
+        assert_eq!(cpu.registers.get_hl(), 0xC010);
+        step(&mut cpu);
+        assert_eq!(cpu.registers.c, 0x5A);
+    }
+
+    #[test]
+    fn inc_and_dec_set_flags_and_wrap() {
+        // INC B then DEC B
+        let mut cpu = cpu_with(&[0x04, 0x05]);
+        cpu.registers.b = 0xFF;
+        cpu.registers.flags.c = true;
+        step(&mut cpu);
+        assert_eq!(cpu.registers.b, 0x00);
+        assert!(cpu.registers.flags.z && cpu.registers.flags.h && cpu.registers.flags.c);
+        step(&mut cpu);
+        assert_eq!(cpu.registers.b, 0xFF);
+        assert!(!cpu.registers.flags.z && cpu.registers.flags.n && cpu.registers.flags.h);
+    }
+
+    #[test]
+    fn conditional_branches_take_longer_when_taken() {
+        // XOR A; JR NZ,+0; JR Z,+0; CALL NZ,0x0200; CALL Z,0x0200
+        let mut cpu = cpu_with(&[0xAF, 0x20, 0x00, 0x28, 0x00, 0xC4, 0x00, 0x02, 0xCC, 0x00, 0x02]);
+        // RET NZ; RET Z at the call target
+        cpu.bus.memory[0x0200..0x0202].copy_from_slice(&[0xC0, 0xC8]);
+        assert_cycles(&mut cpu, &[1, 2, 3, 3, 6]);
+        assert_eq!(cpu.program_counter, 0x0200);
+        assert_cycles(&mut cpu, &[2, 5]);
+        assert_eq!(cpu.program_counter, 0x010B);
+    }
+
+    #[test]
+    fn cb_table_cycles_depend_on_the_operand() {
+        // LD HL,0xC000; RLC (HL); BIT 7,(HL); SET 0,(HL); SWAP A
+        let mut cpu = cpu_with(&[0x21, 0x00, 0xC0, 0xCB, 0x06, 0xCB, 0x7E, 0xCB, 0xC6, 0xCB, 0x37]);
+        assert_cycles(&mut cpu, &[3, 4, 3, 4, 2]);
+    }
+
+    #[test]
+    fn cycle_counter_adds_up() {
+        // PUSH BC; POP DE; LD (0xC000),SP; JP 0x0100
+        let mut cpu = cpu_with(&[0xC5, 0xD1, 0x08, 0x00, 0xC0, 0xC3, 0x00, 0x01]);
+        assert_cycles(&mut cpu, &[4, 3, 5, 4]);
+        assert_eq!(cpu.cycles, 16);
+        assert_eq!(cpu.program_counter, 0x0100);
+    }
+
+    #[test]
+    fn ei_takes_effect_after_the_next_instruction() {
+        // EI; NOP
+        let mut cpu = cpu_with(&[0xFB, 0x00]);
+        step(&mut cpu);
+        assert!(!cpu.ime);
+        step(&mut cpu);
+        assert!(cpu.ime);
+    }
+
+    #[test]
+    fn ei_then_di_never_enables_interrupts() {
+        // EI; DI; NOP
+        let mut cpu = cpu_with(&[0xFB, 0xF3, 0x00]);
+        for _ in 0..3 {
+            step(&mut cpu);
+            assert!(!cpu.ime);
+        }
+    }
+
+    #[test]
+    fn reti_enables_interrupts_immediately() {
+        // RETI with 0x0200 on the stack
+        let mut cpu = cpu_with(&[0xD9]);
+        push(0x0200, &mut cpu);
+        step(&mut cpu);
+        assert!(cpu.ime);
+        assert_eq!(cpu.program_counter, 0x0200);
+    }
 
-        _ => todo!(), 
+    #[test]
+    fn halt_bug_reads_the_next_byte_twice() {
+        // HALT; INC A
+        let mut cpu = cpu_with(&[0x76, 0x3C]);
+        cpu.bus.write8(interrupts::INTERRUPT_ENABLE, 0x04);
+        cpu.bus.write8(interrupts::INTERRUPT_FLAG, 0x04);
+        cpu.registers.a = 0x01;
+        step(&mut cpu);
+        assert!(matches!(cpu.state, CpuState::CONTINUE));
+        step(&mut cpu);
+        step(&mut cpu);
+        assert_eq!(cpu.registers.a, 0x03);
+        assert_eq!(cpu.program_counter, 0x0102);
     }
 }
@@ -0,0 +1,1119 @@
+use std::collections::VecDeque;
+
+use crate::cpu::Event;
+use crate::display::{Display, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::interrupts::Interrupt;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+/// the PPU's registers in the IO area. 0xFF46 in between is OAM DMA
+pub const LCDC: u16 = 0xFF40;
+pub const STAT: u16 = 0xFF41;
+pub const SCY: u16 = 0xFF42;
+pub const SCX: u16 = 0xFF43;
+pub const LY: u16 = 0xFF44;
+pub const LYC: u16 = 0xFF45;
+pub const BGP: u16 = 0xFF47;
+pub const OBP0: u16 = 0xFF48;
+pub const OBP1: u16 = 0xFF49;
+pub const WY: u16 = 0xFF4A;
+pub const WX: u16 = 0xFF4B;
+
+/// bits of LCDC
+const LCDC_BG_ENABLE: u8 = 0x01;
+const LCDC_OBJ_ENABLE: u8 = 0x02;
+const LCDC_OBJ_SIZE: u8 = 0x04;
+const LCDC_BG_TILE_MAP: u8 = 0x08;
+const LCDC_TILE_DATA: u8 = 0x10;
+const LCDC_WINDOW_ENABLE: u8 = 0x20;
+const LCDC_WINDOW_TILE_MAP: u8 = 0x40;
+const LCDC_LCD_ENABLE: u8 = 0x80;
+
+/// bits of STAT. bits 3-6 pick which conditions drive the STAT interrupt
+const STAT_COINCIDENCE: u8 = 0x04;
+const STAT_HBLANK_SOURCE: u8 = 0x08;
+const STAT_VBLANK_SOURCE: u8 = 0x10;
+const STAT_OAM_SCAN_SOURCE: u8 = 0x20;
+const STAT_COINCIDENCE_SOURCE: u8 = 0x40;
+const STAT_WRITABLE: u8 = 0x78;
+
+/// the PPU runs on dots, four to every machine cycle
+const DOTS_PER_CYCLE: u32 = 4;
+const DOTS_PER_LINE: u16 = 456;
+const OAM_SCAN_DOTS: u16 = 80;
+/// mode 3 length with no scrolling, window or sprites. the scanline
+/// renderer always uses it, the real length varies
+const DRAWING_DOTS: u16 = 172;
+/// each step of the FIFO's tile fetcher takes two dots
+const FETCH_STEP_DOTS: u8 = 2;
+/// the fetcher fetches the first tile of a line twice, throwing the first away
+const FIRST_FETCH_DOTS: u16 = 6;
+/// dots the fetcher spends on an object's tile
+const SPRITE_FETCH_DOTS: u16 = 6;
+const LINES_PER_FRAME: u8 = 154;
+
+/// VRAM offsets of the tile data and the two 32x32 tile maps
+const TILE_DATA_UNSIGNED: usize = 0x0000;
+const TILE_DATA_SIGNED: usize = 0x1000;
+const TILE_MAP_0: usize = 0x1800;
+const TILE_MAP_1: usize = 0x1C00;
+const TILE_BYTES: usize = 16;
+
+/// the most objects the DMG draws on one line
+pub const SPRITES_PER_LINE: usize = 10;
+const OAM_ENTRIES: usize = 40;
+
+/// bits of an object's attribute byte
+const SPRITE_PALETTE: u8 = 0x10;
+const SPRITE_X_FLIP: u8 = 0x20;
+const SPRITE_Y_FLIP: u8 = 0x40;
+const SPRITE_BEHIND_BG: u8 = 0x80;
+
+/// An OAM entry, with the position converted to screen coordinates
+#[derive(Clone, Copy, Debug)]
+struct Sprite {
+    y: i16,
+    x: i16,
+    tile: u8,
+    attributes: u8,
+    /// the object size at OAM scan, changing LCDC later in the line
+    /// doesn't change which rows were picked
+    height: i16,
+}
+
+impl Sprite {
+    fn from_oam(entry: &[u8], height: i16) -> Sprite {
+        Sprite {
+            y: entry[0] as i16 - 16,
+            x: entry[1] as i16 - 8,
+            tile: entry[2],
+            attributes: entry[3],
+            height,
+        }
+    }
+}
+
+/// How the PPU turns VRAM into pixels
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Renderer {
+    /// draws each line in one go at the end of a fixed length mode 3.
+    /// fast, but changes to the registers during mode 3 are missed
+    Scanline,
+    /// models the pixel FIFO and its fetcher dot by dot, so registers written
+    /// mid-line take effect where they would on hardware and mode 3 is
+    /// stretched by fine scrolling, the window and object fetches
+    Fifo,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FetchStep {
+    Tile,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+/// An object pixel waiting in the object FIFO. color 0 is transparent
+#[derive(Clone, Copy, Debug, Default)]
+struct SpritePixel {
+    color: u8,
+    attributes: u8,
+}
+
+/// State of the pixel FIFO renderer during mode 3. It isn't part of
+/// save states, a line interrupted by loading is drawn by the scanline renderer
+struct Fifo {
+    background: VecDeque<u8>,
+    /// lines up with the front of the background FIFO
+    sprites: VecDeque<SpritePixel>,
+    step: FetchStep,
+    step_dots: u8,
+    /// tile column the fetcher is on, counted from the start of the line or window
+    fetch_x: u8,
+    tile: u8,
+    low: u8,
+    high: u8,
+    fetching_window: bool,
+    /// pixels shifted out so far, the next one goes to this column
+    x: u8,
+    /// pixels to throw away before drawing, for SCX fine scroll
+    discard: u8,
+    /// dots left of the throwaway first fetch, nothing else happens meanwhile
+    warmup: u16,
+    /// dots pixel output is held up for by an object fetch
+    stall: u16,
+    /// objects on this line that haven't been fetched yet, in fetch order
+    pending_sprites: VecDeque<Sprite>,
+}
+
+impl Fifo {
+    fn new() -> Fifo {
+        Fifo {
+            background: VecDeque::with_capacity(8),
+            sprites: VecDeque::with_capacity(8),
+            step: FetchStep::Tile,
+            step_dots: 0,
+            fetch_x: 0,
+            tile: 0,
+            low: 0,
+            high: 0,
+            fetching_window: false,
+            x: 0,
+            discard: 0,
+            warmup: FIRST_FETCH_DOTS,
+            stall: 0,
+            pending_sprites: VecDeque::new(),
+        }
+    }
+
+    /// restarts the fetcher on a new tile row, for the window
+    fn restart_fetcher(&mut self) {
+        self.background.clear();
+        self.step = FetchStep::Tile;
+        self.step_dots = 0;
+        self.fetch_x = 0;
+    }
+}
+
+/// The PPU mode, as reported in the low bits of STAT
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+impl Mode {
+    fn from_bits(bits: u8) -> Option<Mode> {
+        match bits {
+            0 => Some(Mode::HBlank),
+            1 => Some(Mode::VBlank),
+            2 => Some(Mode::OamScan),
+            3 => Some(Mode::Drawing),
+            _ => None,
+        }
+    }
+}
+
+/// The picture processing unit. VRAM and OAM live on the
+/// [MMU](crate::mmu::MMU) and are handed in as the PPU is ticked
+pub struct PPU {
+    pub lcdc: u8,
+    /// only the interrupt enable bits, the rest of STAT is derived
+    stat: u8,
+    pub scy: u8,
+    pub scx: u8,
+    ly: u8,
+    pub lyc: u8,
+    pub bgp: u8,
+    pub obp0: u8,
+    pub obp1: u8,
+    pub wy: u8,
+    pub wx: u8,
+    mode: Mode,
+    /// dots into the current line
+    line_dot: u16,
+    /// the window has its own line counter, which only moves on
+    /// lines where the window was actually drawn
+    window_line: u8,
+    /// LY matched WY at some point this frame
+    window_triggered: bool,
+    /// the first line after the LCD is turned on skips OAM scan and
+    /// sits in mode 0 for those 80 dots instead
+    lcd_starting: bool,
+    /// the STAT interrupt line, all enabled sources ORed together. the
+    /// interrupt is only requested when it rises, so a source becoming true
+    /// while another one already holds the line high is lost
+    stat_line: bool,
+    /// interrupts raised since the last [take_interrupts](PPU::take_interrupts)
+    interrupts: u8,
+    pub display: Display,
+    /// draws every object on a line instead of stopping at
+    /// [SPRITES_PER_LINE], which removes flicker some games use on purpose
+    pub unlimited_sprites: bool,
+    /// takes effect from the next line
+    pub renderer: Renderer,
+    /// the renderer drawing the current line
+    line_renderer: Renderer,
+    fifo: Fifo,
+    events: Vec<Event>,
+}
+
+impl PPU {
+    /// creates a PPU in the state the DMG boot ROM leaves it in
+    pub fn new() -> PPU {
+        PPU {
+            lcdc: 0x91,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0xFC,
+            obp0: 0xFF,
+            obp1: 0xFF,
+            wy: 0,
+            wx: 0,
+            mode: Mode::OamScan,
+            line_dot: 0,
+            window_line: 0,
+            window_triggered: false,
+            lcd_starting: false,
+            stat_line: false,
+            interrupts: 0,
+            display: Display::new(),
+            unlimited_sprites: false,
+            renderer: Renderer::Scanline,
+            line_renderer: Renderer::Scanline,
+            fifo: Fifo::new(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn ly(&self) -> u8 {
+        self.ly
+    }
+
+    pub fn lcd_enabled(&self) -> bool {
+        self.lcdc & LCDC_LCD_ENABLE != 0
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            LCDC => self.lcdc,
+            // bit 7 is unused and always reads as 1
+            STAT => 0x80 | self.stat | self.coincidence_bit() | self.mode as u8,
+            SCY => self.scy,
+            SCX => self.scx,
+            LY => self.ly,
+            LYC => self.lyc,
+            BGP => self.bgp,
+            OBP0 => self.obp0,
+            OBP1 => self.obp1,
+            WY => self.wy,
+            WX => self.wx,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            LCDC => self.write_lcdc(value),
+            STAT => {
+                self.stat = value & STAT_WRITABLE;
+                self.update_stat_line();
+            },
+            SCY => self.scy = value,
+            SCX => self.scx = value,
+            // LY is read only
+            LY => {},
+            LYC => {
+                self.lyc = value;
+                self.update_stat_line();
+            },
+            BGP => self.bgp = value,
+            OBP0 => self.obp0 = value,
+            OBP1 => self.obp1 = value,
+            WY => self.wy = value,
+            WX => self.wx = value,
+            _ => {},
+        }
+    }
+
+    /// turning the LCD off stops the PPU at the start of line 0 in mode 0
+    /// and blanks the screen. turning it back on starts a fresh frame
+    /// whose first line skips OAM scan
+    fn write_lcdc(&mut self, value: u8) {
+        let was_enabled = self.lcd_enabled();
+        self.lcdc = value;
+        if was_enabled == self.lcd_enabled() {
+            return;
+        }
+
+        self.ly = 0;
+        self.line_dot = 0;
+        self.window_line = 0;
+        self.window_triggered = false;
+        self.mode = Mode::HBlank;
+        self.lcd_starting = self.lcd_enabled();
+        if !self.lcd_enabled() {
+            self.display.clear();
+        }
+        self.update_stat_line();
+    }
+
+    fn coincidence_bit(&self) -> u8 {
+        if self.ly == self.lyc {
+            STAT_COINCIDENCE
+        } else {
+            0
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u32, video_ram: &[u8], oam: &[u8]) {
+        if !self.lcd_enabled() {
+            return;
+        }
+        let mut dots = cycles * DOTS_PER_CYCLE;
+        while dots > 0 {
+            if self.mode == Mode::Drawing && self.line_renderer == Renderer::Fifo {
+                self.line_dot += 1;
+                dots -= 1;
+                self.fifo_dot(video_ram);
+                continue;
+            }
+            let step = dots.min(self.dots_until_next_mode() as u32);
+            self.line_dot += step as u16;
+            dots -= step;
+            if self.dots_until_next_mode() == 0 {
+                self.next_mode(video_ram, oam);
+            }
+        }
+    }
+
+    /// the dot of the line the current mode ends on
+    fn mode_end(&self) -> u16 {
+        match self.mode {
+            Mode::OamScan => OAM_SCAN_DOTS,
+            Mode::Drawing => OAM_SCAN_DOTS + DRAWING_DOTS,
+            Mode::HBlank if self.lcd_starting => OAM_SCAN_DOTS,
+            Mode::HBlank | Mode::VBlank => DOTS_PER_LINE,
+        }
+    }
+
+    fn dots_until_next_mode(&self) -> u16 {
+        // mode 3 can run past its usual end if the FIFO renderer was switched out mid-line
+        self.mode_end().saturating_sub(self.line_dot)
+    }
+
+    fn next_mode(&mut self, video_ram: &[u8], oam: &[u8]) {
+        match self.mode {
+            Mode::OamScan => self.start_drawing(oam),
+            Mode::Drawing => {
+                self.render_line(video_ram, oam);
+                self.end_drawing(self.window_visible());
+            },
+            Mode::HBlank if self.lcd_starting => {
+                self.lcd_starting = false;
+                if self.ly == self.wy {
+                    self.window_triggered = true;
+                }
+                self.start_drawing(oam);
+            },
+            Mode::HBlank | Mode::VBlank => {
+                self.line_dot = 0;
+                self.ly += 1;
+                if self.ly as usize == SCREEN_HEIGHT {
+                    self.mode = Mode::VBlank;
+                    self.interrupts |= Interrupt::VBlank.mask();
+                    self.events.push(Event::FrameReady);
+                    self.update_stat_line();
+                } else if self.ly == LINES_PER_FRAME {
+                    self.ly = 0;
+                    self.window_line = 0;
+                    self.window_triggered = false;
+                    self.start_line();
+                } else if self.mode == Mode::HBlank {
+                    self.start_line();
+                } else {
+                    // LY still moves during VBlank, which matters for LYC
+                    self.update_stat_line();
+                }
+            },
+        }
+    }
+
+    fn start_line(&mut self) {
+        self.mode = Mode::OamScan;
+        if self.ly == self.wy {
+            self.window_triggered = true;
+        }
+        self.update_stat_line();
+    }
+
+    fn start_drawing(&mut self, oam: &[u8]) {
+        self.mode = Mode::Drawing;
+        self.line_renderer = self.renderer;
+        if self.line_renderer == Renderer::Fifo {
+            self.start_fifo(oam);
+        }
+        self.update_stat_line();
+    }
+
+    /// both renderers move the window line counter on the same rule: once
+    /// for every line the window started on, whatever BG enable says
+    fn end_drawing(&mut self, window_drawn: bool) {
+        if window_drawn {
+            self.window_line += 1;
+        }
+        self.mode = Mode::HBlank;
+        self.update_stat_line();
+    }
+
+    fn stat_sources(&self) -> bool {
+        let mode_source = match self.mode {
+            Mode::HBlank => STAT_HBLANK_SOURCE,
+            Mode::VBlank => STAT_VBLANK_SOURCE,
+            Mode::OamScan => STAT_OAM_SCAN_SOURCE,
+            Mode::Drawing => 0,
+        };
+        let coincidence = self.lcd_enabled() && self.ly == self.lyc;
+        self.stat & mode_source != 0 || (coincidence && self.stat & STAT_COINCIDENCE_SOURCE != 0)
+    }
+
+    /// re-evaluates the STAT interrupt line, requesting the
+    /// interrupt if it went from low to high
+    fn update_stat_line(&mut self) {
+        let line = self.stat_sources();
+        if line && !self.stat_line {
+            self.interrupts |= Interrupt::LcdStat.mask();
+        }
+        self.stat_line = line;
+    }
+
+    /// hands over the interrupts requested since the last call, as IF bits
+    pub fn take_interrupts(&mut self) -> u8 {
+        std::mem::take(&mut self.interrupts)
+    }
+
+    /// draws the background, window and objects for line LY
+    fn render_line(&mut self, video_ram: &[u8], oam: &[u8]) {
+        let y = self.ly as usize;
+        let window_visible = self.window_visible();
+        let sprites = if self.lcdc & LCDC_OBJ_ENABLE != 0 {
+            self.line_sprites(oam)
+        } else {
+            Vec::new()
+        };
+
+        for x in 0..SCREEN_WIDTH {
+            // on the DMG clearing the BG enable bit blanks the window too
+            let bg_color = if self.lcdc & LCDC_BG_ENABLE == 0 {
+                0
+            } else if window_visible && x + 7 >= self.wx as usize {
+                let map = self.tile_map(LCDC_WINDOW_TILE_MAP);
+                self.tile_map_color(video_ram, map, x + 7 - self.wx as usize, self.window_line as usize)
+            } else {
+                let map = self.tile_map(LCDC_BG_TILE_MAP);
+                let bg_x = (x + self.scx as usize) % 256;
+                let bg_y = (y + self.scy as usize) % 256;
+                self.tile_map_color(video_ram, map, bg_x, bg_y)
+            };
+
+            // only the first opaque object counts, even if it ends up behind the background
+            let sprite = sprites.iter().find_map(|sprite| {
+                let color = self.sprite_color(video_ram, sprite, x as i16)?;
+                Some(SpritePixel {
+                    color,
+                    attributes: sprite.attributes,
+                })
+            });
+            let pixel = self.mix(bg_color, sprite);
+            self.display.set_pixel(x, y, pixel);
+        }
+    }
+
+    /// the shade of a pixel once the object on top of the background has been resolved
+    fn mix(&self, bg_color: u8, sprite: Option<SpritePixel>) -> u8 {
+        match sprite {
+            Some(sprite)
+                if sprite.color != 0 && (sprite.attributes & SPRITE_BEHIND_BG == 0 || bg_color == 0) =>
+            {
+                let palette = if sprite.attributes & SPRITE_PALETTE != 0 {
+                    self.obp1
+                } else {
+                    self.obp0
+                };
+                shade(palette, sprite.color)
+            },
+            _ => shade(self.bgp, bg_color),
+        }
+    }
+
+    fn window_visible(&self) -> bool {
+        self.lcdc & LCDC_WINDOW_ENABLE != 0 && self.window_triggered && self.wx <= 166
+    }
+
+    fn start_fifo(&mut self, oam: &[u8]) {
+        self.fifo = Fifo::new();
+        self.fifo.discard = self.scx % 8;
+        self.fifo.pending_sprites = self.line_sprites(oam).into();
+    }
+
+    /// one dot of mode 3 with the FIFO renderer. every register is read
+    /// when the hardware would read it, so changes made by the CPU
+    /// part way through the line show up part way through the line
+    fn fifo_dot(&mut self, video_ram: &[u8]) {
+        if self.fifo.warmup > 0 {
+            self.fifo.warmup -= 1;
+            return;
+        }
+        if self.fifo.stall > 0 {
+            // the background fetcher finishes its tile while the object waits
+            self.fifo.stall -= 1;
+            self.fetcher_dot(video_ram);
+            return;
+        }
+
+        if !self.fifo.fetching_window && self.window_visible() && self.fifo.x as usize + 7 >= self.wx as usize {
+            self.fifo.fetching_window = true;
+            self.fifo.restart_fetcher();
+            // a window starting left of the screen edge is shifted out of view
+            self.fifo.discard = 7u8.saturating_sub(self.wx);
+        }
+
+        if self.fifo.discard == 0 && self.lcdc & LCDC_OBJ_ENABLE != 0 {
+            if let Some(sprite) = self.fifo.pending_sprites.front().copied() {
+                if sprite.x <= self.fifo.x as i16 {
+                    self.fifo.pending_sprites.pop_front();
+                    self.fetch_sprite(video_ram, &sprite);
+                    return;
+                }
+            }
+        }
+
+        self.fetcher_dot(video_ram);
+        self.shift_pixel();
+    }
+
+    fn fetcher_dot(&mut self, video_ram: &[u8]) {
+        if self.fifo.step == FetchStep::Push {
+            // the DMG fetcher can only push into an empty FIFO
+            if self.fifo.background.is_empty() {
+                for column in 0..8 {
+                    let color = tile_color(self.fifo.low, self.fifo.high, column);
+                    self.fifo.background.push_back(color);
+                }
+                self.fifo.fetch_x = self.fifo.fetch_x.wrapping_add(1);
+                self.fifo.step = FetchStep::Tile;
+            }
+            return;
+        }
+
+        self.fifo.step_dots += 1;
+        if self.fifo.step_dots < FETCH_STEP_DOTS {
+            return;
+        }
+        self.fifo.step_dots = 0;
+
+        let (map, x, y) = if self.fifo.fetching_window {
+            (self.tile_map(LCDC_WINDOW_TILE_MAP), self.fifo.fetch_x as usize * 8, self.window_line as usize)
+        } else {
+            let x = (self.scx as usize / 8 + self.fifo.fetch_x as usize) % 32 * 8;
+            (self.tile_map(LCDC_BG_TILE_MAP), x, (self.ly as usize + self.scy as usize) % 256)
+        };
+        match self.fifo.step {
+            FetchStep::Tile => {
+                self.fifo.tile = video_ram[map + (y / 8) * 32 + (x / 8) % 32];
+                self.fifo.step = FetchStep::DataLow;
+            },
+            FetchStep::DataLow => {
+                self.fifo.low = video_ram[self.tile_address(self.fifo.tile) + (y % 8) * 2];
+                self.fifo.step = FetchStep::DataHigh;
+            },
+            FetchStep::DataHigh => {
+                self.fifo.high = video_ram[self.tile_address(self.fifo.tile) + (y % 8) * 2 + 1];
+                self.fifo.step = FetchStep::Push;
+            },
+            FetchStep::Push => {},
+        }
+    }
+
+    /// fetches an object's row into the object FIFO. pixel output waits for
+    /// the background fetcher to finish its tile and then for the object's
+    fn fetch_sprite(&mut self, video_ram: &[u8], sprite: &Sprite) {
+        let x = self.fifo.x as i16;
+        for (slot, screen_x) in (x..sprite.x + 8).enumerate() {
+            if self.fifo.sprites.len() <= slot {
+                self.fifo.sprites.push_back(SpritePixel::default());
+            }
+            // objects fetched earlier win, so only transparent pixels are replaced
+            if self.fifo.sprites[slot].color == 0 {
+                if let Some(color) = self.sprite_color(video_ram, sprite, screen_x) {
+                    self.fifo.sprites[slot] = SpritePixel {
+                        color,
+                        attributes: sprite.attributes,
+                    };
+                }
+            }
+        }
+
+        let fetch_remaining = match self.fifo.step {
+            FetchStep::Push => 0,
+            step => {
+                let steps_left = FetchStep::Push as u16 - step as u16;
+                steps_left * FETCH_STEP_DOTS as u16 - self.fifo.step_dots as u16
+            },
+        };
+        // this dot counts towards the fetch
+        self.fifo.stall = fetch_remaining + SPRITE_FETCH_DOTS - 1;
+    }
+
+    fn shift_pixel(&mut self) {
+        let Some(bg_color) = self.fifo.background.pop_front() else {
+            return;
+        };
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return;
+        }
+
+        let bg_color = if self.lcdc & LCDC_BG_ENABLE == 0 {
+            0
+        } else {
+            bg_color
+        };
+        let sprite = self.fifo.sprites.pop_front();
+        let sprite = sprite.filter(|_| self.lcdc & LCDC_OBJ_ENABLE != 0);
+        let pixel = self.mix(bg_color, sprite);
+        self.display.set_pixel(self.fifo.x as usize, self.ly as usize, pixel);
+
+        self.fifo.x += 1;
+        if self.fifo.x as usize == SCREEN_WIDTH {
+            self.end_drawing(self.fifo.fetching_window);
+        }
+    }
+
+    fn sprite_height(&self) -> i16 {
+        if self.lcdc & LCDC_OBJ_SIZE != 0 {
+            16
+        } else {
+            8
+        }
+    }
+
+    /// the objects on line LY, in the order they are drawn. OAM is scanned
+    /// front to back and stops at the line limit, then on the DMG the object
+    /// with the lower X wins, and OAM order breaks ties
+    fn line_sprites(&self, oam: &[u8]) -> Vec<Sprite> {
+        let y = self.ly as i16;
+        let height = self.sprite_height();
+        let limit = if self.unlimited_sprites {
+            OAM_ENTRIES
+        } else {
+            SPRITES_PER_LINE
+        };
+
+        let mut sprites: Vec<Sprite> = oam
+            .chunks_exact(4)
+            .map(|entry| Sprite::from_oam(entry, height))
+            .filter(|sprite| y >= sprite.y && y < sprite.y + height)
+            .take(limit)
+            .collect();
+        // a stable sort keeps OAM order between objects at the same X
+        sprites.sort_by_key(|sprite| sprite.x);
+        sprites
+    }
+
+    /// the sprite's color index at screen column x, or None where
+    /// it doesn't cover x or is transparent
+    fn sprite_color(&self, video_ram: &[u8], sprite: &Sprite, x: i16) -> Option<u8> {
+        if x < sprite.x || x >= sprite.x + 8 {
+            return None;
+        }
+        let height = sprite.height;
+        let mut row = self.ly as i16 - sprite.y;
+        if sprite.attributes & SPRITE_Y_FLIP != 0 {
+            row = height - 1 - row;
+        }
+        let mut column = x - sprite.x;
+        if sprite.attributes & SPRITE_X_FLIP != 0 {
+            column = 7 - column;
+        }
+        // tall objects ignore the lowest bit of the tile number
+        let tile = if height == 16 {
+            sprite.tile & 0xFE
+        } else {
+            sprite.tile
+        };
+
+        let address = TILE_DATA_UNSIGNED + tile as usize * TILE_BYTES + row as usize * 2;
+        match tile_color(video_ram[address], video_ram[address + 1], column as usize) {
+            0 => None,
+            color => Some(color),
+        }
+    }
+
+    /// the VRAM offset of the tile map picked by the given LCDC bit
+    fn tile_map(&self, select: u8) -> usize {
+        if self.lcdc & select != 0 {
+            TILE_MAP_1
+        } else {
+            TILE_MAP_0
+        }
+    }
+
+    /// the color index at x, y of the 256x256 picture a tile map describes
+    fn tile_map_color(&self, video_ram: &[u8], map: usize, x: usize, y: usize) -> u8 {
+        let tile = video_ram[map + (y / 8) * 32 + x / 8];
+        let row = self.tile_address(tile) + (y % 8) * 2;
+        tile_color(video_ram[row], video_ram[row + 1], x % 8)
+    }
+
+    /// LCDC bit 4 picks between tiles 0-255 from 0x8000, or tiles
+    /// -128-127 around 0x9000. objects always use the first
+    fn tile_address(&self, tile: u8) -> usize {
+        if self.lcdc & LCDC_TILE_DATA != 0 {
+            TILE_DATA_UNSIGNED + tile as usize * TILE_BYTES
+        } else {
+            (TILE_DATA_SIGNED as isize + (tile as i8) as isize * TILE_BYTES as isize) as usize
+        }
+    }
+
+    pub fn drain_events(&mut self, events: &mut Vec<Event>) {
+        events.append(&mut self.events);
+    }
+}
+
+impl Default for PPU {
+    fn default() -> Self {
+        PPU::new()
+    }
+}
+
+/// a tile row is two bytes, the low and high bit planes. the
+/// leftmost pixel is bit 7
+pub fn tile_color(low: u8, high: u8, x: usize) -> u8 {
+    let bit = 7 - x;
+    ((high >> bit) & 1) << 1 | ((low >> bit) & 1)
+}
+
+/// maps a color index through a palette register to a shade
+pub fn shade(palette: u8, color: u8) -> u8 {
+    (palette >> (color * 2)) & 0x03
+}
+
+impl SaveState for PPU {
+    fn save_state(&self, writer: &mut StateWriter) {
+        for register in [
+            self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc, self.bgp, self.obp0, self.obp1, self.wy,
+            self.wx,
+        ] {
+            writer.u8(register);
+        }
+        writer.u8(self.mode as u8);
+        writer.u16(self.line_dot);
+        writer.u8(self.window_line);
+        writer.bool(self.window_triggered);
+        writer.bool(self.lcd_starting);
+        self.display.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.lcdc = reader.u8()?;
+        self.stat = reader.u8()? & STAT_WRITABLE;
+        self.scy = reader.u8()?;
+        self.scx = reader.u8()?;
+        self.ly = reader.u8()?;
+        self.lyc = reader.u8()?;
+        self.bgp = reader.u8()?;
+        self.obp0 = reader.u8()?;
+        self.obp1 = reader.u8()?;
+        self.wy = reader.u8()?;
+        self.wx = reader.u8()?;
+        self.mode = Mode::from_bits(reader.u8()?).ok_or(StateError::InvalidValue("PPU mode"))?;
+        self.line_dot = reader.u16()?;
+        self.window_line = reader.u8()?;
+        self.window_triggered = reader.bool()?;
+        self.lcd_starting = reader.bool()?;
+        if self.ly >= LINES_PER_FRAME || self.line_dot >= DOTS_PER_LINE {
+            return Err(StateError::InvalidValue("PPU position"));
+        }
+        self.line_renderer = Renderer::Scanline;
+        // the line only ever changes together with the state it is derived from
+        self.stat_line = self.stat_sources();
+        self.display.load_state(reader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CYCLES_PER_LINE: u32 = DOTS_PER_LINE as u32 / DOTS_PER_CYCLE;
+
+    /// fills the 8 rows of the tile at address with color
+    fn solid_tile(video_ram: &mut [u8], address: usize, color: u8) {
+        for row in 0..8 {
+            video_ram[address + row * 2] = if color & 1 != 0 { 0xFF } else { 0x00 };
+            video_ram[address + row * 2 + 1] = if color & 2 != 0 { 0xFF } else { 0x00 };
+        }
+    }
+
+    fn run_lines(ppu: &mut PPU, video_ram: &[u8], lines: u32) {
+        ppu.tick(lines * CYCLES_PER_LINE, video_ram, &[0; 160]);
+    }
+
+    fn run_lines_with_oam(ppu: &mut PPU, video_ram: &[u8], oam: &[u8], lines: u32) {
+        ppu.tick(lines * CYCLES_PER_LINE, video_ram, oam);
+    }
+
+    #[test]
+    fn background_scrolls_through_the_palette() {
+        let mut video_ram = vec![0; 0x2000];
+        solid_tile(&mut video_ram, TILE_BYTES, 3);
+        video_ram[TILE_MAP_0 + 1] = 1;
+        let mut ppu = PPU::new();
+        ppu.bgp = 0xE4;
+        ppu.scx = 4;
+        run_lines(&mut ppu, &video_ram, 1);
+        let row: Vec<u8> = (2..14).map(|x| ppu.display.pixel(x, 0)).collect();
+        assert_eq!(row, [0, 0, 3, 3, 3, 3, 3, 3, 3, 3, 0, 0]);
+
+        // an inverted palette, on the second line
+        ppu.bgp = 0x1B;
+        run_lines(&mut ppu, &video_ram, 1);
+        assert_eq!(ppu.display.pixel(0, 1), 3);
+        assert_eq!(ppu.display.pixel(4, 1), 0);
+    }
+
+    #[test]
+    fn signed_tile_data_is_addressed_around_0x9000() {
+        let mut video_ram = vec![0; 0x2000];
+        solid_tile(&mut video_ram, TILE_DATA_SIGNED - 0x80 * TILE_BYTES, 1);
+        solid_tile(&mut video_ram, TILE_DATA_SIGNED, 2);
+        video_ram[TILE_MAP_0] = 0x80;
+        let mut ppu = PPU::new();
+        ppu.lcdc = 0x81;
+        ppu.bgp = 0xE4;
+        run_lines(&mut ppu, &video_ram, 1);
+        assert_eq!(ppu.display.pixel(0, 0), 1);
+        assert_eq!(ppu.display.pixel(8, 0), 2);
+    }
+
+    #[test]
+    fn window_line_only_counts_lines_the_window_was_drawn() {
+        for renderer in [Renderer::Scanline, Renderer::Fifo] {
+            window_line_counts_drawn_lines(renderer);
+        }
+    }
+
+    fn window_line_counts_drawn_lines(renderer: Renderer) {
+        let mut video_ram = vec![0; 0x2000];
+        solid_tile(&mut video_ram, TILE_BYTES, 3);
+        solid_tile(&mut video_ram, 2 * TILE_BYTES, 1);
+        video_ram[TILE_MAP_1..TILE_MAP_1 + 32].fill(1);
+        video_ram[TILE_MAP_1 + 32..TILE_MAP_1 + 64].fill(2);
+        let mut ppu = PPU::new();
+        ppu.renderer = renderer;
+        ppu.lcdc = 0x91 | LCDC_WINDOW_ENABLE | LCDC_WINDOW_TILE_MAP;
+        ppu.bgp = 0xE4;
+        ppu.wx = 7;
+        // WY is compared as each line starts, so begin on a fresh frame
+        run_lines(&mut ppu, &video_ram, LINES_PER_FRAME as u32);
+
+        run_lines(&mut ppu, &video_ram, 4);
+        assert_eq!(ppu.display.pixel(0, 3), 3);
+        ppu.lcdc &= !LCDC_WINDOW_ENABLE;
+        run_lines(&mut ppu, &video_ram, 8);
+        assert_eq!(ppu.display.pixel(0, 11), 0);
+
+        // line 12 picks up at window line 4, still in the first tile row
+        ppu.lcdc |= LCDC_WINDOW_ENABLE;
+        run_lines(&mut ppu, &video_ram, 5);
+        assert_eq!(ppu.display.pixel(0, 12), 3);
+        assert_eq!(ppu.display.pixel(0, 16), 1);
+    }
+
+    #[test]
+    fn frame_ready_at_the_start_of_vblank() {
+        let video_ram = vec![0; 0x2000];
+        let mut ppu = PPU::new();
+        let mut events = Vec::new();
+        run_lines(&mut ppu, &video_ram, SCREEN_HEIGHT as u32 - 1);
+        ppu.drain_events(&mut events);
+        assert!(events.is_empty());
+
+        run_lines(&mut ppu, &video_ram, 1);
+        ppu.drain_events(&mut events);
+        assert_eq!(events, vec![Event::FrameReady]);
+        assert_eq!((ppu.ly(), ppu.mode()), (SCREEN_HEIGHT as u8, Mode::VBlank));
+
+        run_lines(&mut ppu, &video_ram, (LINES_PER_FRAME as usize - SCREEN_HEIGHT) as u32);
+        assert_eq!((ppu.ly(), ppu.mode()), (0, Mode::OamScan));
+    }
+
+    /// OAM with 8x8 objects at the given screen positions, all using tile 1
+    fn oam_with(sprites: &[(i16, i16, u8)]) -> Vec<u8> {
+        let mut oam = vec![0; 160];
+        for (index, (x, y, attributes)) in sprites.iter().enumerate() {
+            oam[index * 4..index * 4 + 4].copy_from_slice(&[(y + 16) as u8, (x + 8) as u8, 1, *attributes]);
+        }
+        oam
+    }
+
+    #[test]
+    fn line_limit_drops_the_eleventh_object() {
+        let mut video_ram = vec![0; 0x2000];
+        solid_tile(&mut video_ram, TILE_BYTES, 3);
+        let positions: Vec<(i16, i16, u8)> = (0..11).map(|index| (index * 10, 0, 0)).collect();
+        let oam = oam_with(&positions);
+
+        for (unlimited_sprites, drawn) in [(false, 10), (true, 11)] {
+            let mut ppu = PPU::new();
+            ppu.lcdc |= LCDC_OBJ_ENABLE;
+            ppu.obp0 = 0xE4;
+            ppu.unlimited_sprites = unlimited_sprites;
+            run_lines_with_oam(&mut ppu, &video_ram, &oam, 1);
+            let visible = positions.iter().filter(|(x, _, _)| ppu.display.pixel(*x as usize, 0) == 3).count();
+            assert_eq!(visible, drawn);
+        }
+    }
+
+    #[test]
+    fn lower_x_wins_and_oam_order_breaks_ties() {
+        let mut video_ram = vec![0; 0x2000];
+        solid_tile(&mut video_ram, TILE_BYTES, 3);
+        // the first object uses OBP1, the later ones OBP0
+        let oam = oam_with(&[(4, 0, SPRITE_PALETTE), (0, 0, 0), (20, 0, SPRITE_PALETTE), (20, 0, 0)]);
+        let mut ppu = PPU::new();
+        ppu.lcdc |= LCDC_OBJ_ENABLE;
+        ppu.obp0 = 0xE4;
+        ppu.obp1 = 0x54;
+        run_lines_with_oam(&mut ppu, &video_ram, &oam, 1);
+        assert_eq!(ppu.display.pixel(6, 0), 3);
+        assert_eq!(ppu.display.pixel(10, 0), 1);
+        assert_eq!(ppu.display.pixel(20, 0), 1);
+    }
+
+    #[test]
+    fn objects_behind_the_background_only_cover_color_0() {
+        let mut video_ram = vec![0; 0x2000];
+        solid_tile(&mut video_ram, TILE_BYTES, 3);
+        solid_tile(&mut video_ram, 2 * TILE_BYTES, 1);
+        video_ram[TILE_MAP_0 + 1] = 2;
+        let oam = oam_with(&[(4, 0, SPRITE_BEHIND_BG)]);
+        let mut ppu = PPU::new();
+        ppu.lcdc |= LCDC_OBJ_ENABLE;
+        ppu.bgp = 0xE4;
+        ppu.obp0 = 0xE4;
+        run_lines_with_oam(&mut ppu, &video_ram, &oam, 1);
+        assert_eq!(ppu.display.pixel(7, 0), 3);
+        assert_eq!(ppu.display.pixel(8, 0), 1);
+    }
+
+    #[test]
+    fn lcd_on_starts_line_0_in_mode_0_without_oam_scan() {
+        let video_ram = vec![0; 0x2000];
+        let mut ppu = PPU::new();
+        ppu.write(STAT, STAT_OAM_SCAN_SOURCE);
+        ppu.write(LCDC, 0x11);
+        run_lines(&mut ppu, &video_ram, 3);
+        assert_eq!((ppu.ly(), ppu.mode()), (0, Mode::HBlank));
+        ppu.take_interrupts();
+
+        ppu.write(LCDC, 0x91);
+        ppu.tick(OAM_SCAN_DOTS as u32 / DOTS_PER_CYCLE - 1, &video_ram, &[0; 160]);
+        assert_eq!((ppu.ly(), ppu.mode()), (0, Mode::HBlank));
+        ppu.tick(1, &video_ram, &[0; 160]);
+        assert_eq!(ppu.mode(), Mode::Drawing);
+        assert_eq!(ppu.take_interrupts() & Interrupt::LcdStat.mask(), 0);
+
+        // line 1 is a normal line again
+        ppu.tick(CYCLES_PER_LINE - OAM_SCAN_DOTS as u32 / DOTS_PER_CYCLE, &video_ram, &[0; 160]);
+        assert_eq!((ppu.ly(), ppu.mode()), (1, Mode::OamScan));
+        assert_ne!(ppu.take_interrupts() & Interrupt::LcdStat.mask(), 0);
+    }
+
+    #[test]
+    fn lyc_interrupt_when_ly_reaches_lyc() {
+        let video_ram = vec![0; 0x2000];
+        let mut ppu = PPU::new();
+        ppu.write(LYC, 5);
+        ppu.write(STAT, STAT_COINCIDENCE_SOURCE);
+        run_lines(&mut ppu, &video_ram, 4);
+        assert_eq!(ppu.take_interrupts() & Interrupt::LcdStat.mask(), 0);
+        assert_eq!(ppu.read(STAT) & STAT_COINCIDENCE, 0);
+
+        run_lines(&mut ppu, &video_ram, 1);
+        assert_eq!(ppu.take_interrupts(), Interrupt::LcdStat.mask());
+        assert_eq!(ppu.read(STAT) & STAT_COINCIDENCE, STAT_COINCIDENCE);
+
+        // LY keeps counting through VBlank
+        ppu.write(LYC, 150);
+        run_lines(&mut ppu, &video_ram, 150 - 5);
+        assert_eq!(ppu.take_interrupts(), Interrupt::VBlank.mask() | Interrupt::LcdStat.mask());
+    }
+
+    #[test]
+    fn a_held_stat_line_blocks_the_next_source() {
+        let video_ram = vec![0; 0x2000];
+        let mut ppu = PPU::new();
+        ppu.write(STAT, STAT_HBLANK_SOURCE | STAT_COINCIDENCE_SOURCE);
+        ppu.write(LYC, 1);
+        run_lines(&mut ppu, &video_ram, 1);
+        assert_eq!(ppu.take_interrupts(), Interrupt::LcdStat.mask());
+
+        // LY=LYC holds the line high from the end of line 0's HBlank
+        // through line 1's HBlank, so that one raises nothing
+        run_lines(&mut ppu, &video_ram, 1);
+        assert_eq!(ppu.take_interrupts(), 0);
+
+        run_lines(&mut ppu, &video_ram, 1);
+        assert_eq!(ppu.take_interrupts(), Interrupt::LcdStat.mask());
+    }
+
+    #[test]
+    fn object_size_change_during_mode_3_keeps_scanned_height() {
+        let mut video_ram = vec![0; 0x2000];
+        // tile 2 is the lower half of the tall object, fill it so it draws
+        video_ram[2 * TILE_BYTES..3 * TILE_BYTES].fill(0xFF);
+        let mut oam = vec![0; 0xA0];
+        // a Y flipped 8x16 object whose lower half covers line 1
+        oam[..4].copy_from_slice(&[9, 40, 2, SPRITE_Y_FLIP]);
+
+        let mut ppu = PPU::new();
+        ppu.renderer = Renderer::Fifo;
+        ppu.write(LCDC, 0x91 | LCDC_OBJ_ENABLE | LCDC_OBJ_SIZE);
+        ppu.write(OBP0, 0xE4);
+        run_lines_with_oam(&mut ppu, &video_ram, &oam, 1);
+        ppu.tick(OAM_SCAN_DOTS as u32 / DOTS_PER_CYCLE + 2, &video_ram, &oam);
+        assert_eq!(ppu.mode(), Mode::Drawing);
+
+        ppu.write(LCDC, 0x91 | LCDC_OBJ_ENABLE);
+        run_lines_with_oam(&mut ppu, &video_ram, &oam, 1);
+        assert_eq!(ppu.ly(), 2);
+        assert_eq!(ppu.display.pixel(32, 1), 3);
+    }
+
+    #[test]
+    fn renderers_agree_on_a_static_scene() {
+        let mut video_ram = vec![0; 0x2000];
+        // tiles with a different pattern on every row so scrolling and flips show
+        for (index, byte) in video_ram[..4 * TILE_BYTES].iter_mut().enumerate() {
+            *byte = (index as u8).wrapping_mul(37) ^ 0x5A;
+        }
+        for (index, tile) in video_ram[TILE_MAP_0..TILE_MAP_0 + 0x400].iter_mut().enumerate() {
+            *tile = (index % 3) as u8;
+        }
+        let oam = oam_with(&[
+            (-3, 10, 0),
+            (20, 30, SPRITE_X_FLIP),
+            (24, 34, SPRITE_Y_FLIP | SPRITE_PALETTE),
+            (60, 70, SPRITE_BEHIND_BG),
+            (156, 100, 0),
+        ]);
+
+        let frames: Vec<_> = [Renderer::Scanline, Renderer::Fifo]
+            .into_iter()
+            .map(|renderer| {
+                let mut ppu = PPU::new();
+                ppu.renderer = renderer;
+                ppu.write(LCDC, 0x91 | LCDC_OBJ_ENABLE);
+                ppu.write(SCX, 13);
+                ppu.write(SCY, 5);
+                ppu.write(BGP, 0xE4);
+                ppu.write(OBP0, 0xD2);
+                ppu.write(OBP1, 0x1B);
+                run_lines_with_oam(&mut ppu, &video_ram, &oam, SCREEN_HEIGHT as u32);
+                *ppu.display.rows()
+            })
+            .collect();
+        assert!(frames[0].iter().flatten().any(|shade| *shade != frames[0][0][0]));
+        assert_eq!(frames[0], frames[1]);
+    }
+}
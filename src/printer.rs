@@ -0,0 +1,412 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::display::SCREEN_WIDTH;
+use crate::ppu::{shade, tile_color};
+use crate::serial::SerialLink;
+
+/// every packet starts with these two bytes
+const MAGIC: [u8; 2] = [0x88, 0x33];
+/// what the printer answers with on the first byte after the checksum
+const ALIVE: u8 = 0x81;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_BUSY: u8 = 0x02;
+const STATUS_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED: u8 = 0x08;
+const STATUS_PACKET_ERROR: u8 = 0x10;
+
+/// the image is 20 tiles wide, each tile row 16 bytes
+const TILES_PER_ROW: usize = SCREEN_WIDTH / 8;
+const BYTES_PER_TILE_ROW: usize = TILES_PER_ROW * 16;
+/// a full data packet is two rows of tiles
+const BAND_SIZE: usize = BYTES_PER_TILE_ROW * 2;
+/// the printer's memory holds nine bands, a screen and a bit
+const BUFFER_SIZE: usize = BAND_SIZE * 9;
+
+/// each unit of margin feeds a band's height of blank paper
+const ROWS_PER_FEED: usize = 16;
+/// the printer is polled every bit time while idle, roughly 4ms of printing per pixel row
+const POLLS_PER_ROW: u32 = 32;
+/// a palette of 0 is taken as the default one
+const DEFAULT_PALETTE: u8 = 0xE4;
+
+/// gray levels for the four shades, lightest first
+const GRAYS: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+/// Where the printer is in receiving a packet
+#[derive(Clone, Copy)]
+enum Receive {
+    Magic(usize),
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+/// The Game Boy Printer. The game drives the clock and sends packets of
+/// magic, command, compression flag, length, data and checksum, then two
+/// more bytes that the printer answers with 0x81 and its status.
+///
+/// Printed image data is fed onto the paper along with its margins. The
+/// paper is torn off and written out as a PGM file once a print ends with a
+/// bottom margin, or when the printer is dropped with a part printed page
+pub struct Printer {
+    directory: PathBuf,
+    receive: Receive,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+    status: u8,
+    /// decompressed image data waiting to be printed
+    buffer: Vec<u8>,
+    /// rows of shades printed on the current page
+    paper: Vec<[u8; SCREEN_WIDTH]>,
+    busy_polls: u32,
+    pages: Arc<Mutex<Vec<io::Result<PathBuf>>>>,
+}
+
+impl Printer {
+    /// finished pages are written to directory as print-001.pgm and so on
+    pub fn new(directory: impl AsRef<Path>) -> Printer {
+        Printer {
+            directory: directory.as_ref().to_path_buf(),
+            receive: Receive::Magic(0),
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+            status: 0,
+            buffer: Vec::with_capacity(BUFFER_SIZE),
+            paper: Vec::new(),
+            busy_polls: 0,
+            pages: Arc::default(),
+        }
+    }
+
+    /// a handle to the pages written so far, the file or the error writing
+    /// it, that stays usable once the printer is plugged in
+    pub fn pages(&self) -> Arc<Mutex<Vec<io::Result<PathBuf>>>> {
+        Arc::clone(&self.pages)
+    }
+
+    /// takes in one byte of a packet, returning what the printer shifts out
+    /// at the same time. the answer is loaded before the byte arrives
+    fn receive(&mut self, byte: u8) -> u8 {
+        let reply = match self.receive {
+            Receive::Alive => ALIVE,
+            Receive::Status => self.status(),
+            _ => 0x00,
+        };
+        // the checksum covers everything from the command to the end of the data
+        if matches!(
+            self.receive,
+            Receive::Command | Receive::Compression | Receive::LengthLow | Receive::LengthHigh | Receive::Data
+        ) {
+            self.checksum = self.checksum.wrapping_add(byte as u16);
+        }
+
+        self.receive = match self.receive {
+            // out of sync bytes are ignored until the magic comes around again
+            Receive::Magic(index) if byte != MAGIC[index] => Receive::Magic(if byte == MAGIC[0] { 1 } else { 0 }),
+            Receive::Magic(0) => Receive::Magic(1),
+            Receive::Magic(_) => {
+                self.checksum = 0;
+                self.data.clear();
+                Receive::Command
+            },
+            Receive::Command => {
+                self.command = byte;
+                Receive::Compression
+            },
+            Receive::Compression => {
+                self.compressed = byte & 0x01 != 0;
+                Receive::LengthLow
+            },
+            Receive::LengthLow => {
+                self.length = byte as u16;
+                Receive::LengthHigh
+            },
+            Receive::LengthHigh => {
+                self.length |= (byte as u16) << 8;
+                if self.length == 0 {
+                    Receive::ChecksumLow
+                } else {
+                    Receive::Data
+                }
+            },
+            Receive::Data => {
+                self.data.push(byte);
+                if self.data.len() == self.length as usize {
+                    Receive::ChecksumLow
+                } else {
+                    Receive::Data
+                }
+            },
+            Receive::ChecksumLow => {
+                self.received_checksum = byte as u16;
+                Receive::ChecksumHigh
+            },
+            Receive::ChecksumHigh => {
+                self.received_checksum |= (byte as u16) << 8;
+                self.finish_packet();
+                Receive::Alive
+            },
+            Receive::Alive => Receive::Status,
+            Receive::Status => Receive::Magic(0),
+        };
+        reply
+    }
+
+    fn status(&self) -> u8 {
+        if self.busy_polls > 0 {
+            self.status | STATUS_BUSY
+        } else {
+            self.status
+        }
+    }
+
+    fn finish_packet(&mut self) {
+        if self.checksum != self.received_checksum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !(STATUS_CHECKSUM_ERROR | STATUS_PACKET_ERROR);
+        let data = std::mem::take(&mut self.data);
+        match self.command {
+            COMMAND_INIT => {
+                self.buffer.clear();
+                self.status = 0;
+                self.busy_polls = 0;
+            },
+            COMMAND_DATA if data.is_empty() => {
+                // an empty data packet marks the end of the image
+                self.status |= STATUS_FULL;
+            },
+            COMMAND_DATA => {
+                let data = if self.compressed { decompress(&data) } else { Some(data) };
+                match data {
+                    Some(data) => self.store(&data),
+                    None => self.status |= STATUS_PACKET_ERROR,
+                }
+            },
+            COMMAND_PRINT => match data[..] {
+                [sheets, margins, palette, _exposure] => self.print(sheets, margins, palette),
+                _ => self.status |= STATUS_PACKET_ERROR,
+            },
+            COMMAND_STATUS => {},
+            _ => self.status |= STATUS_PACKET_ERROR,
+        }
+    }
+
+    /// data that doesn't fit in the printer's memory is lost
+    fn store(&mut self, data: &[u8]) {
+        let room = BUFFER_SIZE - self.buffer.len();
+        self.buffer.extend_from_slice(&data[..data.len().min(room)]);
+        self.status |= STATUS_UNPROCESSED;
+        if self.buffer.len() == BUFFER_SIZE {
+            self.status |= STATUS_FULL;
+        }
+    }
+
+    /// prints the buffer sheets times. the upper nibble of margins is the
+    /// feed before the image and the lower nibble the feed after it. zero
+    /// sheets only feeds the paper
+    fn print(&mut self, sheets: u8, margins: u8, palette: u8) {
+        let palette = if palette == 0 { DEFAULT_PALETTE } else { palette };
+        let image = if sheets == 0 { Vec::new() } else { render(&self.buffer, palette) };
+
+        if !image.is_empty() {
+            let top = self.paper.len() + (margins >> 4) as usize * ROWS_PER_FEED;
+            self.paper.resize(top, [0; SCREEN_WIDTH]);
+            for _ in 0..sheets {
+                self.paper.extend_from_slice(&image);
+            }
+        }
+        self.feed((margins & 0x0F) as usize);
+
+        self.busy_polls = (image.len() * sheets as usize) as u32 * POLLS_PER_ROW;
+        self.buffer.clear();
+        self.status &= !(STATUS_UNPROCESSED | STATUS_FULL);
+        // feeding paper out after the image tears off the page
+        if margins & 0x0F != 0 {
+            self.tear_off();
+        }
+    }
+
+    fn feed(&mut self, feeds: usize) {
+        // blank paper with nothing printed on it isn't worth keeping
+        if !self.paper.is_empty() {
+            self.paper.resize(self.paper.len() + feeds * ROWS_PER_FEED, [0; SCREEN_WIDTH]);
+        }
+    }
+
+    fn tear_off(&mut self) {
+        if self.paper.is_empty() {
+            return;
+        }
+        let paper = std::mem::take(&mut self.paper);
+        let mut pages = self.pages.lock().unwrap();
+        let path = self.directory.join(format!("print-{:03}.pgm", pages.len() + 1));
+        pages.push(write_pgm(&path, &paper).map(|_| path));
+    }
+}
+
+impl SerialLink for Printer {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        // printing goes on while the game talks to the printer, a byte takes eight bit times
+        self.busy_polls = self.busy_polls.saturating_sub(8);
+        self.receive(outgoing)
+    }
+
+    /// the printer never drives the clock, being polled only moves printing along
+    fn poll(&mut self, _pending: Option<u8>) -> Option<u8> {
+        self.busy_polls = self.busy_polls.saturating_sub(1);
+        None
+    }
+}
+
+impl Drop for Printer {
+    fn drop(&mut self) {
+        self.tear_off();
+    }
+}
+
+/// undoes the run length encoding of compressed data packets. a control
+/// byte with bit 7 set repeats the next byte (control & 0x7F) + 2 times,
+/// otherwise control + 1 bytes follow as they are
+fn decompress(data: &[u8]) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(BAND_SIZE);
+    let mut bytes = data.iter();
+    while let Some(&control) = bytes.next() {
+        if control & 0x80 != 0 {
+            let byte = *bytes.next()?;
+            output.resize(output.len() + (control & 0x7F) as usize + 2, byte);
+        } else {
+            for _ in 0..=control {
+                output.push(*bytes.next()?);
+            }
+        }
+    }
+    Some(output)
+}
+
+/// turns the buffered tiles into rows of shades. tiles run left to right
+/// in rows of 20, an incomplete last row of tiles is dropped
+fn render(buffer: &[u8], palette: u8) -> Vec<[u8; SCREEN_WIDTH]> {
+    let mut rows = Vec::with_capacity(buffer.len() / BYTES_PER_TILE_ROW * 8);
+    for tiles in buffer.chunks_exact(BYTES_PER_TILE_ROW) {
+        for y in 0..8 {
+            let mut row = [0; SCREEN_WIDTH];
+            for (tile, pixels) in tiles.chunks_exact(16).zip(row.chunks_exact_mut(8)) {
+                let (low, high) = (tile[y * 2], tile[y * 2 + 1]);
+                for (x, pixel) in pixels.iter_mut().enumerate() {
+                    *pixel = shade(palette, tile_color(low, high, x));
+                }
+            }
+            rows.push(row);
+        }
+    }
+    rows
+}
+
+/// a binary graymap, about the simplest image format there is
+fn write_pgm(path: &Path, rows: &[[u8; SCREEN_WIDTH]]) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write!(writer, "P5\n{} {}\n255\n", SCREEN_WIDTH, rows.len())?;
+    for row in rows {
+        let grays: Vec<u8> = row.iter().map(|shade| GRAYS[*shade as usize]).collect();
+        writer.write_all(&grays)?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// sends a whole packet, returning the printer's answers to the two bytes after the checksum
+    fn send_packet(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> [u8; 2] {
+        let mut body = vec![command, compressed as u8];
+        body.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        body.extend_from_slice(data);
+        let checksum = body.iter().fold(0u16, |sum, byte| sum.wrapping_add(*byte as u16));
+
+        for byte in MAGIC.iter().chain(&body).chain(&checksum.to_le_bytes()) {
+            assert_eq!(printer.transfer(*byte), 0x00);
+        }
+        [printer.transfer(0x00), printer.transfer(0x00)]
+    }
+
+    #[test]
+    fn decompress_runs_and_literals() {
+        assert_eq!(decompress(&[]), Some(vec![]));
+        assert_eq!(decompress(&[0x81, 0xAA]), Some(vec![0xAA; 3]));
+        assert_eq!(decompress(&[0x02, 1, 2, 3, 0x80, 4]), Some(vec![1, 2, 3, 4, 4]));
+        // a run with nothing to repeat, and literals cut short
+        assert_eq!(decompress(&[0x80]), None);
+        assert_eq!(decompress(&[0x03, 1, 2]), None);
+    }
+
+    #[test]
+    fn prints_a_band_to_a_pgm_file() {
+        let directory = std::env::temp_dir().join(format!("gbr-printer-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let mut printer = Printer::new(&directory);
+        let pages = printer.pages();
+
+        assert_eq!(send_packet(&mut printer, COMMAND_INIT, false, &[]), [ALIVE, 0x00]);
+
+        // a row of tiles in color 3 over a row in color 0, as runs of at most 129 bytes
+        let mut band = Vec::new();
+        for byte in [0xFF, 0x00] {
+            band.extend_from_slice(&[0xFF, byte, 0xFF, byte, 0x80 | (62 - 2), byte]);
+        }
+        assert_eq!(decompress(&band).unwrap().len(), BAND_SIZE);
+        assert_eq!(send_packet(&mut printer, COMMAND_DATA, true, &band), [ALIVE, STATUS_UNPROCESSED]);
+        assert_eq!(
+            send_packet(&mut printer, COMMAND_DATA, false, &[]),
+            [ALIVE, STATUS_UNPROCESSED | STATUS_FULL]
+        );
+
+        // one sheet, no margin before and one feed after, which tears the page off
+        assert_eq!(send_packet(&mut printer, COMMAND_PRINT, false, &[1, 0x01, 0xE4, 0x40]), [ALIVE, STATUS_BUSY]);
+        let page = pages.lock().unwrap().pop().unwrap().unwrap();
+        let file = fs::read(&page).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        let header = b"P5\n160 32\n255\n";
+        assert_eq!(&file[..header.len()], header);
+        let rows: Vec<&[u8]> = file[header.len()..].chunks(SCREEN_WIDTH).collect();
+        assert_eq!(rows.len(), 16 + ROWS_PER_FEED);
+        assert!(rows[..8].iter().all(|row| row.iter().all(|gray| *gray == 0x00)));
+        assert!(rows[8..].iter().all(|row| row.iter().all(|gray| *gray == 0xFF)));
+    }
+
+    #[test]
+    fn bad_checksum_is_reported() {
+        let mut printer = Printer::new(std::env::temp_dir());
+        for byte in [0x88, 0x33, COMMAND_STATUS, 0x00, 0x00, 0x00, 0x12, 0x34] {
+            printer.transfer(byte);
+        }
+        assert_eq!([printer.transfer(0x00), printer.transfer(0x00)], [ALIVE, STATUS_CHECKSUM_ERROR]);
+    }
+}
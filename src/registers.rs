@@ -1,3 +1,5 @@
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
 pub struct Registers {
    pub a: u8,
    pub b: u8,
@@ -36,7 +38,72 @@ pub struct Flags {
    pub c: bool
 }
 
+impl Default for Registers {
+    fn default() -> Self {
+        Registers::new()
+    }
+}
+
+impl SaveState for Registers {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.u16(self.get_af());
+        writer.u16(self.get_bc());
+        writer.u16(self.get_de());
+        writer.u16(self.get_hl());
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.set_af(reader.u16()?);
+        self.set_bc(reader.u16()?);
+        self.set_de(reader.u16()?);
+        self.set_hl(reader.u16()?);
+        Ok(())
+    }
+}
+
+impl Flags {
+    /// packs the flags into the upper nibble of a byte, as stored in F
+    pub fn as_byte(&self) -> u8 {
+        (self.z as u8) << 7
+            | (self.n as u8) << 6
+            | (self.h as u8) << 5
+            | (self.c as u8) << 4
+    }
+    pub fn set_from_byte(&mut self, value: u8) {
+        self.z = value & 0x80 != 0;
+        self.n = value & 0x40 != 0;
+        self.h = value & 0x20 != 0;
+        self.c = value & 0x10 != 0;
+    }
+}
+
 impl Registers {
+    /// register values left behind by the DMG boot ROM
+    pub fn new() -> Registers {
+        Registers {
+            a: 0x01,
+            b: 0x00,
+            c: 0x13,
+            d: 0x00,
+            e: 0xD8,
+            f: 0xB0,
+            h: 0x01,
+            l: 0x4D,
+            flags: Flags { z: true, n: false, h: true, c: true },
+        }
+    }
+
+    /// AF is made up of A and the flags, the lower nibble of F always reads as 0
+    pub fn get_af(&self) -> u16 {
+        (self.a as u16) << 8
+            | self.flags.as_byte() as u16
+    }
+    pub fn set_af(&mut self, value: u16) {
+        self.a = ((value & 0xFF00) >> 8) as u8;
+        self.f = (value & 0xF0) as u8;
+        self.flags.set_from_byte(self.f);
+    }
+
     pub fn get_bc(&self) -> u16 {
         (self.b as u16) << 8
             | self.c as u16
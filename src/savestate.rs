@@ -0,0 +1,489 @@
+//! Save states for the whole machine.
+//!
+//! A state is a header followed by a list of chunks, all integers little endian:
+//!
+//! | offset | size | contents                                      |
+//! |--------|------|-----------------------------------------------|
+//! | 0      | 4    | magic, `GBRS`                                 |
+//! | 4      | 2    | format version, [FORMAT_VERSION]              |
+//! | 6      | 1    | header checksum of the ROM (0x014D)           |
+//! | 7      | 2    | global checksum of the ROM (0x014E-0x014F)    |
+//! | 9      | 16   | ROM title, zero padded                        |
+//! | 25     | 2    | number of chunks                              |
+//!
+//! Each chunk is a 4 byte tag, a u32 payload length and the payload. Every
+//! component of the machine owns one chunk and is responsible for its layout.
+//! The CPU, MMU and cartridge chunks must always be present. A chunk added
+//! later that is missing from a state leaves that component in its power on
+//! state, so new components can be added without breaking older states. Changing the
+//! layout of an existing chunk bumps [FORMAT_VERSION], and older versions are
+//! either migrated in [migrate] or refused.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::apu::APU;
+use crate::audio::NullSink;
+use crate::cpu::CPU;
+use crate::dma::Dma;
+use crate::joypad::Joypad;
+use crate::mmu::MMU;
+use crate::ppu::PPU;
+use crate::serial::{Disconnected, Serial};
+use crate::timer::Timer;
+
+const MAGIC: &[u8; 4] = b"GBRS";
+pub const FORMAT_VERSION: u16 = 1;
+const TITLE_SIZE: usize = 16;
+
+pub const CPU_CHUNK: [u8; 4] = *b"CPU ";
+pub const MMU_CHUNK: [u8; 4] = *b"MMU ";
+pub const CARTRIDGE_CHUNK: [u8; 4] = *b"CART";
+pub const PPU_CHUNK: [u8; 4] = *b"PPU ";
+pub const DMA_CHUNK: [u8; 4] = *b"DMA ";
+pub const TIMER_CHUNK: [u8; 4] = *b"TIMR";
+pub const APU_CHUNK: [u8; 4] = *b"APU ";
+pub const JOYPAD_CHUNK: [u8; 4] = *b"JOYP";
+pub const SERIAL_CHUNK: [u8; 4] = *b"SERL";
+
+/// chunks every state has had since the first version
+const REQUIRED_CHUNKS: [[u8; 4]; 3] = [CPU_CHUNK, MMU_CHUNK, CARTRIDGE_CHUNK];
+
+/// Reasons a state can't be loaded. The machine is left untouched
+#[derive(Debug, PartialEq, Eq)]
+pub enum StateError {
+    NotASaveState,
+    /// made by a newer build, or an older format with no migration
+    UnsupportedVersion { found: u16, supported: u16 },
+    /// made while running a different ROM
+    RomMismatch { expected: String, found: String },
+    /// the data ended in the middle of a field
+    Truncated,
+    UnknownChunk([u8; 4]),
+    MissingChunk([u8; 4]),
+    /// a field holds a value the component can't be in
+    InvalidValue(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::NotASaveState => write!(f, "not a save state"),
+            StateError::UnsupportedVersion { found, supported } => write!(
+                f,
+                "save state format version {} can't be loaded, this build supports version {}",
+                found, supported
+            ),
+            StateError::RomMismatch { expected, found } => write!(
+                f,
+                "save state was made for \"{}\" but \"{}\" is loaded",
+                found, expected
+            ),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::UnknownChunk(tag) => write!(f, "unknown save state chunk {:?}", String::from_utf8_lossy(tag)),
+            StateError::MissingChunk(tag) => write!(f, "save state has no {:?} chunk", String::from_utf8_lossy(tag)),
+            StateError::InvalidValue(field) => write!(f, "save state has an invalid {}", field),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// Implemented by each component that contributes a chunk
+pub trait SaveState {
+    fn save_state(&self, writer: &mut StateWriter);
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError>;
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// fixed size data, the reader must know the length
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// variable sized data, prefixed with its length
+    pub fn sized_bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.bytes(bytes);
+    }
+
+    pub fn chunk(&mut self, tag: [u8; 4], component: &dyn SaveState) {
+        let mut payload = StateWriter::new();
+        component.save_state(&mut payload);
+        self.bytes(&tag);
+        self.sized_bytes(&payload.data);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data, position: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.position >= self.data.len()
+    }
+
+    pub fn bytes(&mut self, length: usize) -> Result<&'a [u8], StateError> {
+        let end = self.position.checked_add(length).ok_or(StateError::Truncated)?;
+        let bytes = self.data.get(self.position..end).ok_or(StateError::Truncated)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    /// fills a fixed size buffer
+    pub fn bytes_into(&mut self, buffer: &mut [u8]) -> Result<(), StateError> {
+        buffer.copy_from_slice(self.bytes(buffer.len())?);
+        Ok(())
+    }
+
+    pub fn sized_bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let length = self.u32()? as usize;
+        self.bytes(length)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::InvalidValue("flag")),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        let mut bytes = [0; 2];
+        self.bytes_into(&mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        let mut bytes = [0; 4];
+        self.bytes_into(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes = [0; 8];
+        self.bytes_into(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+/// identifies the ROM a state belongs to
+fn rom_identity(mmu: &MMU) -> (u8, u16, [u8; TITLE_SIZE]) {
+    let header = &mmu.cartridge.header;
+    let mut title = [0; TITLE_SIZE];
+    for (slot, byte) in title.iter_mut().zip(header.title.bytes()) {
+        *slot = byte;
+    }
+    (header.header_checksum, header.global_checksum, title)
+}
+
+/// brings the chunks of an older format up to date. there are no
+/// older formats yet, so anything other than the current one is refused
+fn migrate(version: u16, chunks: HashMap<[u8; 4], &[u8]>) -> Result<HashMap<[u8; 4], &[u8]>, StateError> {
+    if version != FORMAT_VERSION {
+        return Err(StateError::UnsupportedVersion {
+            found: version,
+            supported: FORMAT_VERSION,
+        });
+    }
+    Ok(chunks)
+}
+
+impl CPU<MMU> {
+    /// snapshots the whole machine
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        let (header_checksum, global_checksum, title) = rom_identity(&self.bus);
+        writer.bytes(MAGIC);
+        writer.u16(FORMAT_VERSION);
+        writer.u8(header_checksum);
+        writer.u16(global_checksum);
+        writer.bytes(&title);
+
+        let chunks: [([u8; 4], &dyn SaveState); 9] = [
+            (CPU_CHUNK, self),
+            (MMU_CHUNK, &self.bus),
+            (CARTRIDGE_CHUNK, &self.bus.cartridge),
+            (PPU_CHUNK, &self.bus.ppu),
+            (DMA_CHUNK, &self.bus.dma),
+            (TIMER_CHUNK, &self.bus.timer),
+            (APU_CHUNK, &self.bus.apu),
+            (JOYPAD_CHUNK, &self.bus.joypad),
+            (SERIAL_CHUNK, &self.bus.serial),
+        ];
+        writer.u16(chunks.len() as u16);
+        for (tag, component) in chunks {
+            writer.chunk(tag, component);
+        }
+        writer.into_bytes()
+    }
+
+    /// restores a snapshot made by [snapshot](CPU::snapshot). states for
+    /// another ROM or an unsupported version are refused, and if anything
+    /// goes wrong part way through the machine is put back as it was
+    pub fn restore(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut reader = StateReader::new(data);
+        if reader.bytes(MAGIC.len()).map_err(|_| StateError::NotASaveState)? != MAGIC {
+            return Err(StateError::NotASaveState);
+        }
+        let version = reader.u16()?;
+        let header_checksum = reader.u8()?;
+        let global_checksum = reader.u16()?;
+        let mut title = [0; TITLE_SIZE];
+        reader.bytes_into(&mut title)?;
+
+        if (header_checksum, global_checksum, title) != rom_identity(&self.bus) {
+            let found = title.iter().take_while(|byte| **byte != 0).map(|byte| *byte as char).collect();
+            return Err(StateError::RomMismatch {
+                expected: self.bus.cartridge.header.title.clone(),
+                found,
+            });
+        }
+
+        let chunk_count = reader.u16()?;
+        let mut chunks = HashMap::new();
+        for _ in 0..chunk_count {
+            let mut tag = [0; 4];
+            reader.bytes_into(&mut tag)?;
+            chunks.insert(tag, reader.sized_bytes()?);
+        }
+        let chunks = migrate(version, chunks)?;
+        if let Some(tag) = REQUIRED_CHUNKS.iter().find(|tag| !chunks.contains_key(*tag)) {
+            return Err(StateError::MissingChunk(*tag));
+        }
+
+        let backup = self.snapshot();
+        if let Err(err) = self.apply_chunks(&chunks) {
+            self.apply_chunks(&Self::chunks_of(&backup))
+                .expect("restoring a state this build just made cannot fail");
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    fn apply_chunks(&mut self, chunks: &HashMap<[u8; 4], &[u8]>) -> Result<(), StateError> {
+        self.reset_missing(chunks);
+        for (tag, payload) in chunks {
+            let mut reader = StateReader::new(payload);
+            match *tag {
+                CPU_CHUNK => SaveState::load_state(self, &mut reader)?,
+                MMU_CHUNK => self.bus.load_state(&mut reader)?,
+                CARTRIDGE_CHUNK => self.bus.cartridge.load_state(&mut reader)?,
+                PPU_CHUNK => self.bus.ppu.load_state(&mut reader)?,
+                DMA_CHUNK => self.bus.dma.load_state(&mut reader)?,
+                TIMER_CHUNK => self.bus.timer.load_state(&mut reader)?,
+                APU_CHUNK => self.bus.apu.load_state(&mut reader)?,
+                JOYPAD_CHUNK => self.bus.joypad.load_state(&mut reader)?,
+                SERIAL_CHUNK => self.bus.serial.load_state(&mut reader)?,
+                unknown => return Err(StateError::UnknownChunk(unknown)),
+            }
+            if !reader.is_empty() {
+                return Err(StateError::InvalidValue("chunk length"));
+            }
+        }
+        Ok(())
+    }
+
+    /// puts components that have no chunk in the state, because it was made
+    /// before they existed, back in their power on state. frontend settings
+    /// like the PPU's renderer are kept
+    fn reset_missing(&mut self, chunks: &HashMap<[u8; 4], &[u8]>) {
+        if !chunks.contains_key(&PPU_CHUNK) {
+            let mut ppu = PPU::new();
+            ppu.renderer = self.bus.ppu.renderer;
+            ppu.unlimited_sprites = self.bus.ppu.unlimited_sprites;
+            self.bus.ppu = ppu;
+        }
+        if !chunks.contains_key(&DMA_CHUNK) {
+            self.bus.dma = Dma::new();
+        }
+        if !chunks.contains_key(&TIMER_CHUNK) {
+            self.bus.timer = Timer::new();
+        }
+        if !chunks.contains_key(&APU_CHUNK) {
+            let mut apu = APU::new();
+            apu.set_sample_rate(self.bus.apu.sample_rate());
+            apu.set_sink(self.bus.apu.set_sink(Box::new(NullSink)));
+            self.bus.apu = apu;
+        }
+        if !chunks.contains_key(&JOYPAD_CHUNK) {
+            let buttons = self.bus.joypad.buttons();
+            self.bus.joypad = Joypad::new();
+            self.bus.joypad.set_buttons(buttons);
+        }
+        if !chunks.contains_key(&SERIAL_CHUNK) {
+            let mut serial = Serial::new();
+            serial.set_link(self.bus.serial.set_link(Box::new(Disconnected)));
+            self.bus.serial = serial;
+        }
+    }
+
+    /// splits a state this build wrote back into its chunks
+    fn chunks_of(data: &[u8]) -> HashMap<[u8; 4], &[u8]> {
+        let mut reader = StateReader::new(data);
+        let mut chunks = HashMap::new();
+        let header_size = MAGIC.len() + 2 + 1 + 2 + TITLE_SIZE;
+        if reader.bytes(header_size).is_err() {
+            return chunks;
+        }
+        let chunk_count = reader.u16().unwrap_or(0);
+        for _ in 0..chunk_count {
+            let mut tag = [0; 4];
+            if reader.bytes_into(&mut tag).is_err() {
+                break;
+            }
+            match reader.sized_bytes() {
+                Ok(payload) => chunks.insert(tag, payload),
+                Err(_) => break,
+            };
+        }
+        chunks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::{header_checksum, test_rom, Cartridge};
+
+    /// a ROM-only machine with title at 0x0134 and a loop that keeps A counting
+    fn machine(title: &[u8]) -> CPU<MMU> {
+        let mut rom = test_rom(0x00, 0x00, 0x00);
+        rom[0x0134..0x0134 + title.len()].copy_from_slice(title);
+        // INC A; LD (0xC000),A; JR -6
+        rom[0x0100..0x0106].copy_from_slice(&[0x3C, 0xEA, 0x00, 0xC0, 0x18, 0xFA]);
+        rom[0x014D] = header_checksum(&rom);
+        CPU::new(MMU::new(Cartridge::from_bytes(rom).unwrap()))
+    }
+
+    /// rebuilds a state with the same header as state but the chunks given
+    fn with_chunks(state: &[u8], chunks: &[([u8; 4], &[u8])]) -> Vec<u8> {
+        let header_size = MAGIC.len() + 2 + 1 + 2 + TITLE_SIZE;
+        let mut writer = StateWriter::new();
+        writer.bytes(&state[..header_size]);
+        writer.u16(chunks.len() as u16);
+        for (tag, payload) in chunks {
+            writer.bytes(tag);
+            writer.sized_bytes(payload);
+        }
+        writer.into_bytes()
+    }
+
+    #[test]
+    fn restored_state_snapshots_identically() {
+        let mut cpu = machine(b"ROUND TRIP");
+        cpu.run_for_cycles(1000);
+        let state = cpu.snapshot();
+
+        cpu.run_for_cycles(1000);
+        assert_ne!(cpu.snapshot(), state);
+        cpu.restore(&state).unwrap();
+        assert_eq!(cpu.snapshot(), state);
+    }
+
+    #[test]
+    fn state_from_another_rom_is_refused() {
+        let state = machine(b"FIRST GAME").snapshot();
+        let mut cpu = machine(b"SECOND GAME");
+        let before = cpu.snapshot();
+        assert_eq!(
+            cpu.restore(&state),
+            Err(StateError::RomMismatch {
+                expected: "SECOND GAME".to_string(),
+                found: "FIRST GAME".to_string(),
+            })
+        );
+        assert_eq!(cpu.snapshot(), before);
+    }
+
+    #[test]
+    fn unknown_versions_are_refused() {
+        let mut cpu = machine(b"VERSIONS");
+        for version in [0, FORMAT_VERSION + 1] {
+            let mut state = cpu.snapshot();
+            state[4..6].copy_from_slice(&version.to_le_bytes());
+            assert_eq!(
+                cpu.restore(&state),
+                Err(StateError::UnsupportedVersion { found: version, supported: FORMAT_VERSION })
+            );
+        }
+    }
+
+    #[test]
+    fn anything_else_is_not_a_save_state() {
+        let mut cpu = machine(b"MAGIC");
+        assert_eq!(cpu.restore(b"GB"), Err(StateError::NotASaveState));
+        assert_eq!(cpu.restore(b"RIFF0000"), Err(StateError::NotASaveState));
+    }
+
+    #[test]
+    fn truncated_chunk_rolls_the_machine_back() {
+        let mut cpu = machine(b"ROLLBACK");
+        let state = cpu.snapshot();
+        let chunks = CPU::<MMU>::chunks_of(&state);
+        cpu.run_for_cycles(1000);
+        let before = cpu.snapshot();
+
+        let mmu = chunks[&MMU_CHUNK];
+        let broken = with_chunks(&state, &[
+            (CPU_CHUNK, chunks[&CPU_CHUNK]),
+            (CARTRIDGE_CHUNK, chunks[&CARTRIDGE_CHUNK]),
+            (MMU_CHUNK, &mmu[..mmu.len() - 1]),
+        ]);
+        assert_eq!(cpu.restore(&broken), Err(StateError::Truncated));
+        assert_eq!(cpu.snapshot(), before);
+    }
+
+    #[test]
+    fn state_without_a_required_chunk_is_refused() {
+        let mut cpu = machine(b"REQUIRED");
+        let state = cpu.snapshot();
+        let chunks = CPU::<MMU>::chunks_of(&state);
+        let partial = with_chunks(&state, &[(CPU_CHUNK, chunks[&CPU_CHUNK]), (MMU_CHUNK, chunks[&MMU_CHUNK])]);
+        assert_eq!(cpu.restore(&partial), Err(StateError::MissingChunk(CARTRIDGE_CHUNK)));
+    }
+}
@@ -0,0 +1,440 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::interrupts::Interrupt;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+pub const SB: u16 = 0xFF01;
+pub const SC: u16 = 0xFF02;
+
+const SC_TRANSFER: u8 = 0x80;
+const SC_INTERNAL_CLOCK: u8 = 0x01;
+
+/// the internal clock runs at 8192Hz, 128 machine cycles a bit
+const CYCLES_PER_BIT: u32 = 128;
+const CYCLES_PER_BYTE: u32 = CYCLES_PER_BIT * 8;
+
+/// what an unconnected port shifts in, the line idles high
+const DISCONNECTED: u8 = 0xFF;
+
+/// The other end of the link cable. Transfers are exchanged a byte at a time,
+/// both sides shift out their byte while shifting in the other's
+pub trait SerialLink {
+    /// this side drives the clock and has shifted out outgoing. returns
+    /// what was shifted in, 0xFF if nobody on the other end took part
+    fn transfer(&mut self, outgoing: u8) -> u8;
+
+    /// called regularly while running. pending is the byte this side would
+    /// shift out if the other side drove the clock now, or None if it isn't
+    /// waiting on an external clock. returns the byte shifted in if the other
+    /// side did drive the clock, which completes this side's transfer
+    fn poll(&mut self, pending: Option<u8>) -> Option<u8>;
+}
+
+/// No cable plugged in
+#[derive(Default)]
+pub struct Disconnected;
+
+impl SerialLink for Disconnected {
+    fn transfer(&mut self, _outgoing: u8) -> u8 {
+        DISCONNECTED
+    }
+
+    fn poll(&mut self, _pending: Option<u8>) -> Option<u8> {
+        None
+    }
+}
+
+/// Collects every byte this side sends, which is how test ROMs report
+/// their results. Optionally echoes them to stdout as they arrive
+#[derive(Default)]
+pub struct CaptureLink {
+    bytes: Arc<Mutex<Vec<u8>>>,
+    echo: bool,
+}
+
+impl CaptureLink {
+    pub fn new() -> CaptureLink {
+        CaptureLink::default()
+    }
+
+    pub fn stdout() -> CaptureLink {
+        CaptureLink {
+            bytes: Arc::default(),
+            echo: true,
+        }
+    }
+
+    /// a handle to the captured bytes that stays usable once the link is plugged in
+    pub fn bytes(&self) -> Arc<Mutex<Vec<u8>>> {
+        Arc::clone(&self.bytes)
+    }
+}
+
+impl SerialLink for CaptureLink {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        self.bytes.lock().unwrap().push(outgoing);
+        if self.echo {
+            let mut stdout = io::stdout();
+            let _ = stdout.write_all(&[outgoing]).and_then(|_| stdout.flush());
+        }
+        DISCONNECTED
+    }
+
+    fn poll(&mut self, _pending: Option<u8>) -> Option<u8> {
+        None
+    }
+}
+
+#[derive(Default)]
+struct LoopbackEnd {
+    /// the byte this end has ready while waiting on the other's clock
+    waiting: Option<u8>,
+    /// a byte the other end clocked in, completing this end's transfer
+    received: Option<u8>,
+}
+
+/// One end of a cable between two emulators in the same process
+pub struct LoopbackLink {
+    ends: Arc<Mutex<[LoopbackEnd; 2]>>,
+    side: usize,
+}
+
+impl LoopbackLink {
+    /// both ends of a new cable, one for each emulator
+    pub fn pair() -> (LoopbackLink, LoopbackLink) {
+        let ends = Arc::new(Mutex::new([LoopbackEnd::default(), LoopbackEnd::default()]));
+        let first = LoopbackLink {
+            ends: Arc::clone(&ends),
+            side: 0,
+        };
+        (first, LoopbackLink { ends, side: 1 })
+    }
+}
+
+impl SerialLink for LoopbackLink {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        let mut ends = self.ends.lock().unwrap();
+        let other = &mut ends[1 - self.side];
+        match other.waiting.take() {
+            Some(incoming) => {
+                other.received = Some(outgoing);
+                incoming
+            },
+            None => DISCONNECTED,
+        }
+    }
+
+    fn poll(&mut self, pending: Option<u8>) -> Option<u8> {
+        let mut ends = self.ends.lock().unwrap();
+        let end = &mut ends[self.side];
+        if let Some(received) = end.received.take() {
+            return Some(received);
+        }
+        end.waiting = pending;
+        None
+    }
+}
+
+/// message types on the TCP link, each followed by one data byte
+const TCP_TRANSFER: u8 = 0x01;
+const TCP_REPLY: u8 = 0x02;
+/// how long a transfer waits for the other emulator to answer. emulation
+/// stalls for that long, so it only allows for a fast local network
+const TCP_TIMEOUT: Duration = Duration::from_millis(20);
+
+/// A cable between two emulator processes over TCP. A transfer sends the
+/// outgoing byte and blocks until the other side answers with its own, or
+/// reads 0xFF after [TCP_TIMEOUT]. If the connection drops the link behaves
+/// as if the cable was pulled
+pub struct TcpLink {
+    stream: Option<TcpStream>,
+    /// bytes of a message that has only partly arrived
+    partial: Vec<u8>,
+}
+
+impl TcpLink {
+    /// waits for the other emulator to connect
+    pub fn listen(address: impl ToSocketAddrs) -> io::Result<TcpLink> {
+        let (stream, _) = TcpListener::bind(address)?.accept()?;
+        TcpLink::from_stream(stream)
+    }
+
+    pub fn connect(address: impl ToSocketAddrs) -> io::Result<TcpLink> {
+        TcpLink::from_stream(TcpStream::connect(address)?)
+    }
+
+    fn from_stream(stream: TcpStream) -> io::Result<TcpLink> {
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+        Ok(TcpLink {
+            stream: Some(stream),
+            partial: Vec::with_capacity(2),
+        })
+    }
+
+    pub fn connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    fn send(&mut self, kind: u8, byte: u8) -> io::Result<()> {
+        let stream = self.stream.as_mut().ok_or(ErrorKind::NotConnected)?;
+        stream.set_nonblocking(false)?;
+        stream.write_all(&[kind, byte])?;
+        stream.set_nonblocking(true)
+    }
+
+    /// reads whatever has arrived, returning a message once all of it is there
+    fn receive(&mut self) -> io::Result<Option<(u8, u8)>> {
+        let stream = self.stream.as_mut().ok_or(ErrorKind::NotConnected)?;
+        while self.partial.len() < 2 {
+            let mut byte = [0];
+            match stream.read(&mut byte) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(_) => self.partial.push(byte[0]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(err) => return Err(err),
+            }
+        }
+        let message = (self.partial[0], self.partial[1]);
+        self.partial.clear();
+        Ok(Some(message))
+    }
+
+    fn exchange(&mut self, outgoing: u8) -> io::Result<u8> {
+        self.send(TCP_TRANSFER, outgoing)?;
+        let started = std::time::Instant::now();
+        loop {
+            match self.receive()? {
+                Some((TCP_REPLY, incoming)) => return Ok(incoming),
+                // both sides started a transfer at once, neither is listening to the other's clock
+                Some((_, _)) => self.send(TCP_REPLY, DISCONNECTED)?,
+                None if started.elapsed() > TCP_TIMEOUT => return Ok(DISCONNECTED),
+                None => std::thread::sleep(Duration::from_micros(50)),
+            }
+        }
+    }
+
+    fn answer(&mut self, pending: Option<u8>) -> io::Result<Option<u8>> {
+        match self.receive()? {
+            Some((TCP_TRANSFER, incoming)) => {
+                self.send(TCP_REPLY, pending.unwrap_or(DISCONNECTED))?;
+                Ok(pending.map(|_| incoming))
+            },
+            // a late reply to a transfer that already timed out
+            _ => Ok(None),
+        }
+    }
+}
+
+impl SerialLink for TcpLink {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        self.exchange(outgoing).unwrap_or_else(|_| {
+            self.stream = None;
+            DISCONNECTED
+        })
+    }
+
+    fn poll(&mut self, pending: Option<u8>) -> Option<u8> {
+        self.stream.as_ref()?;
+        self.answer(pending).unwrap_or_else(|_| {
+            self.stream = None;
+            None
+        })
+    }
+}
+
+/// The serial port, SB holds the byte being shifted and SC starts a transfer
+/// and picks who drives the clock
+pub struct Serial {
+    data: u8,
+    control: u8,
+    /// cycles into a transfer on the internal clock
+    cycles: u32,
+    /// cycles since the link was last polled
+    poll_cycles: u32,
+    link: Box<dyn SerialLink>,
+    interrupts: u8,
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            data: 0,
+            control: 0,
+            cycles: 0,
+            poll_cycles: 0,
+            link: Box::new(Disconnected),
+            interrupts: 0,
+        }
+    }
+
+    /// plugs in a cable, returning the old one
+    pub fn set_link(&mut self, link: Box<dyn SerialLink>) -> Box<dyn SerialLink> {
+        std::mem::replace(&mut self.link, link)
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            SB => self.data,
+            // the unused bits of SC read as 1
+            SC => 0x7E | self.control,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            SB => self.data = value,
+            SC => {
+                self.control = value & (SC_TRANSFER | SC_INTERNAL_CLOCK);
+                self.cycles = 0;
+            },
+            _ => {},
+        }
+    }
+
+    fn transferring(&self) -> bool {
+        self.control & SC_TRANSFER != 0
+    }
+
+    fn internal_clock(&self) -> bool {
+        self.control & SC_INTERNAL_CLOCK != 0
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        if self.transferring() && self.internal_clock() {
+            self.cycles += cycles;
+            if self.cycles >= CYCLES_PER_BYTE {
+                let incoming = self.link.transfer(self.data);
+                self.complete(incoming);
+            }
+            return;
+        }
+
+        // the other side can only clock a bit in every so often, no need to look more often
+        self.poll_cycles += cycles;
+        if self.poll_cycles < CYCLES_PER_BIT {
+            return;
+        }
+        self.poll_cycles = 0;
+        let pending = self.transferring().then_some(self.data);
+        if let Some(incoming) = self.link.poll(pending) {
+            self.complete(incoming);
+        }
+    }
+
+    fn complete(&mut self, incoming: u8) {
+        self.data = incoming;
+        self.control &= !SC_TRANSFER;
+        self.cycles = 0;
+        self.interrupts |= Interrupt::Serial.mask();
+    }
+
+    /// hands over the interrupts requested since the last call, as IF bits
+    pub fn take_interrupts(&mut self) -> u8 {
+        std::mem::take(&mut self.interrupts)
+    }
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Serial::new()
+    }
+}
+
+/// the link is plugged in by the frontend and isn't saved
+impl SaveState for Serial {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.data);
+        writer.u8(self.control);
+        writer.u32(self.cycles);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.data = reader.u8()?;
+        self.control = reader.u8()? & (SC_TRANSFER | SC_INTERNAL_CLOCK);
+        self.cycles = reader.u32()?.min(CYCLES_PER_BYTE);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::{test_rom, Cartridge};
+    use crate::interrupts::INTERRUPT_FLAG;
+    use crate::mmu::MMU;
+
+    #[test]
+    fn internal_clock_transfer_takes_1024_cycles() {
+        let mut mmu = MMU::new(Cartridge::from_bytes(test_rom(0x00, 0x00, 0x00)).unwrap());
+        mmu.write8(INTERRUPT_FLAG, 0);
+        mmu.write8(SB, 0x42);
+        mmu.write8(SC, SC_TRANSFER | SC_INTERNAL_CLOCK);
+        mmu.tick(CYCLES_PER_BYTE - 1);
+        assert_eq!(mmu.read8(SC) & SC_TRANSFER, SC_TRANSFER);
+        assert_eq!(mmu.read8(INTERRUPT_FLAG) & 0x08, 0);
+
+        mmu.tick(1);
+        assert_eq!(mmu.read8(SC) & SC_TRANSFER, 0);
+        assert_eq!(mmu.read8(INTERRUPT_FLAG) & 0x08, 0x08);
+        // nothing plugged in, so only ones were shifted in
+        assert_eq!(mmu.read8(SB), 0xFF);
+    }
+
+    #[test]
+    fn disconnected_reads_0xff_and_never_clocks() {
+        assert_eq!(Disconnected.transfer(0x12), 0xFF);
+        assert_eq!(Disconnected.poll(Some(0x12)), None);
+
+        // waiting on an external clock that never comes
+        let mut serial = Serial::new();
+        serial.write(SB, 0x12);
+        serial.write(SC, SC_TRANSFER);
+        serial.tick(10 * CYCLES_PER_BYTE);
+        assert_eq!(serial.read(SC) & SC_TRANSFER, SC_TRANSFER);
+        assert_eq!(serial.take_interrupts(), 0);
+    }
+
+    #[test]
+    fn capture_link_records_sent_bytes() {
+        let link = CaptureLink::new();
+        let bytes = link.bytes();
+        let mut serial = Serial::new();
+        serial.set_link(Box::new(link));
+        for byte in *b"OK" {
+            serial.write(SB, byte);
+            serial.write(SC, SC_TRANSFER | SC_INTERNAL_CLOCK);
+            serial.tick(CYCLES_PER_BYTE);
+            assert_eq!(serial.read(SB), 0xFF);
+        }
+        assert_eq!(*bytes.lock().unwrap(), b"OK");
+    }
+
+    #[test]
+    fn loopback_swaps_bytes_between_the_clock_sides() {
+        let (first, second) = LoopbackLink::pair();
+        let mut master = Serial::new();
+        master.set_link(Box::new(first));
+        let mut slave = Serial::new();
+        slave.set_link(Box::new(second));
+
+        slave.write(SB, 0x42);
+        slave.write(SC, SC_TRANSFER);
+        slave.tick(CYCLES_PER_BIT);
+        master.write(SB, 0x99);
+        master.write(SC, SC_TRANSFER | SC_INTERNAL_CLOCK);
+        master.tick(CYCLES_PER_BYTE);
+        assert_eq!(master.read(SB), 0x42);
+        assert_eq!(master.take_interrupts(), Interrupt::Serial.mask());
+
+        slave.tick(CYCLES_PER_BIT);
+        assert_eq!(slave.read(SB), 0x99);
+        assert_eq!(slave.read(SC) & SC_TRANSFER, 0);
+        assert_eq!(slave.take_interrupts(), Interrupt::Serial.mask());
+    }
+}
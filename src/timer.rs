@@ -0,0 +1,245 @@
+use crate::interrupts::Interrupt;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+pub const DIV: u16 = 0xFF04;
+pub const TIMA: u16 = 0xFF05;
+pub const TMA: u16 = 0xFF06;
+pub const TAC: u16 = 0xFF07;
+
+const TAC_ENABLE: u8 = 0x04;
+const TAC_CLOCK_SELECT: u8 = 0x03;
+
+/// the system counter counts clocks, four to every machine cycle
+const CLOCKS_PER_CYCLE: u16 = 4;
+
+/// The timer. Everything is driven by a 16-bit counter that ticks every clock,
+/// DIV is its upper byte and TIMA counts falling edges of one of its bits. As
+/// that bit is ANDed with the enable bit before the edge detector, resetting
+/// DIV or changing TAC can produce an edge and bump TIMA early.
+///
+/// the quirks are exact at the timer's own cycle level, but the MMU ticks
+/// it once per instruction, after the instruction's memory accesses, so
+/// the CPU only hits the one cycle overflow and reload windows roughly
+pub struct Timer {
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    /// TIMA overflowed in the last cycle and reads 0. it is reloaded
+    /// from TMA, and the interrupt raised, one cycle later
+    overflowed: bool,
+    /// TIMA was reloaded in the last cycle. writes to TIMA are lost
+    /// and writes to TMA go through to TIMA as well
+    reloaded: bool,
+    interrupts: u8,
+}
+
+impl Timer {
+    /// creates a timer in the state the DMG boot ROM leaves it in
+    pub fn new() -> Timer {
+        Timer {
+            counter: 0xABCC,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            overflowed: false,
+            reloaded: false,
+            interrupts: 0,
+        }
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            DIV => (self.counter >> 8) as u8,
+            TIMA => self.tima,
+            TMA => self.tma,
+            // the upper bits of TAC are unused and read as 1
+            TAC => 0xF8 | self.tac,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            DIV => self.set_counter(0),
+            // writing during the delay cancels the reload, writing
+            // right after the reload is lost
+            TIMA if !self.reloaded => {
+                self.tima = value;
+                self.overflowed = false;
+            },
+            TMA => {
+                self.tma = value;
+                if self.reloaded {
+                    self.tima = value;
+                }
+            },
+            TAC => {
+                let signal = self.signal();
+                self.tac = value & (TAC_ENABLE | TAC_CLOCK_SELECT);
+                self.check_edge(signal);
+            },
+            _ => {},
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        for _ in 0..cycles {
+            self.reloaded = false;
+            if self.overflowed {
+                self.overflowed = false;
+                self.reloaded = true;
+                self.tima = self.tma;
+                self.interrupts |= Interrupt::Timer.mask();
+            }
+            self.set_counter(self.counter.wrapping_add(CLOCKS_PER_CYCLE));
+        }
+    }
+
+    /// hands over the interrupts requested since the last call, as IF bits
+    pub fn take_interrupts(&mut self) -> u8 {
+        std::mem::take(&mut self.interrupts)
+    }
+
+    /// the bit of the system counter TIMA counts, which is
+    /// 4096Hz, 262144Hz, 65536Hz or 16384Hz
+    fn counter_bit(&self) -> u16 {
+        match self.tac & TAC_CLOCK_SELECT {
+            0 => 1 << 9,
+            1 => 1 << 3,
+            2 => 1 << 5,
+            _ => 1 << 7,
+        }
+    }
+
+    /// the input to TIMA's falling edge detector
+    fn signal(&self) -> bool {
+        self.tac & TAC_ENABLE != 0 && self.counter & self.counter_bit() != 0
+    }
+
+    fn set_counter(&mut self, counter: u16) {
+        let signal = self.signal();
+        self.counter = counter;
+        self.check_edge(signal);
+    }
+
+    fn check_edge(&mut self, old_signal: bool) {
+        if old_signal && !self.signal() {
+            let (tima, overflow) = self.tima.overflowing_add(1);
+            self.tima = tima;
+            self.overflowed |= overflow;
+        }
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Timer::new()
+    }
+}
+
+impl SaveState for Timer {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.u16(self.counter);
+        writer.u8(self.tima);
+        writer.u8(self.tma);
+        writer.u8(self.tac);
+        writer.bool(self.overflowed);
+        writer.bool(self.reloaded);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.counter = reader.u16()?;
+        self.tima = reader.u8()?;
+        self.tma = reader.u8()?;
+        self.tac = reader.u8()? & (TAC_ENABLE | TAC_CLOCK_SELECT);
+        self.overflowed = reader.bool()?;
+        self.reloaded = reader.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// an enabled timer counting every 4 cycles, with DIV just reset
+    fn fast_timer() -> Timer {
+        let mut timer = Timer::new();
+        timer.write(TAC, TAC_ENABLE | 1);
+        timer.write(DIV, 0);
+        timer.write(TIMA, 0);
+        timer
+    }
+
+    #[test]
+    fn resetting_div_with_the_selected_bit_set_bumps_tima() {
+        let mut timer = fast_timer();
+        timer.tick(1);
+        timer.write(DIV, 0);
+        assert_eq!(timer.read(TIMA), 0);
+
+        // bit 3 is set after two cycles
+        timer.tick(2);
+        timer.write(DIV, 0);
+        assert_eq!(timer.read(TIMA), 1);
+        assert_eq!(timer.read(DIV), 0);
+    }
+
+    #[test]
+    fn changing_tac_can_bump_tima() {
+        let mut timer = fast_timer();
+        timer.tick(2);
+        // bit 9 is clear, so moving over to it looks like a falling edge
+        timer.write(TAC, TAC_ENABLE);
+        assert_eq!(timer.read(TIMA), 1);
+
+        let mut timer = fast_timer();
+        timer.tick(2);
+        timer.write(TAC, 1);
+        assert_eq!(timer.read(TIMA), 1);
+    }
+
+    #[test]
+    fn tma_is_reloaded_one_cycle_after_the_overflow() {
+        let mut timer = fast_timer();
+        timer.write(TIMA, 0xFF);
+        timer.write(TMA, 0x42);
+        timer.tick(4);
+        assert_eq!(timer.read(TIMA), 0);
+        assert_eq!(timer.take_interrupts(), 0);
+
+        timer.tick(1);
+        assert_eq!(timer.read(TIMA), 0x42);
+        assert_eq!(timer.take_interrupts(), Interrupt::Timer.mask());
+    }
+
+    #[test]
+    fn writes_around_the_reload() {
+        // writing TIMA while it reads 0 cancels the reload and the interrupt
+        let mut timer = fast_timer();
+        timer.write(TIMA, 0xFF);
+        timer.write(TMA, 0x42);
+        timer.tick(4);
+        timer.write(TIMA, 0x10);
+        timer.tick(1);
+        assert_eq!(timer.read(TIMA), 0x10);
+        assert_eq!(timer.take_interrupts(), 0);
+
+        // in the reload cycle TIMA writes are lost and TMA writes go through
+        let mut timer = fast_timer();
+        timer.write(TIMA, 0xFF);
+        timer.write(TMA, 0x42);
+        timer.tick(5);
+        timer.write(TIMA, 0x10);
+        assert_eq!(timer.read(TIMA), 0x42);
+        timer.write(TMA, 0x20);
+        assert_eq!(timer.read(TIMA), 0x20);
+
+        // a cycle later both behave normally again
+        timer.tick(1);
+        timer.write(TIMA, 0x10);
+        timer.write(TMA, 0x30);
+        assert_eq!(timer.read(TIMA), 0x10);
+    }
+}